@@ -0,0 +1,645 @@
+use crate::cpu::isa::{JpCond, RegisterName};
+use crate::cpu::optable::{self, OpInfo, Operand, CB_PREFIX};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::process;
+
+/// The size of the assembled ROM. Only 32KB cartridges without an MBC are produced
+pub const ROM_SIZE: usize = 0x8000;
+/// Where the CPU starts executing the cartridge once the boot ROM has finished
+pub const ENTRY_POINT: u16 = 0x0100;
+/// The first address after the cartridge header, where code placed at the entry point is moved to
+pub const CODE_START: u16 = 0x0150;
+
+/// The logo the boot ROM compares against before starting the cartridge
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// An error in the source being assembled, with the line it occurred on
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+/// A number, a label, or a sum of them, eg `.loop` or `tile+$10`
+#[derive(Clone, Debug)]
+struct Expr {
+    terms: Vec<(bool, Term)>,
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+    Num(i32),
+    Label(String),
+}
+
+impl Expr {
+    fn has_label(&self) -> bool {
+        self.terms.iter().any(|(_, t)| matches!(t, Term::Label(_)))
+    }
+
+    fn eval(&self, labels: &HashMap<String, u16>) -> Result<i32> {
+        let mut value = 0;
+        for (neg, term) in &self.terms {
+            let n = match term {
+                Term::Num(n) => *n,
+                Term::Label(name) => match labels.get(name) {
+                    Some(addr) => *addr as i32,
+                    None => return Err(format!("Undefined label {}", name)),
+                },
+            };
+            value += if *neg { -n } else { n };
+        }
+        Ok(value)
+    }
+}
+
+/// An operand as written in the source
+#[derive(Clone, Debug)]
+enum Arg {
+    /// A register, or (BC), (DE), (HL)
+    Reg(RegisterName),
+    Cond(JpCond),
+    HLInc,
+    HLDec,
+    /// (C)
+    MemC,
+    /// (nn)
+    Mem(Expr),
+    /// SP+e
+    SPRel(Expr),
+    Imm(Expr),
+}
+
+enum Stmt {
+    Ins(&'static OpInfo, Vec<Arg>),
+    Data(Vec<Expr>),
+}
+
+/// An assembled program, with what is known about where its code came from
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+    /// The address and source line of each statement, in source order
+    pub lines: Vec<(u16, usize)>,
+}
+
+/// A statement placed at an address by the first pass
+struct Placed {
+    line: usize,
+    addr: u16,
+    stmt: Stmt,
+}
+
+/// Assembles a program into a 32KB ROM image with a valid cartridge header.
+///
+/// The dialect is that of `test_programs`: one instruction per line, `;` comments, `org`, `db`,
+/// `$hex`, `%binary` and decimal numbers, global `labels:` and local `.labels:` which are scoped to the
+/// global label before them. Memory operands may be written with parentheses or brackets, and `LDI`/`LDD` are
+/// accepted for the `(HL+)`/`(HL-)` forms. A numeric JR operand is a raw offset, while a label is a target.
+///
+/// A label is bound to the address of the next byte emitted, so a label followed by `org` names the start of
+/// the new block. As the header occupies $0104-$014F, `org $100` places code at $0150 instead. The entry point
+/// at $0100 jumps to `main` if it is defined, or $0150 otherwise
+pub fn assemble(source: &str) -> std::result::Result<Vec<u8>, AsmError> {
+    assemble_program(source).map(|p| p.rom)
+}
+
+/// Assembles a program as `assemble` does, keeping its labels and line information for debugging
+pub fn assemble_program(source: &str) -> std::result::Result<Program, AsmError> {
+    let mut labels = HashMap::new();
+    let mut placed = Vec::new();
+    let mut scope = String::new();
+    let mut pending: Vec<String> = Vec::new();
+    let mut addr: u32 = CODE_START as u32;
+
+    // First pass: find the size and address of every statement, and so the address of every label
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let err = |message: String| AsmError { line, message };
+
+        let mut rest = strip_comment(text).trim();
+        while let Some((label, after)) = split_label(rest) {
+            let name = if label.starts_with('.') {
+                format!("{}{}", scope, label)
+            } else {
+                scope = label.to_string();
+                label.to_string()
+            };
+            if labels.contains_key(&name) || pending.contains(&name) {
+                return Err(err(format!("Label {} is already defined", name)));
+            }
+            pending.push(name);
+            rest = after.trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+
+        let (word, operands) = match rest.find(char::is_whitespace) {
+            Some(i) => (&rest[..i], rest[i..].trim()),
+            None => (rest, ""),
+        };
+        let word = word.to_uppercase();
+
+        if word == "ORG" {
+            let origin = parse_expr(operands, &scope)
+                .and_then(|e| e.eval(&HashMap::new()))
+                .map_err(err)?;
+            addr = match origin {
+                o if o == ENTRY_POINT as i32 => CODE_START as u32,
+                o if (ENTRY_POINT as i32..CODE_START as i32).contains(&o) => {
+                    return Err(err(format!("org ${:04X} overlaps the cartridge header", o)))
+                }
+                o if (0..ROM_SIZE as i32).contains(&o) => o as u32,
+                o => return Err(err(format!("org ${:04X} is outside of ROM", o))),
+            };
+            continue;
+        }
+
+        let (stmt, len) = match word.as_str() {
+            "DB" => {
+                let data = parse_data(operands, &scope).map_err(err)?;
+                let len = data.len() as u32;
+                (Stmt::Data(data), len)
+            }
+            _ => {
+                let args = split_operands(operands)
+                    .iter()
+                    .map(|op| parse_arg(op, &scope))
+                    .collect::<Result<Vec<Arg>>>()
+                    .map_err(err)?;
+                let (info, args) = select(&word, args).map_err(err)?;
+                (Stmt::Ins(info, args), info.length as u32)
+            }
+        };
+
+        let end = addr + len;
+        let overlaps_header = addr < CODE_START as u32 && end > ENTRY_POINT as u32;
+        if end > ROM_SIZE as u32 || overlaps_header {
+            return Err(err(format!("${:04X}-${:04X} is outside of the space available for code", addr, end - 1)));
+        }
+
+        for name in pending.drain(..) {
+            labels.insert(name, addr as u16);
+        }
+        placed.push(Placed {
+            line,
+            addr: addr as u16,
+            stmt,
+        });
+        addr = end;
+    }
+    for name in pending.drain(..) {
+        labels.insert(name, addr as u16);
+    }
+
+    // Second pass: encode every statement now every label is known
+    let mut rom = vec![0xFF; ROM_SIZE];
+    let mut used = vec![false; ROM_SIZE];
+    for p in &placed {
+        let err = |message: String| AsmError {
+            line: p.line,
+            message,
+        };
+        let bytes = match &p.stmt {
+            Stmt::Ins(info, args) => encode(info, args, p.addr, &labels).map_err(err)?,
+            Stmt::Data(data) => data
+                .iter()
+                .map(|e| e.eval(&labels).and_then(byte))
+                .collect::<Result<Vec<u8>>>()
+                .map_err(err)?,
+        };
+
+        for (i, b) in bytes.into_iter().enumerate() {
+            let at = p.addr as usize + i;
+            if used[at] {
+                return Err(err(format!("${:04X} has already been assembled to", at)));
+            }
+            used[at] = true;
+            rom[at] = b;
+        }
+    }
+
+    let entry = labels.get("main").copied().unwrap_or(CODE_START);
+    write_header(&mut rom, entry);
+    Ok(Program {
+        rom,
+        labels,
+        lines: placed.iter().map(|p| (p.addr, p.line)).collect(),
+    })
+}
+
+/// Writes the cartridge header: an entry point jumping to `entry`, the logo, and the header and global checksums.
+/// The title is left blank and the cartridge is described as a 32KB ROM with no MBC or RAM
+pub fn write_header(rom: &mut [u8], entry: u16) {
+    let [low, high] = entry.to_le_bytes();
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, low, high]);
+    rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x14D].fill(0x00);
+    // Non-Japanese destination
+    rom[0x14A] = 0x01;
+
+    rom[0x14D] = header_checksum(rom);
+    let [high, low] = global_checksum(rom).to_be_bytes();
+    rom[0x14E] = high;
+    rom[0x14F] = low;
+}
+
+/// The checksum over $0134-$014C that the boot ROM verifies
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..0x14D]
+        .iter()
+        .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1))
+}
+
+/// The sum of every byte of the ROM, except the checksum itself
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 0x14E && *i != 0x14F)
+        .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16))
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Splits a `label:` off the start of a line
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let end = line.find(|c| !is_label_char(c))?;
+    if end > 0 && line[end..].starts_with(':') {
+        Some((&line[..end], &line[end + 1..]))
+    } else {
+        None
+    }
+}
+
+/// Splits operands on the commas between them, ignoring commas in strings
+fn split_operands(operands: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in operands.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(operands[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !operands.trim().is_empty() {
+        parts.push(operands[start..].trim());
+    }
+    parts
+}
+
+fn parse_number(s: &str) -> Option<i32> {
+    if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        i32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix('%') {
+        i32::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse_expr(s: &str, scope: &str) -> Result<Expr> {
+    let mut terms = Vec::new();
+    let mut neg = false;
+    let mut rest = s.trim();
+    if let Some(r) = rest.strip_prefix('-') {
+        neg = true;
+        rest = r.trim_start();
+    } else if let Some(r) = rest.strip_prefix('+') {
+        rest = r.trim_start();
+    }
+
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        if term.is_empty() {
+            return Err(format!("Invalid expression {}", s));
+        }
+        let term = match parse_number(term) {
+            Some(n) => Term::Num(n),
+            None if term.starts_with('.') => Term::Label(format!("{}{}", scope, term)),
+            None if term.chars().all(is_label_char) && !term.starts_with(|c: char| c.is_ascii_digit()) => {
+                Term::Label(term.to_string())
+            }
+            None => return Err(format!("Invalid expression {}", s)),
+        };
+        terms.push((neg, term));
+
+        if end == rest.len() {
+            return Ok(Expr { terms });
+        }
+        neg = rest[end..].starts_with('-');
+        rest = &rest[end + 1..];
+    }
+}
+
+/// Parses the operands of `db`, which may include strings
+fn parse_data(operands: &str, scope: &str) -> Result<Vec<Expr>> {
+    let mut data = Vec::new();
+    for op in split_operands(operands) {
+        match op.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Some(string) => data.extend(string.bytes().map(|b| Expr {
+                terms: vec![(false, Term::Num(b as i32))],
+            })),
+            None => data.push(parse_expr(op, scope)?),
+        }
+    }
+    Ok(data)
+}
+
+fn parse_register(s: &str) -> Option<RegisterName> {
+    Some(match s {
+        "A" => RegisterName::A,
+        "F" => RegisterName::F,
+        "AF" => RegisterName::AF,
+        "B" => RegisterName::B,
+        "C" => RegisterName::C,
+        "BC" => RegisterName::BC,
+        "D" => RegisterName::D,
+        "E" => RegisterName::E,
+        "DE" => RegisterName::DE,
+        "H" => RegisterName::H,
+        "L" => RegisterName::L,
+        "HL" => RegisterName::HL,
+        "SP" => RegisterName::SP,
+        _ => return None,
+    })
+}
+
+fn parse_arg(s: &str, scope: &str) -> Result<Arg> {
+    let upper = s.to_uppercase().replace(' ', "");
+
+    let inner = upper
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix(')'))
+        .or_else(|| upper.strip_prefix('[').and_then(|s| s.strip_suffix(']')));
+    if let Some(inner) = inner {
+        return Ok(match inner {
+            "BC" => Arg::Reg(RegisterName::BCRef),
+            "DE" => Arg::Reg(RegisterName::DERef),
+            "HL" => Arg::Reg(RegisterName::HLRef),
+            "HL+" | "HLI" => Arg::HLInc,
+            "HL-" | "HLD" => Arg::HLDec,
+            "C" | "$FF00+C" => Arg::MemC,
+            _ => Arg::Mem(parse_expr(&s.trim()[1..s.trim().len() - 1], scope)?),
+        });
+    }
+
+    if let Some(reg) = parse_register(&upper) {
+        return Ok(Arg::Reg(reg));
+    }
+    match upper.as_str() {
+        "NZ" => return Ok(Arg::Cond(JpCond::NotZero)),
+        "Z" => return Ok(Arg::Cond(JpCond::Zero)),
+        "NC" => return Ok(Arg::Cond(JpCond::NotCarry)),
+        _ => {}
+    }
+    if upper.starts_with("SP+") || upper.starts_with("SP-") {
+        return Ok(Arg::SPRel(parse_expr(&s.trim()[2..], scope)?));
+    }
+    Ok(Arg::Imm(parse_expr(s, scope)?))
+}
+
+/// Finds the opcode for a mnemonic and its operands, rewriting the aliases accepted by the dialect
+fn select(mnemonic: &str, mut args: Vec<Arg>) -> Result<(&'static OpInfo, Vec<Arg>)> {
+    let mnemonic = match mnemonic {
+        "LDI" | "LDD" => {
+            let replacement = if mnemonic == "LDI" { Arg::HLInc } else { Arg::HLDec };
+            for arg in args.iter_mut() {
+                if let Arg::Reg(RegisterName::HLRef) = arg {
+                    *arg = replacement.clone();
+                }
+            }
+            "LD"
+        }
+        "LD" if args.iter().any(|a| matches!(a, Arg::MemC)) => "LDH",
+        "JP" => {
+            if let [Arg::Reg(reg @ RegisterName::HLRef)] = args.as_mut_slice() {
+                *reg = RegisterName::HL;
+            }
+            "JP"
+        }
+        "ADD" | "ADC" | "SUB" | "SBC" | "AND" | "XOR" | "OR" | "CP" if args.len() == 1 => {
+            args.insert(0, Arg::Reg(RegisterName::A));
+            mnemonic
+        }
+        _ => mnemonic,
+    };
+
+    if args.len() > 2 {
+        return Err(format!("Too many operands for {}", mnemonic));
+    }
+
+    let base = optable::BASE
+        .iter()
+        .enumerate()
+        .filter(|(opcode, _)| *opcode as u8 != CB_PREFIX);
+    let cb = optable::CB.iter().enumerate();
+    for (_, info) in base.chain(cb) {
+        if info.mnemonic != mnemonic {
+            continue;
+        }
+        let matched = (0..2).all(|i| match (info.operands[i], args.get(i)) {
+            (Operand::None, None) => true,
+            (op, Some(arg)) => accepts(op, arg),
+            _ => false,
+        });
+        if matched {
+            return Ok((info, args));
+        }
+    }
+    Err(format!("Invalid instruction {} with these operands", mnemonic))
+}
+
+/// Whether an operand of an opcode can be written as the given source operand
+fn accepts(op: Operand, arg: &Arg) -> bool {
+    match (op, arg) {
+        (Operand::Reg(reg), Arg::Reg(r)) => reg == *r,
+        (Operand::Cond(cond), Arg::Cond(c)) => cond == *c,
+        (Operand::Cond(JpCond::Carry), Arg::Reg(RegisterName::C)) => true,
+        (Operand::HLInc, Arg::HLInc) | (Operand::HLDec, Arg::HLDec) => true,
+        (Operand::HighC, Arg::MemC) => true,
+        (Operand::Addr16 | Operand::HighImm8, Arg::Mem(_)) => true,
+        (Operand::SPRel8, Arg::SPRel(_)) => true,
+        (Operand::Imm8 | Operand::SImm8 | Operand::Imm16 | Operand::Rel8, Arg::Imm(_)) => true,
+        (Operand::Bit(bit), Arg::Imm(e)) => e.eval(&HashMap::new()) == Ok(bit as i32),
+        (Operand::Vector(vec), Arg::Imm(e)) => e.eval(&HashMap::new()) == Ok(vec as i32),
+        _ => false,
+    }
+}
+
+fn byte(n: i32) -> Result<u8> {
+    if (-128..=255).contains(&n) {
+        Ok(n as u8)
+    } else {
+        Err(format!("{} does not fit in a byte", n))
+    }
+}
+
+fn signed_byte(n: i32) -> Result<u8> {
+    if (-128..=127).contains(&n) {
+        Ok(n as u8)
+    } else {
+        Err(format!("{} is out of range for a signed byte", n))
+    }
+}
+
+/// Encodes an instruction whose opcode has been selected, evaluating its immediate operand
+fn encode(info: &OpInfo, args: &[Arg], addr: u16, labels: &HashMap<String, u16>) -> Result<Vec<u8>> {
+    let mut imm = 0;
+    for (op, arg) in info.operands.iter().zip(args) {
+        let expr = match arg {
+            Arg::Mem(e) | Arg::SPRel(e) | Arg::Imm(e) => e,
+            _ => continue,
+        };
+        let value = expr.eval(labels)?;
+        imm = match op {
+            Operand::Imm8 => byte(value)? as u16,
+            Operand::SImm8 | Operand::SPRel8 => signed_byte(value)? as u16,
+            Operand::Imm16 | Operand::Addr16 => {
+                if !(-32768..=0xFFFF).contains(&value) {
+                    return Err(format!("{} does not fit in a word", value));
+                }
+                value as u16
+            }
+            Operand::HighImm8 => match value {
+                0xFF00..=0xFFFF => value as u16 & 0xFF,
+                0x00..=0xFF => value as u16,
+                _ => return Err(format!("${:04X} is not in the high page", value)),
+            },
+            Operand::Rel8 if expr.has_label() => {
+                let next = addr as i32 + info.length as i32;
+                signed_byte(value - next)? as u16
+            }
+            Operand::Rel8 => signed_byte(value)? as u16,
+            _ => continue,
+        };
+    }
+
+    info.instruction(imm)
+        .encode()
+        .ok_or_else(|| format!("{} can't be encoded", info.mnemonic))
+}
+
+/// Entry point for `gbemu asm <source> <rom>`
+pub fn cli(args: &[String]) {
+    let (source, out) = match args {
+        [source, out] => (source, out),
+        _ => {
+            eprintln!("usage: gbemu asm <source> <rom>");
+            process::exit(1);
+        }
+    };
+    let text = match fs::read_to_string(source) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", source, e);
+            process::exit(1);
+        }
+    };
+    match assemble(&text) {
+        Ok(rom) => fs::write(out, rom).unwrap(),
+        Err(e) => {
+            eprintln!("{}: {}", source, e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::mem::MemoryRegister;
+    use crate::cpu::{CPU, CYCLES_PER_FRAME};
+
+    /// Runs a ROM from the entry point for the given number of clock cycles
+    fn run(rom: Vec<u8>, cycles: u64) -> CPU {
+        let mut cpu = CPU::headless();
+        cpu.load_code(rom);
+        cpu.skip_bootrom();
+        while cpu.state.cycles < cycles {
+            cpu.tick();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_header() {
+        let rom = assemble("main:\n nop").unwrap();
+        assert_eq!(rom.len(), ROM_SIZE);
+        assert_eq!(&rom[0x100..0x104], &[0x00, 0xC3, 0x50, 0x01]);
+        assert_eq!(&rom[0x104..0x134], &NINTENDO_LOGO);
+        assert_eq!(rom[0x14D], header_checksum(&rom));
+        assert_eq!(u16::from_be_bytes([rom[0x14E], rom[0x14F]]), global_checksum(&rom));
+
+        let err = assemble("org $00\n nop\n undefined").unwrap_err();
+        assert_eq!(err.line, 3);
+        assert!(assemble("org $120\n nop").is_err());
+    }
+
+    #[test]
+    fn test_program_test() {
+        let rom = assemble(include_str!("../test_programs/test.s")).unwrap();
+        assert_eq!(&rom[0..7], &[0x00, 0x00, 0x3E, 0x50, 0x3C, 0x18, 0xFF]);
+        assert_eq!(&rom[0x100..0x104], &[0x00, 0xC3, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_program_background() {
+        let rom = assemble(include_str!("../test_programs/background.s")).unwrap();
+        let cpu = run(rom, CYCLES_PER_FRAME * 2);
+
+        let tile = [0x81, 0x00, 0x00, 0x42, 0x3c, 0x3c, 0x3c, 0x3c, 0x3c, 0x3c, 0x3c, 0x3c, 0x00, 0x42, 0x81, 0x00, 0xff];
+        assert_eq!(&cpu.mem.buffer[0x8000..0x8011], &tile);
+        assert_eq!(cpu.mem.get_register(MemoryRegister::BGP), 0b11100100);
+        assert_eq!(cpu.mem.get_register(MemoryRegister::LCDC), 0b10010000);
+    }
+
+    #[test]
+    fn test_program_serial() {
+        let driver = "
+main:
+    ld HL,message
+    call send_string
+    .done:
+        jr .done
+message: db \"Hello\", 0
+";
+        let source = format!("{}{}", driver, include_str!("../test_programs/serial.s"));
+        let cpu = run(assemble(&source).unwrap(), CYCLES_PER_FRAME);
+        assert_eq!(cpu.serial.output, b"Hello");
+    }
+}
@@ -1,21 +1,38 @@
+mod bus;
+pub mod calls;
+#[cfg(test)]
+mod conformance;
 mod decode;
 mod exec;
 pub mod int;
 pub mod isa;
 pub mod mem;
-use crate::peripherals::audio::AudioDrv;
+pub mod optable;
+pub mod sched;
+pub mod snapshot;
+pub use bus::BusAccess;
+use crate::debug::coverage::Coverage;
+use crate::debug::profile::Profiler;
+use crate::debug::trace::Tracer;
+use calls::CallStack;
+use crate::peripherals::audio::{AudioDrv, FRAME_SEQUENCER_CYCLES};
+use crate::peripherals::dma::DmaDrv;
+use crate::peripherals::joypad::JoypadDrv;
+use crate::peripherals::serial::SerialDrv;
+use crate::peripherals::timer::TimerDrv;
 use crate::peripherals::video::VideoDrv;
 use int::*;
 use isa::*;
 use mem::*;
+use sched::{EventKind, Scheduler};
 use sdl2::Sdl;
 
-/// The number of CPU cycles that can be performed between screen refreshes
-pub const CYCLES_PER_FRAME: u64 = 280_896;
+/// The number of clock cycles the LCD takes to draw a frame, which is the time between screen refreshes
+pub const CYCLES_PER_FRAME: u64 = 70_224;
 pub const CLOCK_SPEED: u64 = 4_190_000;
 pub static BOOTROM: &'static [u8; 256] = include_bytes!("bootrom.bin");
 
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct Registers {
     pub af: u16,
     pub bc: u16,
@@ -41,7 +58,7 @@ impl Registers {
         }
     }
 
-    fn read16(&self, name: RegisterName) -> Option<u16> {
+    pub fn read16(&self, name: RegisterName) -> Option<u16> {
         match name {
             RegisterName::AF => Some(self.af),
             RegisterName::BC => Some(self.bc),
@@ -52,7 +69,7 @@ impl Registers {
         }
     }
 
-    fn read8(&self, name: RegisterName) -> Option<u8> {
+    pub fn read8(&self, name: RegisterName) -> Option<u8> {
         match name {
             RegisterName::A => Some((self.af >> 8) as u8),
             RegisterName::F => Some((self.af & 0xff) as u8),
@@ -81,7 +98,7 @@ impl Registers {
         match name {
             RegisterName::A => self.af = ((value as u16) << 8) | (self.af & 0xff),
             RegisterName::F => self.af = (self.af & 0xff00) | value as u16,
-            RegisterName::B => self.bc = ((value as u16) << 8) | (self.af & 0xff),
+            RegisterName::B => self.bc = ((value as u16) << 8) | (self.bc & 0xff),
             RegisterName::C => self.bc = (self.bc & 0xff00) | value as u16,
             RegisterName::D => self.de = ((value as u16) << 8) | (self.de & 0xff),
             RegisterName::E => self.de = (self.de & 0xff00) | value as u16,
@@ -158,30 +175,67 @@ impl Registers {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct CPUState {
     pub ei_pending: bool,
     pub di_pending: bool,
     pub halted: bool,
     pub stopped: bool,
+    /// Set when HALT is executed with IME=0 and an interrupt already pending.
+    /// The next opcode fetch then fails to increment PC, so the byte after HALT is read twice
+    pub halt_bug: bool,
+    /// CGB double speed mode, toggled by STOP when KEY1 bit 0 is armed
+    pub double_speed: bool,
+    /// Clock cycles elapsed since power on, advanced 4 at a time by every M-cycle
+    pub cycles: u64,
+    /// The value of `cycles` when the opcode of the instruction currently executing was fetched
+    pub ins_start: u64,
     pending_cycles: u8,
 }
 
 pub struct CPU {
-    pub sdl: Sdl,
+    /// The SDL context, or None when running headless
+    pub sdl: Option<Sdl>,
     pub reg: Registers,
     pub mem: Memory,
     pub state: CPUState,
     pub audio: AudioDrv,
     pub video: VideoDrv,
-    dbgwait: bool,
+    pub joypad: JoypadDrv,
+    pub timer: TimerDrv,
+    pub dma: DmaDrv,
+    pub serial: SerialDrv,
+    pub sched: Scheduler,
+    /// The calls and interrupts that haven't returned yet, for debugging
+    pub calls: CallStack,
+    /// When set, the bus is a flat 64KB RAM with no I/O side effects or peripheral events, and every
+    /// M-cycle is logged here. Used to run conformance tests which expect nothing but the CPU on the bus
+    pub test_bus: Option<Vec<BusAccess>>,
+    /// Writes a trace line before each instruction, if set
+    pub tracer: Option<Tracer>,
+    /// Accumulates where time is spent, if set
+    pub profiler: Option<Profiler>,
+    /// Records what each byte of ROM is used for, if set
+    pub coverage: Option<Coverage>,
+    /// A value reads of LY return instead of the current line, as trace comparison tools expect
+    pub force_ly: Option<u8>,
 }
 
 impl CPU {
     pub fn new() -> CPU {
-        let sdl = sdl2::init().unwrap();
-        let audio = AudioDrv::new(&sdl);
-        let video = VideoDrv::new(&sdl);
+        CPU::with_sdl(Some(sdl2::init().unwrap()))
+    }
+
+    /// Creates a CPU with no window or audio output, for running tests and tools
+    pub fn headless() -> CPU {
+        CPU::with_sdl(None)
+    }
+
+    fn with_sdl(sdl: Option<Sdl>) -> CPU {
+        let audio = AudioDrv::new(sdl.as_ref());
+        let video = VideoDrv::new(sdl.as_ref());
+        let mut sched = Scheduler::new();
+        sched.schedule(FRAME_SEQUENCER_CYCLES, EventKind::FrameSequencer);
         CPU {
             sdl,
             reg: Registers::new(),
@@ -189,7 +243,17 @@ impl CPU {
             state: CPUState::default(),
             audio,
             video,
-            dbgwait: false,
+            joypad: JoypadDrv::new(),
+            timer: TimerDrv::new(),
+            dma: DmaDrv::new(),
+            serial: SerialDrv::new(),
+            sched,
+            calls: CallStack::new(),
+            test_bus: None,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            force_ly: None,
         }
     }
 
@@ -205,11 +269,63 @@ impl CPU {
         self.mem.bootrom_paged = true;
     }
 
+    /// Puts the CPU in the state the DMG boot ROM leaves it in, so the cartridge starts running at $0100 straight away
+    pub fn skip_bootrom(&mut self) {
+        self.reg.af = 0x01B0;
+        self.reg.bc = 0x0013;
+        self.reg.de = 0x00D8;
+        self.reg.hl = 0x014D;
+        self.reg.sp = 0xFFFE;
+        self.reg.pc = 0x0100;
+        self.mem.bootrom_paged = false;
+        self.mem.set_addr(0xFF50, 1);
+        self.mem.set_register(MemoryRegister::BGP, 0xFC);
+        self.write8(MemoryRegister::LCDC.to_addr(), 0x91);
+    }
+
+    /// Runs a single instruction, servicing any interrupts raised during it.
+    /// While halted this instead advances to the next peripheral event.
+    ///
+    /// Returns the number of clock cycles that elapsed
     pub fn tick(&mut self) -> u32 {
+        let start = self.state.cycles;
+
+        if let Some(int) = self.joypad.update(&mut self.mem) {
+            self.dispatch_interrupt(int);
+        }
+
+        if self.state.stopped {
+            // STOP halts both the CPU and the LCD until one of the selected joypad lines goes low.
+            // Time still passes, so anything scheduled keeps happening
+            if !self.joypad.line_low() {
+                self.cycle();
+                return 4;
+            }
+            self.state.stopped = false;
+        }
+
         if self.state.halted {
+            // HALT is exited by any pending interrupt, even if IME is not set.
+            // If IME is not set execution simply resumes after the HALT
+            if self.pending_interrupts() == 0 {
+                // Nothing can change until the next peripheral event, so skip straight to the M-cycle it falls in
+                let next = self.sched.next_at().unwrap_or(self.state.cycles + 4);
+                let next = (next + 3) & !3;
+                self.state.cycles = next.max(self.state.cycles + 4);
+                self.run_events();
+                let elapsed = (self.state.cycles - start) as u32;
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.halt(&self.calls, elapsed);
+                }
+                return elapsed;
+            }
+            self.state.halted = false;
             if self.handle_interrupts() {
-                self.state.halted = false;
-                return 20;
+                let elapsed = (self.state.cycles - start) as u32;
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.interrupt(&self.calls, elapsed);
+                }
+                return elapsed;
             }
         }
 
@@ -219,31 +335,55 @@ impl CPU {
         }
 
         if self.mem.get_addr(0xFF50) == 1 && self.mem.bootrom_paged {
-            println!("Unpaging bootrom");
             self.mem.bootrom_paged = false;
         }
 
-        println!("{:#x?}", self.reg);
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.trace(&self.reg, &self.mem) {
+                eprintln!("Unable to write trace, stopping it: {}", e);
+                self.tracer = None;
+            }
+        }
+        let pc = self.reg.pc;
+        let opcode = match &mut self.profiler {
+            Some(profiler) => {
+                profiler.enter(&self.calls);
+                match self.mem.get_addr(pc) {
+                    optable::CB_PREFIX => 0x100 + self.mem.get_addr(pc.wrapping_add(1)) as usize,
+                    opcode => opcode as usize,
+                }
+            }
+            None => 0,
+        };
         let ins = self.decode();
-        // println!("LY {:#x}", self.mem.get_register(MemoryRegister::LY));
-        let mut cycles = self.execute(ins) as u32;
-
-        if self.handle_interrupts() {
-            cycles += 20;
+        let next = self.reg.pc;
+        let taken = self.execute(ins);
+        self.calls.check_sp(pc, self.reg.sp);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.instruction(pc, opcode, taken as u32);
         }
-
-        // self.audio.tick(&self.mem);
-        if let Some(int) = self.video.tick(&mut self.mem) {
-            self.dispatch_interrupt(int);
+        if let Some(coverage) = &mut self.coverage {
+            if self.mem.is_cartridge_rom(pc) {
+                coverage.execute(pc, next.wrapping_sub(pc));
+            }
+            if self.reg.pc != next && self.mem.is_cartridge_rom(self.reg.pc) {
+                coverage.jump(self.reg.pc);
+            }
         }
 
+        let dispatch = self.state.cycles;
+        if self.handle_interrupts() {
+            if let Some(profiler) = &mut self.profiler {
+                profiler.interrupt(&self.calls, (self.state.cycles - dispatch) as u32);
+            }
+        }
 
         if self.state.di_pending && self.reg.ie {
             self.state.di_pending = false;
             self.reg.ie = false;
         }
 
-        cycles
+        (self.state.cycles - start) as u32
     }
 }
 
@@ -251,19 +391,225 @@ impl CPU {
 mod tests {
     use super::isa::*;
     use super::*;
+    use crate::asm::assemble;
+    use crate::peripherals::joypad::Button;
+
+    fn start(source: &str) -> CPU {
+        let mut cpu = CPU::headless();
+        cpu.load_code(assemble(source).unwrap());
+        cpu.skip_bootrom();
+        cpu
+    }
+
+    /// Ticks until `done` holds, failing if it takes more than `limit` ticks
+    fn run_until(cpu: &mut CPU, limit: usize, done: impl Fn(&CPU) -> bool) {
+        for _ in 0..limit {
+            if done(cpu) {
+                return;
+            }
+            cpu.tick();
+        }
+        panic!("Not done after {} ticks, PC is ${:04X}", limit, cpu.reg.pc);
+    }
+
+    fn reg(cpu: &CPU, name: RegisterName) -> u8 {
+        cpu.reg.read8(name).unwrap()
+    }
 
     #[test]
     fn test_ld() {
-        let code = vec![0x06, 0x05];
+        let code = Instruction::Ld8Imm(RegisterName::B, 5).encode().unwrap();
 
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         cpu.load_code(code);
+        cpu.mem.bootrom_paged = false;
         let ins = cpu.decode();
         assert_eq!(ins, Instruction::Ld8Imm(RegisterName::B, 5));
 
-        let code = vec![0x11, 0x01, 0x00];
+        let code = Instruction::Ld16Imm(RegisterName::DE, 1).encode().unwrap();
         cpu.load_code(code);
+        cpu.mem.bootrom_paged = false;
         let ins = cpu.decode();
         assert_eq!(ins, Instruction::Ld16Imm(RegisterName::DE, 1));
     }
+
+    #[test]
+    fn test_alu() {
+        // Runs an instruction with the given A and F, and B set to $01, returning A and F
+        let run = |a: u8, f: u8, ins: Instruction| {
+            let mut cpu = CPU::headless();
+            cpu.reg.af = u16::from_be_bytes([a, f]);
+            cpu.reg.bc = 0x0100;
+            cpu.execute(ins);
+            cpu.reg.af.to_be_bytes()
+        };
+        let (z, n, h, c) = (0x80, 0x40, 0x20, 0x10);
+        assert_eq!(run(0x0F, 0, Instruction::AddImm(0x01)), [0x10, h]);
+        assert_eq!(run(0xFF, 0, Instruction::AddImm(0x01)), [0x00, z | h | c]);
+        assert_eq!(run(0xFE, c, Instruction::AdcImm(0x01)), [0x00, z | h | c]);
+        assert_eq!(run(0xFF, c, Instruction::AdcReg(RegisterName::A)), [0xFF, h | c]);
+        assert_eq!(run(0x10, 0, Instruction::SubImm(0x01)), [0x0F, n | h]);
+        assert_eq!(run(0x00, c, Instruction::SbcImm(0xFF)), [0x00, z | n | h | c]);
+        assert_eq!(run(0x02, c, Instruction::SbcReg(RegisterName::B)), [0x00, z | n]);
+        // CP only sets the flags, and a register greater than A borrows
+        assert_eq!(run(0x00, 0, Instruction::CpReg(RegisterName::B)), [0x00, n | h | c]);
+        assert_eq!(run(0x01, 0, Instruction::CpImm(0x01)), [0x01, z | n]);
+        assert_eq!(run(0x10, c, Instruction::Dec(RegisterName::A)), [0x0F, n | h | c]);
+
+        let mut cpu = CPU::headless();
+        cpu.reg.hl = 0x8FFF;
+        cpu.reg.bc = 0x8001;
+        cpu.execute(Instruction::Add16(RegisterName::BC));
+        assert_eq!((cpu.reg.hl, cpu.reg.af as u8), (0x1000, h | c));
+        // SP+e8 takes its flags from the low byte, even for a negative offset
+        cpu.reg.sp = 0x00FF;
+        cpu.execute(Instruction::LdHLSPn(-1));
+        assert_eq!((cpu.reg.hl, cpu.reg.af as u8), (0x00FE, h | c));
+        cpu.reg.sp = 0xFFF8;
+        cpu.execute(Instruction::AddSP(8));
+        assert_eq!((cpu.reg.sp, cpu.reg.af as u8), (0x0000, h | c));
+    }
+
+    #[test]
+    fn test_decode_table() {
+        let code = [0xCB, 0x37, 0x18, 0xFE, 0xFA, 0x34, 0x12];
+        let read = |addr: u16| code[addr as usize];
+
+        let (ins, info) = optable::decode_at(read, 0);
+        assert_eq!(ins, Instruction::Swap(RegisterName::A));
+        assert_eq!((info.mnemonic, info.length, info.cycles), ("SWAP", 2, 8));
+
+        let (ins, info) = optable::decode_at(read, 2);
+        assert_eq!(ins, Instruction::Jr(-2));
+        assert_eq!(info.length, 2);
+
+        let (ins, info) = optable::decode_at(read, 4);
+        assert_eq!(ins, Instruction::Lda(0x1234));
+        assert_eq!((info.length, info.cycles), (3, 16));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let tables = [(false, &optable::BASE), (true, &optable::CB)];
+        for (cb, table) in tables {
+            for (opcode, info) in table.iter().enumerate() {
+                // Illegal opcodes all decode to the same instruction, so can't be told apart
+                if info.template == Instruction::IllInsn || (!cb && opcode as u8 == optable::CB_PREFIX) {
+                    continue;
+                }
+
+                let mut code = if cb { vec![optable::CB_PREFIX] } else { vec![] };
+                code.push(opcode as u8);
+                code.extend_from_slice(&[0x34, 0x12][..info.imm_len() as usize]);
+                code.resize(info.length as usize, 0x00);
+
+                let (ins, _) = optable::decode_at(|addr| code[addr as usize], 0);
+                assert_eq!(ins.encode(), Some(code), "{:?}", ins);
+            }
+        }
+
+        assert_eq!(Instruction::LdnnSP(0xC000).encode(), Some(vec![0x08, 0x00, 0xC0]));
+        assert_eq!(Instruction::Jr(-2).encode(), Some(vec![0x18, 0xFE]));
+        assert_eq!(Instruction::Ld8Reg(RegisterName::HLRef, RegisterName::HLRef).encode(), None);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT with IME=0 and an interrupt already pending isn't entered, and the INC after it runs twice
+        let mut cpu = start(
+            "
+main:
+    di
+    ld A,$04
+    ldh ($FFFF),A
+    ldh ($FF0F),A
+    halt
+    inc B
+    ld C,$42
+    .done:
+        jr .done
+",
+        );
+        run_until(&mut cpu, 100, |cpu| reg(cpu, RegisterName::C) == 0x42);
+        assert_eq!(reg(&cpu, RegisterName::B), 2);
+        assert!(!cpu.state.halted);
+    }
+
+    #[test]
+    fn test_halt_wake_without_ime() {
+        // With IME=0 a timer interrupt wakes HALT, but execution carries on after it without being serviced
+        let mut cpu = start(
+            "
+main:
+    di
+    ld A,$04
+    ldh ($FFFF),A
+    xor A
+    ldh ($FF0F),A
+    ldh ($FF05),A
+    ld A,$05
+    ldh ($FF07),A
+    halt
+    ld B,$42
+    .done:
+        jr .done
+",
+        );
+        run_until(&mut cpu, 100, |cpu| cpu.state.halted);
+        let halted_at = cpu.state.cycles;
+        run_until(&mut cpu, 1000, |cpu| reg(cpu, RegisterName::B) == 0x42);
+        // TIMA overflows after 256 increments 16 cycles apart
+        assert!(cpu.state.cycles - halted_at >= 256 * 16 - 16);
+        assert!(cpu.mem.get_register(MemoryRegister::IF) & 0x04 != 0);
+        assert_eq!(cpu.calls.depth(), 0);
+    }
+
+    #[test]
+    fn test_stop() {
+        // STOP waits for a selected joypad line to go low
+        let mut cpu = start(
+            "
+main:
+    ld A,$10
+    ldh ($FF00),A
+    stop
+    ld B,$42
+    .done:
+        jr .done
+",
+        );
+        run_until(&mut cpu, 100, |cpu| cpu.state.stopped);
+        for _ in 0..100 {
+            cpu.tick();
+        }
+        assert!(cpu.state.stopped);
+        assert_eq!(reg(&cpu, RegisterName::B), 0);
+        cpu.joypad.press(Button::A);
+        run_until(&mut cpu, 100, |cpu| reg(cpu, RegisterName::B) == 0x42);
+
+        // STOP resets DIV. A is held, so it wakes straight away
+        let mut cpu = start(
+            "
+main:
+    ld A,$10
+    ldh ($FF00),A
+    ld B,0
+    .wait:
+        dec B
+        jr nz,.wait
+    ldh A,($FF04)
+    ld C,A
+    stop
+    ldh A,($FF04)
+    ld D,A
+    ld E,$42
+    .done:
+        jr .done
+",
+        );
+        cpu.joypad.press(Button::A);
+        run_until(&mut cpu, 1000, |cpu| reg(cpu, RegisterName::E) == 0x42);
+        assert!(reg(&cpu, RegisterName::C) > 0x08);
+        assert_eq!(reg(&cpu, RegisterName::D), 0);
+    }
 }
@@ -0,0 +1,239 @@
+use super::sched::EventKind;
+use super::*;
+use crate::debug::watch::{Access, Accessor};
+use crate::peripherals::audio::FRAME_SEQUENCER_CYCLES;
+use crate::peripherals::serial::TRANSFER_CYCLES;
+
+/// A single M-cycle of bus activity, as recorded by the flat test bus
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BusAccess {
+    Read(u16, u8),
+    Write(u16, u8),
+    /// An M-cycle in which the bus was not accessed
+    Internal,
+}
+
+impl CPU {
+    /// Advances the clock by one M-cycle, handling any peripheral events that have become due.
+    /// Each bus access made by the CPU takes exactly one M-cycle, so peripherals observe the effects
+    /// of an instruction on the cycle they actually happen, rather than once the whole instruction has completed.
+    pub fn cycle(&mut self) {
+        self.state.cycles += 4;
+        if self.test_bus.is_none() {
+            self.run_events();
+        }
+    }
+
+    /// Handles every event due at or before the current cycle, in the order they were scheduled for
+    pub fn run_events(&mut self) {
+        while let Some((at, kind)) = self.sched.pop_due(self.state.cycles) {
+            self.handle_event(at, kind);
+        }
+    }
+
+    fn handle_event(&mut self, at: u64, kind: EventKind) {
+        match kind {
+            EventKind::PpuMode => {
+                let (dots, ints) = self.video.step(&mut self.mem);
+                for int in ints.iter().flatten() {
+                    self.dispatch_interrupt(*int);
+                }
+                self.schedule_ppu(at, dots);
+            }
+            EventKind::TimerOverflow => {
+                let int = self.timer.overflow(&mut self.mem, at);
+                self.dispatch_interrupt(int);
+                self.reschedule_timer();
+            }
+            EventKind::SerialComplete => {
+                let int = self.serial.complete(&mut self.mem);
+                self.dispatch_interrupt(int);
+            }
+            EventKind::FrameSequencer => {
+                self.audio.step_frame_sequencer(&mut self.mem);
+                self.sched
+                    .schedule(at + FRAME_SEQUENCER_CYCLES, EventKind::FrameSequencer);
+            }
+            EventKind::DmaByte => {
+                if self.dma.step(&mut self.mem) {
+                    self.sched.schedule(at + 4, EventKind::DmaByte);
+                }
+            }
+        }
+    }
+
+    /// Schedules the next PPU mode change `dots` after `from`.
+    /// In double speed mode the CPU runs at twice the rate of the LCD, so each dot lasts two clock cycles
+    fn schedule_ppu(&mut self, from: u64, dots: u32) {
+        let scale = if self.state.double_speed { 2 } else { 1 };
+        self.sched
+            .schedule(from + scale * dots as u64, EventKind::PpuMode);
+    }
+
+    /// Replaces any pending TIMA overflow with one computed from the current timer registers
+    fn reschedule_timer(&mut self) {
+        self.sched.cancel(EventKind::TimerOverflow);
+        if let Some(at) = self.timer.next_overflow(&self.mem, self.state.cycles) {
+            self.sched.schedule(at, EventKind::TimerOverflow);
+        }
+    }
+
+    /// Resets the system counter behind DIV, as done by writes to DIV and by STOP
+    pub fn reset_div(&mut self) {
+        if let Some(int) = self.timer.reset_div(&mut self.mem, self.state.cycles) {
+            self.dispatch_interrupt(int);
+        }
+        self.reschedule_timer();
+    }
+
+    /// An M-cycle in which the CPU does not access the bus
+    pub fn internal_cycle(&mut self) {
+        self.cycle();
+        if let Some(log) = &mut self.test_bus {
+            log.push(BusAccess::Internal);
+        }
+    }
+
+    /// Reads a byte of data from the bus, taking one M-cycle
+    pub fn read8(&mut self, addr: u16) -> u8 {
+        if let Some(coverage) = &mut self.coverage {
+            if self.mem.is_cartridge_rom(addr) {
+                coverage.read(addr);
+            }
+        }
+        self.read_bus(addr)
+    }
+
+    /// Reads a byte from the bus, taking one M-cycle. Instruction fetches read through this directly,
+    /// so that only reads of data are counted as such
+    pub(super) fn read_bus(&mut self, addr: u16) -> u8 {
+        self.cycle();
+
+        if let Some(log) = &mut self.test_bus {
+            let value = self.mem.buffer[addr as usize];
+            log.push(BusAccess::Read(addr, value));
+            return value;
+        }
+
+        // While OAM DMA is running only the high page is reachable by the CPU
+        if self.dma.active(self.state.cycles) && addr < 0xFF00 {
+            return 0xFF;
+        }
+        match addr {
+            // DIV and TIMA are only brought up to date when they are looked at
+            0xFF04 | 0xFF05 => self.timer.sync(&mut self.mem, self.state.cycles),
+            0xFF44 => {
+                if let Some(ly) = self.force_ly {
+                    return ly;
+                }
+            }
+            _ => {}
+        }
+        self.mem.read(addr, Accessor::Cpu)
+    }
+
+    /// Writes a byte to the bus, taking one M-cycle
+    pub fn write8(&mut self, addr: u16, value: u8) {
+        self.cycle();
+
+        if let Some(log) = &mut self.test_bus {
+            log.push(BusAccess::Write(addr, value));
+            self.mem.buffer[addr as usize] = value;
+            return;
+        }
+
+        let now = self.state.cycles;
+        if self.dma.active(now) && addr < 0xFF00 {
+            return;
+        }
+        // Writes with side effects don't go through `Memory::write`, so every write is checked against watchpoints here
+        let old = self.mem.get_addr(addr);
+        self.mem.watch(addr, Access::Write, old, value, Accessor::Cpu);
+        match addr {
+            //TODO: MBCs. Without one, writes to ROM are dropped
+            0x0000..=0x7FFF => {}
+            0xFF02 => {
+                if self.serial.write_sc(&mut self.mem, value) {
+                    self.sched.cancel(EventKind::SerialComplete);
+                    self.sched
+                        .schedule(now + TRANSFER_CYCLES, EventKind::SerialComplete);
+                }
+            }
+            0xFF04 => self.reset_div(),
+            0xFF05 => {
+                self.timer.write_tima(&mut self.mem, now, value);
+                self.reschedule_timer();
+            }
+            0xFF07 => {
+                if let Some(int) = self.timer.write_tac(&mut self.mem, now, value) {
+                    self.dispatch_interrupt(int);
+                }
+                self.reschedule_timer();
+            }
+            0xFF19 => self.audio.write_nr24(&mut self.mem, value),
+            0xFF40 => {
+                self.mem.set_addr(addr, value);
+                let enable = value & 0x80 != 0;
+                if enable && !self.video.is_enabled() {
+                    let dots = self.video.enable(&mut self.mem);
+                    self.schedule_ppu(now, dots);
+                } else if !enable && self.video.is_enabled() {
+                    self.sched.cancel(EventKind::PpuMode);
+                    self.video.disable(&mut self.mem);
+                }
+            }
+            // The mode and coincidence bits of STAT are read only
+            0xFF41 => {
+                let stat = self.mem.get_addr(addr);
+                self.mem.set_addr(addr, 0x80 | (value & 0x78) | (stat & 0x07));
+            }
+            // LY is read only
+            0xFF44 => {}
+            0xFF46 => {
+                self.mem.set_addr(addr, value);
+                if let Some(coverage) = &mut self.coverage {
+                    let (mem, source) = (&self.mem, (value as u16) << 8);
+                    for i in (0..0xA0).filter(|i| mem.is_cartridge_rom(source + i)) {
+                        coverage.read(source + i);
+                    }
+                }
+                let first = self.dma.start(value, now);
+                self.sched.cancel(EventKind::DmaByte);
+                self.sched.schedule(first, EventKind::DmaByte);
+            }
+            _ => self.mem.set_addr(addr, value),
+        }
+    }
+
+    /// Reads the byte at PC and increments it
+    pub fn fetch8(&mut self) -> u8 {
+        let b = self.read_bus(self.reg.pc);
+        self.reg.pc = self.reg.pc.wrapping_add(1);
+        b
+    }
+
+    /// Reads the little endian word at PC and increments it past the word
+    pub fn fetch16(&mut self) -> u16 {
+        let low = self.fetch8();
+        let high = self.fetch8();
+        u16::from_le_bytes([low, high])
+    }
+
+    /// Pushes a word onto the stack, high byte first. Takes two M-cycles
+    pub fn push16(&mut self, value: u16) {
+        let bytes = value.to_le_bytes();
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write8(self.reg.sp, bytes[1]);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write8(self.reg.sp, bytes[0]);
+    }
+
+    /// Pops a word off the stack, low byte first. Takes two M-cycles
+    pub fn pop16(&mut self) -> u16 {
+        let low = self.read8(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        let high = self.read8(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        u16::from_le_bytes([low, high])
+    }
+}
@@ -0,0 +1,200 @@
+/// The most frames kept. Code that calls without ever returning would otherwise grow the stack forever
+pub const MAX_DEPTH: usize = 1024;
+/// The most anomalies kept until they are taken
+pub const MAX_ANOMALIES: usize = 64;
+
+/// A call or interrupt that hasn't returned yet
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Frame {
+    /// The address of the call, or for an interrupt the address of the instruction it interrupted
+    pub caller: u16,
+    /// Where the call or interrupt jumped to
+    pub target: u16,
+    /// The address pushed, which a matching return pops
+    pub return_addr: u16,
+    /// Where the return address was pushed to, so SP after the call
+    pub sp: u16,
+    pub interrupt: bool,
+}
+
+/// Something that didn't match up with the call stack, such as a return that wasn't to the caller
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Anomaly {
+    /// The address of the instruction that caused it
+    pub pc: u16,
+    pub message: String,
+}
+
+/// A shadow of the call stack, kept alongside the real one from calls, RSTs, interrupts and returns.
+/// Code is free to manipulate the stack, so returns are matched up by where they pop the return address from.
+/// Anything that doesn't match is recorded as an anomaly
+#[derive(Clone, Default)]
+pub struct CallStack {
+    /// The frames from outermost to innermost
+    pub frames: Vec<Frame>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    fn anomaly(&mut self, pc: u16, message: String) {
+        if self.anomalies.len() < MAX_ANOMALIES {
+            self.anomalies.push(Anomaly { pc, message });
+        }
+    }
+
+    /// Records a call or interrupt, made once the return address has been pushed to `sp`
+    pub fn call(&mut self, caller: u16, target: u16, return_addr: u16, sp: u16, interrupt: bool) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(Frame {
+            caller,
+            target,
+            return_addr,
+            sp,
+            interrupt,
+        });
+    }
+
+    /// Records a return by the instruction at `pc`, which popped `return_addr` from `sp`
+    pub fn ret(&mut self, pc: u16, return_addr: u16, sp: u16) {
+        // Frames pushed deeper than the return address are ones the code has abandoned
+        let deeper = self.frames.iter().rev().take_while(|f| f.sp < sp).count();
+        if deeper > 0 {
+            self.frames.truncate(self.frames.len() - deeper);
+            self.anomaly(pc, format!("Return abandoned {} frame{}", deeper, if deeper == 1 { "" } else { "s" }));
+        }
+
+        match self.frames.last() {
+            Some(frame) if frame.sp == sp => {
+                let frame = self.frames.pop().unwrap();
+                if frame.return_addr != return_addr {
+                    self.anomaly(
+                        pc,
+                        format!(
+                            "Return to ${:04X}, but the call from ${:04X} would return to ${:04X}",
+                            return_addr, frame.caller, frame.return_addr
+                        ),
+                    );
+                }
+            }
+            // Pushing an address and returning to it is a common way to jump, so this is only worth noting
+            _ => self.anomaly(pc, format!("Return to ${:04X} without a matching call", return_addr)),
+        }
+    }
+
+    /// Checks the frames against SP after an instruction. Any frame whose return address is now above SP
+    /// has been popped without returning, by moving SP directly or popping the address into a register
+    pub fn check_sp(&mut self, pc: u16, sp: u16) {
+        let popped = self.frames.iter().rev().take_while(|f| f.sp < sp).count();
+        if popped > 0 {
+            let frame = self.frames[self.frames.len() - popped];
+            self.frames.truncate(self.frames.len() - popped);
+            self.anomaly(
+                pc,
+                format!(
+                    "SP moved to ${:04X}, past the return address of the call from ${:04X}",
+                    sp, frame.caller
+                ),
+            );
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Takes the anomalies recorded since last taken
+    pub fn take_anomalies(&mut self) -> Vec<Anomaly> {
+        std::mem::take(&mut self.anomalies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::cpu::int::Interrupt;
+    use crate::cpu::mem::MemoryRegister;
+    use crate::cpu::CPU;
+    use crate::debug::debugger::Debugger;
+
+    #[test]
+    fn test_call_stack() {
+        let source = "
+main:
+    call outer
+    call popped
+    ld SP,$DFF0
+    ld HL,target
+    push HL
+    ret
+target:
+    halt
+    .loop:
+        jr .loop
+outer:
+    call inner
+    ret
+inner:
+    rst $38
+    ret
+popped:
+    pop HL
+    jp HL
+";
+        let mut rom = assemble(source).unwrap();
+        // RST $38 handler: return straight away
+        rom[0x38] = 0xC9;
+        let mut cpu = CPU::headless();
+        cpu.load_code(rom);
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+
+        // Stop inside the RST, three calls deep
+        assert_eq!(dbg.command(&mut cpu, "until 38"), "");
+        while dbg.is_running() {
+            dbg.step(&mut cpu);
+        }
+        let frames = &cpu.calls.frames;
+        assert_eq!(frames.len(), 3);
+        assert_eq!((frames[0].caller, frames[0].interrupt), (0x150, false));
+        assert_eq!(frames[2].target, 0x38);
+
+        let bt = dbg.command(&mut cpu, "backtrace");
+        assert_eq!(bt.lines().count(), 4, "{}", bt);
+        assert!(bt.starts_with("#0  $0038\n#1  $"), "{}", bt);
+
+        // Finish runs until the RST returns, not just until any return
+        dbg.command(&mut cpu, "finish");
+        while dbg.is_running() {
+            dbg.step(&mut cpu);
+        }
+        assert_eq!(cpu.calls.depth(), 2);
+        assert!(cpu.calls.anomalies.is_empty());
+
+        // Popping the return address abandons the frame, and returning through a pushed address has no call
+        let target = crate::asm::assemble_program(source).unwrap().labels["target"];
+        dbg.command(&mut cpu, &format!("until {:X}", target));
+        while dbg.is_running() {
+            dbg.step(&mut cpu);
+        }
+        assert_eq!(cpu.calls.depth(), 0);
+        let messages = dbg.messages();
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert!(messages[0].contains("SP moved to $FFFE"), "{}", messages[0]);
+        assert!(messages[1].contains("without a matching call"), "{}", messages[1]);
+
+        // Interrupts are frames too
+        cpu.mem.set_register(MemoryRegister::IE, 1);
+        cpu.reg.ie = true;
+        cpu.dispatch_interrupt(Interrupt::Vblank);
+        cpu.tick();
+        assert_eq!(cpu.calls.depth(), 1);
+        assert!(cpu.calls.frames[0].interrupt);
+        assert_eq!(cpu.calls.frames[0].target, 0x40);
+    }
+}
@@ -0,0 +1,249 @@
+//! Runs the SM83 single step test vectors (https://github.com/SingleStepTests/sm83) against the executor
+
+use super::*;
+use serde_json::Value;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+/// The registers and RAM of one side of a case
+struct CpuState {
+    af: u16,
+    bc: u16,
+    de: u16,
+    hl: u16,
+    sp: u16,
+    pc: u16,
+    ime: bool,
+    /// The IE register at $FFFF
+    ie: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+/// The state before and after a single instruction, and the bus activity of every M-cycle in between
+struct Case {
+    name: String,
+    initial: CpuState,
+    fin: CpuState,
+    cycles: Vec<BusAccess>,
+}
+
+fn field(v: &Value, name: &str) -> u64 {
+    v[name]
+        .as_u64()
+        .unwrap_or_else(|| panic!("Missing field {}", name))
+}
+
+fn pair(v: &Value, high: &str, low: &str) -> u16 {
+    ((field(v, high) as u16) << 8) | field(v, low) as u16
+}
+
+fn parse_state(v: &Value) -> CpuState {
+    let ram = v["ram"]
+        .as_array()
+        .expect("Missing ram")
+        .iter()
+        .map(|entry| (entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8))
+        .collect();
+
+    CpuState {
+        af: pair(v, "a", "f"),
+        bc: pair(v, "b", "c"),
+        de: pair(v, "d", "e"),
+        hl: pair(v, "h", "l"),
+        sp: field(v, "sp") as u16,
+        pc: field(v, "pc") as u16,
+        ime: field(v, "ime") != 0,
+        ie: field(v, "ie") as u8,
+        ram,
+    }
+}
+
+/// Parses a bus cycle, written as `[addr, value, "rwm"]`. Internal cycles have no value, or are null
+fn parse_cycle(v: &Value) -> BusAccess {
+    let kind = v[2].as_str().unwrap_or("---");
+    match (v[0].as_u64(), v[1].as_u64()) {
+        (Some(addr), Some(value)) if kind.contains('r') => BusAccess::Read(addr as u16, value as u8),
+        (Some(addr), Some(value)) if kind.contains('w') => BusAccess::Write(addr as u16, value as u8),
+        _ => BusAccess::Internal,
+    }
+}
+
+fn parse_cases(json: &str) -> Vec<Case> {
+    let cases: Value = serde_json::from_str(json).expect("Invalid test vector file");
+    cases
+        .as_array()
+        .expect("Test vector file is not a list of cases")
+        .iter()
+        .map(|case| Case {
+            name: case["name"].as_str().unwrap_or("").to_string(),
+            initial: parse_state(&case["initial"]),
+            fin: parse_state(&case["final"]),
+            cycles: case["cycles"]
+                .as_array()
+                .map(|cycles| cycles.iter().map(parse_cycle).collect())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Runs a single case, returning a description of every difference from the expected final state
+fn run_case(cpu: &mut CPU, case: &Case) -> Vec<String> {
+    let init = &case.initial;
+    cpu.reg = Registers {
+        af: init.af,
+        bc: init.bc,
+        de: init.de,
+        hl: init.hl,
+        sp: init.sp,
+        pc: init.pc,
+        ie: init.ime,
+    };
+    cpu.state = CPUState::default();
+    cpu.test_bus = Some(Vec::new());
+    cpu.mem.buffer[0xFFFF] = init.ie;
+    for (addr, value) in &init.ram {
+        cpu.mem.buffer[*addr as usize] = *value;
+    }
+
+    let ins = cpu.decode();
+    cpu.execute(ins);
+
+    let mut diffs = Vec::new();
+    let fin = &case.fin;
+    let regs = [
+        ("AF", cpu.reg.af, fin.af),
+        ("BC", cpu.reg.bc, fin.bc),
+        ("DE", cpu.reg.de, fin.de),
+        ("HL", cpu.reg.hl, fin.hl),
+        ("SP", cpu.reg.sp, fin.sp),
+        ("PC", cpu.reg.pc, fin.pc),
+    ];
+    for (name, actual, expected) in regs.iter() {
+        if actual != expected {
+            diffs.push(format!("{}: expected ${:04X}, got ${:04X}", name, expected, actual));
+        }
+    }
+
+    // EI and DI take effect at instruction boundaries in `tick`, so they are applied here as the vectors
+    // report the state at the start of the next instruction
+    let ime = (cpu.reg.ie || cpu.state.ei_pending) && !cpu.state.di_pending;
+    if ime != fin.ime {
+        diffs.push(format!("IME: expected {}, got {}", fin.ime, ime));
+    }
+    let ie = cpu.mem.buffer[0xFFFF];
+    if ie != fin.ie {
+        diffs.push(format!("IE: expected ${:02X}, got ${:02X}", fin.ie, ie));
+    }
+
+    for (addr, expected) in &fin.ram {
+        let actual = cpu.mem.buffer[*addr as usize];
+        if actual != *expected {
+            diffs.push(format!("[${:04X}]: expected ${:02X}, got ${:02X}", addr, expected, actual));
+        }
+    }
+
+    let log = cpu.test_bus.take().unwrap_or_default();
+    if log != case.cycles {
+        diffs.push(format!("Bus: expected {:X?}, got {:X?}", case.cycles, log));
+    }
+
+    // Leave memory clean for the next case
+    cpu.mem.buffer[0xFFFF] = 0;
+    for (addr, _) in init.ram.iter().chain(fin.ram.iter()) {
+        cpu.mem.buffer[*addr as usize] = 0;
+    }
+    for access in log {
+        if let BusAccess::Write(addr, _) = access {
+            cpu.mem.buffer[addr as usize] = 0;
+        }
+    }
+
+    diffs
+}
+
+/// Runs every case in a file, returning the number that passed and a report of the first failure.
+/// A case that panics counts as a failure, and the CPU is replaced as its state is no longer known
+fn run_file(cpu: &mut CPU, path: &Path) -> (usize, usize, Option<String>) {
+    let cases = parse_cases(&fs::read_to_string(path).unwrap());
+    let mut passed = 0;
+    let mut first_failure = None;
+    for case in &cases {
+        let diffs = match panic::catch_unwind(AssertUnwindSafe(|| run_case(cpu, case))) {
+            Ok(diffs) => diffs,
+            Err(payload) => {
+                *cpu = new_cpu();
+                let msg = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                vec![format!("Panicked: {}", msg)]
+            }
+        };
+        if diffs.is_empty() {
+            passed += 1;
+        } else if first_failure.is_none() {
+            first_failure = Some(format!("{}\n    {}", case.name, diffs.join("\n    ")));
+        }
+    }
+    (passed, cases.len(), first_failure)
+}
+
+fn new_cpu() -> CPU {
+    let mut cpu = CPU::headless();
+    cpu.mem.bootrom_paged = false;
+    cpu
+}
+
+#[test]
+fn test_conformance_case() {
+    // LD [HL+],A, with the vector format checked against a hand written case
+    let json = r#"[{
+        "name": "22 0000",
+        "initial": {"pc": 49152, "sp": 65534, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 176, "h": 192, "l": 255, "ime": 0, "ie": 5,
+                    "ram": [[49152, 34]]},
+        "final": {"pc": 49153, "sp": 65534, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 176, "h": 193, "l": 0, "ime": 0, "ie": 5,
+                  "ram": [[49152, 34], [49407, 66]]},
+        "cycles": [[49152, 34, "r-m"], [49407, 66, "-wm"]]
+    }]"#;
+
+    let mut cpu = new_cpu();
+    for case in parse_cases(json) {
+        assert_eq!(run_case(&mut cpu, &case), Vec::<String>::new());
+    }
+    assert!(cpu.mem.buffer.iter().all(|b| *b == 0));
+}
+
+/// Runs each file of vectors, named after its opcode, from `SM83_TESTS` or else `sm83/v1` in the crate root
+#[test]
+#[ignore = "needs the SM83 test vectors, which aren't kept in the repo"]
+fn test_sm83_vectors() {
+    let dir = std::env::var("SM83_TESTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| Path::new(env!("CARGO_MANIFEST_DIR")).join("sm83/v1"));
+    let mut files = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<PathBuf>>(),
+        Err(err) => panic!("No SM83 test vectors at {}: {}", dir.display(), err),
+    };
+    files.sort();
+
+    let mut cpu = new_cpu();
+    let mut failed = Vec::new();
+    for path in &files {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let (passed, total, failure) = run_file(&mut cpu, path);
+        match failure {
+            None => println!("PASS {} ({}/{})", name, passed, total),
+            Some(report) => {
+                println!("FAIL {} ({}/{}): {}", name, passed, total, report);
+                failed.push(name);
+            }
+        }
+    }
+
+    assert!(failed.is_empty(), "{} of {} opcodes failed: {}", failed.len(), files.len(), failed.join(", "));
+}
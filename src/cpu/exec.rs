@@ -1,46 +1,100 @@
 use super::*;
 
 impl CPU {
+    /// Reads an 8 bit operand. The memory operands (HL), (BC) and (DE) go through the bus, taking an M-cycle
+    fn read_operand(&mut self, reg: RegisterName) -> u8 {
+        match reg {
+            RegisterName::HLRef => self.read8(self.reg.hl),
+            RegisterName::BCRef => self.read8(self.reg.bc),
+            RegisterName::DERef => self.read8(self.reg.de),
+            _ => self.reg.read8(reg).unwrap(),
+        }
+    }
+
+    /// Writes an 8 bit operand. The memory operands (HL), (BC) and (DE) go through the bus, taking an M-cycle
+    fn write_operand(&mut self, reg: RegisterName, value: u8) {
+        match reg {
+            RegisterName::HLRef => self.write8(self.reg.hl, value),
+            RegisterName::BCRef => self.write8(self.reg.bc, value),
+            RegisterName::DERef => self.write8(self.reg.de, value),
+            _ => self.reg.write8(reg, value),
+        }
+    }
+
+    /// Adds to A, with the carry flag added in as well for ADC
+    fn add8(&mut self, n: u8, carry: bool) {
+        let a = self.reg.read8(RegisterName::A).unwrap();
+        let c = carry as u8;
+        let result = a.wrapping_add(n).wrapping_add(c);
+        let hc = (a & 0xf) + (n & 0xf) + c > 0xf;
+        let overflow = a as u16 + n as u16 + c as u16 > 0xff;
+        self.reg.set_flags(result == 0, false, hc, overflow);
+        self.reg.write8(RegisterName::A, result);
+    }
+
+    /// Subtracts from A, with the carry flag taken off as well for SBC. Only the flags are set, so CP can use it
+    fn sub8(&mut self, n: u8, carry: bool) -> u8 {
+        let a = self.reg.read8(RegisterName::A).unwrap();
+        let c = carry as u8;
+        let result = a.wrapping_sub(n).wrapping_sub(c);
+        let hc = (a & 0xf) < (n & 0xf) + c;
+        let borrow = (a as u16) < n as u16 + c as u16;
+        self.reg.set_flags(result == 0, true, hc, borrow);
+        result
+    }
+
+    /// Adds a signed offset to SP. The flags come from adding the offset to the low byte as unsigned
+    fn add_sp(&mut self, n: i8) -> u16 {
+        let sp = self.reg.read16(RegisterName::SP).unwrap();
+        let e = n as u8 as u16;
+        let hc = (sp & 0xf) + (e & 0xf) > 0xf;
+        let overflow = (sp & 0xff) + e > 0xff;
+        self.reg.set_flags(false, false, hc, overflow);
+        sp.wrapping_add(n as i16 as u16)
+    }
+
+    /// Evaluates a branch condition against the flags
+    fn condition(&self, cond: JpCond) -> bool {
+        match cond {
+            JpCond::NotZero => !self.reg.flag(Flag::Zero),
+            JpCond::Zero => self.reg.flag(Flag::Zero),
+            JpCond::NotCarry => !self.reg.flag(Flag::Carry),
+            JpCond::Carry => self.reg.flag(Flag::Carry),
+        }
+    }
+
+    /// Pops the return address into PC, matching the return up with the call stack
+    fn ret(&mut self) {
+        let sp = self.reg.sp;
+        let pc = self.reg.pc.wrapping_sub(1);
+        self.reg.pc = self.pop16();
+        self.calls.ret(pc, self.reg.pc, sp);
+    }
+
     /// Executes the given Z80 instruction, updating registers and memory as appropriate
     ///
-    /// This function returns an integer with the number of clock cycles that this operation would take
-    /// on a genuine gameboy.
+    /// Every memory access is made through the bus on the M-cycle it happens on real hardware, with internal
+    /// M-cycles inserted where the instruction has them, so peripherals advance during the instruction.
+    ///
+    /// This function returns the number of clock cycles the instruction took, counted from the fetch of its opcode in `decode`.
     pub fn execute(&mut self, ins: Instruction) -> u8 {
         match ins {
-            Instruction::Ld8Reg(r1, r2) => match (r1, r2) {
-                (RegisterName::HLRef, reg) => {
-                    let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    self.mem.set_addr(hl, self.reg.read8(reg).unwrap());
-                    8
-                }
-                (reg, RegisterName::HLRef) => {
-                    let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    self.reg.write8(reg, self.mem.get_addr(hl));
-                    8
-                }
-                (reg, RegisterName::BCRef) => {
-                    let bc = self.reg.read16(RegisterName::BC).unwrap();
-                    self.reg.write8(reg, self.mem.get_addr(bc));
-                    8
-                }
-                (reg, RegisterName::DERef) => {
-                    let de = self.reg.read16(RegisterName::DE).unwrap();
-                    self.reg.write8(reg, self.mem.get_addr(de));
-                    8
-                }
-                _ => {
-                    self.reg.write8(r1, self.reg.read8(r2).unwrap());
-                    4
-                }
-            },
-            Instruction::IllInsn => 1,
+            Instruction::Ld8Reg(r1, r2) => {
+                let value = self.read_operand(r2);
+                self.write_operand(r1, value);
+            }
+            Instruction::IllInsn => {}
             Instruction::Ld8Imm(r1, value) => {
-                self.reg.write8(r1, value);
-                8
+                self.write_operand(r1, value);
             }
             Instruction::Halt => {
-                self.state.halted = true;
-                4
+                if !self.reg.ie && self.pending_interrupts() != 0 {
+                    // With IME=0 and an interrupt already pending HALT is not entered at all,
+                    // instead triggering the HALT bug on the following fetch
+                    self.state.halt_bug = true;
+                } else {
+                    self.state.halted = true;
+                }
             }
             Instruction::Nop => {
                 // println!("TILEMAP");
@@ -48,249 +102,135 @@ impl CPU {
                 //     println!("{:#x}: {:#x}", addr, self.mem.get_addr(addr));
                 // }
                 // panic!();
-                4
             }
             Instruction::StoSP(addr) => {
                 let sp = self.reg.read16(RegisterName::SP).unwrap();
                 let bytes = sp.to_le_bytes();
-                self.mem.set_addr(addr, bytes[0]);
-                self.mem.set_addr(addr + 1, bytes[1]);
-                20
+                self.write8(addr, bytes[0]);
+                self.write8(addr.wrapping_add(1), bytes[1]);
             }
             Instruction::Stop => {
-                self.state.stopped = true;
-                4
+                // DIV is reset whenever STOP is executed
+                self.reset_div();
+
+                let key1 = self.mem.get_register(MemoryRegister::KEY1);
+                if key1 & 1 != 0 {
+                    // A speed switch was armed through KEY1, STOP performs the switch instead of entering low power mode
+                    self.state.double_speed = !self.state.double_speed;
+                    let speed = if self.state.double_speed { 0x80 } else { 0x00 };
+                    self.mem.set_register(MemoryRegister::KEY1, speed);
+                } else {
+                    self.state.stopped = true;
+                }
             }
             Instruction::LddHLA => {
                 let addr = self.reg.read16(RegisterName::HL).unwrap();
-                self.mem
-                    .set_addr(addr, self.reg.read8(RegisterName::A).unwrap());
-                self.reg.write16(RegisterName::HL, addr - 1);
-                8
+                self.write8(addr, self.reg.read8(RegisterName::A).unwrap());
+                self.reg.write16(RegisterName::HL, addr.wrapping_sub(1));
             }
             Instruction::LddAHL => {
                 let addr = self.reg.read16(RegisterName::HL).unwrap();
-                self.reg.write8(RegisterName::A, self.mem.get_addr(addr));
-                self.reg.write16(RegisterName::HL, addr - 1);
-                8
+                let value = self.read8(addr);
+                self.reg.write8(RegisterName::A, value);
+                self.reg.write16(RegisterName::HL, addr.wrapping_sub(1));
             }
             Instruction::LdiAHL => {
                 let addr = self.reg.read16(RegisterName::HL).unwrap();
-                self.reg.write8(RegisterName::A, self.mem.get_addr(addr));
-                self.reg.write16(RegisterName::HL, addr + 1);
-                8
+                let value = self.read8(addr);
+                self.reg.write8(RegisterName::A, value);
+                self.reg.write16(RegisterName::HL, addr.wrapping_add(1));
             }
             Instruction::LdiHLA => {
                 let addr = self.reg.read16(RegisterName::HL).unwrap();
-                self.mem
-                    .set_addr(addr, self.reg.read8(RegisterName::A).unwrap());
-                self.reg.write16(RegisterName::HL, addr + 1);
-                8
+                self.write8(addr, self.reg.read8(RegisterName::A).unwrap());
+                self.reg.write16(RegisterName::HL, addr.wrapping_add(1));
             }
             Instruction::LdhN(n) => {
-                self.mem.set_addr(
+                self.write8(
                     0xFF00 + (n as u16),
                     self.reg.read8(RegisterName::A).unwrap(),
                 );
-                12
             }
             Instruction::LdhA(n) => {
-                self.reg
-                    .write8(RegisterName::A, self.mem.get_addr(0xFF00 + (n as u16)));
-                12
+                let value = self.read8(0xFF00 + (n as u16));
+                self.reg.write8(RegisterName::A, value);
             }
             Instruction::Lda(addr) => {
-                self.reg.write8(RegisterName::A, self.mem.get_addr(addr));
-                16
+                let value = self.read8(addr);
+                self.reg.write8(RegisterName::A, value);
             }
             Instruction::LdaNN(addr) => {
-                self.mem
-                    .set_addr(addr, self.reg.read8(RegisterName::A).unwrap());
-                16
+                self.write8(addr, self.reg.read8(RegisterName::A).unwrap());
             }
             Instruction::Ldac => {
                 let addr = 0xFF00 + self.reg.read8(RegisterName::C).unwrap() as u16;
-                self.reg.write8(RegisterName::A, self.mem.get_addr(addr));
-                8
+                let value = self.read8(addr);
+                self.reg.write8(RegisterName::A, value);
             }
             Instruction::Ldca => {
                 let addr = 0xFF00 + self.reg.read8(RegisterName::C).unwrap() as u16;
-                self.mem
-                    .set_addr(addr, self.reg.read8(RegisterName::A).unwrap());
-                8
+                self.write8(addr, self.reg.read8(RegisterName::A).unwrap());
             }
             Instruction::Ld16Imm(reg, val) => {
                 self.reg.write16(reg, val);
-                12
             }
             Instruction::LdSPHL => {
                 self.reg
                     .write16(RegisterName::SP, self.reg.read16(RegisterName::HL).unwrap());
-                8
+                self.internal_cycle();
             }
             Instruction::LdHLSPn(n) => {
-                let sp = self.reg.read16(RegisterName::SP).unwrap();
-                let (offset, overflow) = (sp as i16).overflowing_add(n as i16);
-
-                let sp_lower = sp & 0xf;
-                let n_lower = n & 0xf;
-
-                let hc = (sp_lower as i8) + n_lower;
-
-                self.reg.write16(RegisterName::HL, offset as u16);
-                self.reg
-                    .set_flags(false, false, hc & (1 << 4) != 0, overflow);
-                12
+                let result = self.add_sp(n);
+                self.reg.write16(RegisterName::HL, result);
+                self.internal_cycle();
             }
             Instruction::LdnnSP(nn) => {
                 let bytes = self.reg.read16(RegisterName::SP).unwrap().to_le_bytes();
-                self.mem.set_addr(nn, bytes[0]);
-                self.mem.set_addr(nn + 1, bytes[1]);
-                20
+                self.write8(nn, bytes[0]);
+                self.write8(nn.wrapping_add(1), bytes[1]);
             }
             Instruction::Push(reg) => {
-                let bytes = self.reg.read16(reg).unwrap().to_le_bytes();
-                let sp = self.reg.read16(RegisterName::SP).unwrap() - 2;
-                self.mem.set_addr(sp, bytes[0]);
-                self.mem.set_addr(sp + 1, bytes[1]);
-                self.reg.write16(RegisterName::SP, sp);
-                16
+                let value = self.reg.read16(reg).unwrap();
+                self.internal_cycle();
+                self.push16(value);
             }
             Instruction::Pop(reg) => {
-                let sp = self.reg.read16(RegisterName::SP).unwrap();
-
-                let bytes = [self.mem.get_addr(sp), self.mem.get_addr(sp + 1)];
-                self.reg.write16(reg, u16::from_le_bytes(bytes));
-                self.reg.write16(RegisterName::SP, sp + 2);
-                12
-            }
-            Instruction::AddImm(n) => {
-                let a = self.reg.read8(RegisterName::A).unwrap();
-                let (result, overflow) = a.overflowing_add(n);
-
-                let half_add = a & 0xf + n & 0xf;
-
-                self.reg.write8(RegisterName::A, result);
-                self.reg
-                    .set_flags(result == 0, false, half_add & (1 << 4) != 0, overflow);
-                8
+                let value = self.pop16();
+                self.reg.write16(reg, value);
             }
+            Instruction::AddImm(n) => self.add8(n, false),
             Instruction::AddReg(reg) => {
-                let a = self.reg.read8(RegisterName::A).unwrap();
-                let b = match reg {
-                    RegisterName::HLRef => self
-                        .mem
-                        .get_addr(self.reg.read16(RegisterName::HL).unwrap()),
-                    _ => self.reg.read8(reg).unwrap(),
-                };
-
-                let (result, overflow) = a.overflowing_add(b);
-
-                let half_add = a & 0xf + b & 0xf;
-
-                self.reg.write8(RegisterName::A, result);
-                self.reg
-                    .set_flags(result == 0, false, half_add & (1 << 4) != 0, overflow);
-                if reg == RegisterName::HLRef {
-                    8
-                } else {
-                    4
-                }
+                let n = self.read_operand(reg);
+                self.add8(n, false);
             }
             Instruction::AdcImm(n) => {
-                let carry = if self.reg.flag(Flag::Carry) { 1 } else { 0 };
-                let a = self.reg.read8(RegisterName::A).unwrap();
-
-                let (result, overflow) = a.overflowing_add(n + carry);
-
-                let half_add = a & 0xf + (n + carry) & 0xf;
-
-                self.reg
-                    .set_flags(result == 0, false, half_add & (1 << 4) != 0, overflow);
-                self.reg.write8(RegisterName::A, result);
-                8
+                let carry = self.reg.flag(Flag::Carry);
+                self.add8(n, carry);
             }
             Instruction::AdcReg(reg) => {
-                let carry = if self.reg.flag(Flag::Carry) { 1 } else { 0 };
-                let a = self.reg.read8(RegisterName::A).unwrap();
-
-                let b = match reg {
-                    RegisterName::HLRef => self
-                        .mem
-                        .get_addr(self.reg.read16(RegisterName::HL).unwrap()),
-                    _ => self.reg.read8(reg).unwrap(),
-                };
-
-                let (result, overflow) = a.overflowing_add(b + carry);
-
-                let half_add = a & 0xf + (b + carry) & 0xf;
-
-                self.reg
-                    .set_flags(result == 0, false, half_add & (1 << 4) != 0, overflow);
-                self.reg.write8(RegisterName::A, result);
-                if reg == RegisterName::HLRef {
-                    8
-                } else {
-                    4
-                }
+                let carry = self.reg.flag(Flag::Carry);
+                let n = self.read_operand(reg);
+                self.add8(n, carry);
             }
             Instruction::SubImm(n) => {
-                let a = self.reg.read8(RegisterName::A).unwrap();
-                let result = a.wrapping_sub(n);
-                let hc = result & (1 << 4) != 0;
-                self.reg.set_flags(result == 0, true, hc, a < n);
+                let result = self.sub8(n, false);
                 self.reg.write8(RegisterName::A, result);
-                8
             }
             Instruction::SubReg(reg) => {
-                let a = self.reg.read8(RegisterName::A).unwrap();
-                let n = match reg {
-                    RegisterName::HLRef => self
-                        .mem
-                        .get_addr(self.reg.read16(RegisterName::HL).unwrap()),
-                    _ => self.reg.read8(reg).unwrap(),
-                };
-                let result = a.wrapping_sub(n);
-                let hc = result & (1 << 4) != 0;
-                self.reg.set_flags(result == 0, true, hc, a < n);
+                let n = self.read_operand(reg);
+                let result = self.sub8(n, false);
                 self.reg.write8(RegisterName::A, result);
-                if reg == RegisterName::HLRef {
-                    8
-                } else {
-                    4
-                }
             }
             Instruction::SbcImm(n) => {
-                let c = if self.reg.flag(Flag::Carry) { 1 } else { 0 };
-                let a = self.reg.read8(RegisterName::A).unwrap();
-
-                let result = a.wrapping_sub(n + c);
-                let hc = result & (1 << 4) != 0;
-                self.reg.set_flags(result == 0, true, hc, a < (n + c));
-                8
+                let carry = self.reg.flag(Flag::Carry);
+                let result = self.sub8(n, carry);
+                self.reg.write8(RegisterName::A, result);
             }
             Instruction::SbcReg(reg) => {
-                let c = if self.reg.flag(Flag::Carry) { 1 } else { 0 };
-                let a = self.reg.read8(RegisterName::A).unwrap();
-                match reg {
-                    RegisterName::HLRef => {
-                        let hl = self.reg.read16(RegisterName::HL).unwrap();
-                        let n = self.mem.get_addr(hl);
-                        let result = a.wrapping_sub(n + c);
-                        let hc = result & (1 << 4) != 0;
-                        self.reg.set_flags(result == 0, true, hc, a < n);
-                        self.reg.write8(RegisterName::A, result);
-                        8
-                    }
-                    _ => {
-                        let n = self.reg.read8(reg).unwrap();
-                        let result = a.wrapping_sub(n + c);
-                        let hc = result & (1 << 4) != 0;
-                        self.reg.set_flags(result == 0, true, hc, a < n);
-                        self.reg.write8(RegisterName::A, result);
-                        4
-                    }
-                }
+                let carry = self.reg.flag(Flag::Carry);
+                let n = self.read_operand(reg);
+                let result = self.sub8(n, carry);
+                self.reg.write8(RegisterName::A, result);
             }
             Instruction::AndImm(n) => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
@@ -299,25 +239,14 @@ impl CPU {
 
                 self.reg.set_flags(result == 0, false, true, false);
                 self.reg.write8(RegisterName::A, result);
-                8
             }
             Instruction::AndReg(reg) => {
-                let n = match reg {
-                    RegisterName::HLRef => self
-                        .mem
-                        .get_addr(self.reg.read16(RegisterName::HL).unwrap()),
-                    _ => self.reg.read8(reg).unwrap(),
-                };
+                let n = self.read_operand(reg);
                 let a = self.reg.read8(RegisterName::A).unwrap();
 
                 let result = a & n;
                 self.reg.set_flags(result == 0, false, true, false);
                 self.reg.write8(RegisterName::A, result);
-                if reg == RegisterName::HLRef {
-                    8
-                } else {
-                    4
-                }
             }
             Instruction::OrImm(n) => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
@@ -325,25 +254,14 @@ impl CPU {
                 let result = a | n;
                 self.reg.set_flags(result == 0, false, false, false);
                 self.reg.write8(RegisterName::A, result);
-                8
             }
             Instruction::OrReg(reg) => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
-                let n = match reg {
-                    RegisterName::HLRef => self
-                        .mem
-                        .get_addr(self.reg.read16(RegisterName::HL).unwrap()),
-                    _ => self.reg.read8(reg).unwrap(),
-                };
+                let n = self.read_operand(reg);
 
                 let result = a | n;
                 self.reg.set_flags(result == 0, false, false, false);
                 self.reg.write8(RegisterName::A, result);
-                if reg == RegisterName::HLRef {
-                    8
-                } else {
-                    4
-                }
             }
             Instruction::XorImm(n) => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
@@ -351,63 +269,32 @@ impl CPU {
                 let result = a ^ n;
                 self.reg.set_flags(result == 0, false, false, false);
                 self.reg.write8(RegisterName::A, result);
-                8
             }
             Instruction::XorReg(reg) => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
-                let n = match reg {
-                    RegisterName::HLRef => self
-                        .mem
-                        .get_addr(self.reg.read16(RegisterName::HL).unwrap()),
-                    _ => self.reg.read8(reg).unwrap(),
-                };
+                let n = self.read_operand(reg);
 
                 let result = a ^ n;
                 self.reg.set_flags(result == 0, false, false, false);
                 self.reg.write8(RegisterName::A, result);
-                if reg == RegisterName::HLRef {
-                    8
-                } else {
-                    4
-                }
             }
             Instruction::CpImm(n) => {
-                let a = self.reg.read8(RegisterName::A).unwrap();
-                let result = a.wrapping_sub(n);
-                let hc = result & (1 << 4) != 0;
-                self.reg.set_flags(result == 0, true, hc, a < n);
-                8
+                self.sub8(n, false);
             }
             Instruction::CpReg(reg) => {
-                let a = self.reg.read8(RegisterName::A).unwrap();
-                let n = match reg {
-                    RegisterName::HLRef => self
-                        .mem
-                        .get_addr(self.reg.read16(RegisterName::HL).unwrap()),
-                    _ => self.reg.read8(reg).unwrap(),
-                };
-
-                let result = a - n;
-                let hc = result & (1 << 4) != 0;
-
-                self.reg.set_flags(result == 0, true, hc, a < n);
-                if reg == RegisterName::HLRef {
-                    8
-                } else {
-                    4
-                }
+                let n = self.read_operand(reg);
+                self.sub8(n, false);
             }
             Instruction::Inc(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let x = self.mem.get_addr(hl);
+                    let x = self.read8(hl);
                     let (result, _) = x.overflowing_add(1);
                     let hc = x & 0xf == 0xf;
                     self.reg.set_flag(Flag::Zero, result == 0);
                     self.reg.set_flag(Flag::AddSub, false);
                     self.reg.set_flag(Flag::HalfCarry, hc);
-                    self.mem.set_addr(hl, result);
-                    12
+                    self.write8(hl, result);
                 }
                 _ => {
                     let x = self.reg.read8(reg).unwrap();
@@ -418,34 +305,29 @@ impl CPU {
                     self.reg.set_flag(Flag::AddSub, false);
                     self.reg.set_flag(Flag::HalfCarry, hc);
                     self.reg.write8(reg, result);
-                    4
                 }
             },
             Instruction::Dec(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let a = self.mem.get_addr(hl);
+                    let a = self.read8(hl);
                     let result = a.wrapping_sub(1);
-                    let hc = result & (1 << 4) != 0;
+                    let hc = a & 0xf == 0;
 
                     self.reg.set_flag(Flag::Zero, result == 0);
                     self.reg.set_flag(Flag::AddSub, true);
                     self.reg.set_flag(Flag::HalfCarry, hc);
-                    self.mem.set_addr(hl, result);
-
-                    12
+                    self.write8(hl, result);
                 }
                 _ => {
                     let a = self.reg.read8(reg).unwrap();
                     let result = a.wrapping_sub(1);
-                    let hc = result & (1 << 4) != 0;
+                    let hc = a & 0xf == 0;
 
                     self.reg.set_flag(Flag::Zero, result == 0);
                     self.reg.set_flag(Flag::AddSub, true);
                     self.reg.set_flag(Flag::HalfCarry, hc);
                     self.reg.write8(reg, result);
-
-                    4
                 }
             },
             Instruction::Add16(reg) => {
@@ -455,45 +337,40 @@ impl CPU {
                 let result = hl.wrapping_add(reg);
 
                 self.reg.set_flag(Flag::AddSub, false);
-                self.reg.set_flag(Flag::HalfCarry, result & (1 << 11) != 0);
-                self.reg.set_flag(Flag::Carry, result & (1 << 15) != 0);
-                self.reg.write16(RegisterName::HL, reg + hl);
-
-                8
+                self.reg.set_flag(Flag::HalfCarry, (hl & 0xfff) + (reg & 0xfff) > 0xfff);
+                self.reg.set_flag(Flag::Carry, hl as u32 + reg as u32 > 0xffff);
+                self.reg.write16(RegisterName::HL, result);
+                self.internal_cycle();
             }
             Instruction::AddSP(n) => {
-                let sp = self.reg.read16(RegisterName::SP).unwrap();
-                let result = (sp as i16 + n as i16) as u16;
-                self.reg.set_flag(Flag::HalfCarry, result & (1 << 11) != 0);
-                self.reg.set_flag(Flag::Carry, result & (1 << 15) != 0);
+                let result = self.add_sp(n);
                 self.reg.write16(RegisterName::SP, result);
-                16
+                self.internal_cycle();
+                self.internal_cycle();
             }
             Instruction::Inc16(reg) => {
                 let value = self.reg.read16(reg).unwrap();
                 self.reg.write16(reg, value.wrapping_add(1));
-                8
+                self.internal_cycle();
             }
             Instruction::Dec16(reg) => {
                 let value = self.reg.read16(reg).unwrap();
                 self.reg.write16(reg, value.wrapping_sub(1));
-                8
+                self.internal_cycle();
             }
             Instruction::Swap(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let x = self.mem.get_addr(hl);
+                    let x = self.read8(hl);
                     let low = (x & 0xf0) >> 4;
                     let high = x & 0xf << 4;
-                    self.mem.set_addr(hl, high | low);
-                    16
+                    self.write8(hl, high | low);
                 }
                 _ => {
                     let x = self.reg.read8(reg).unwrap();
                     let low = (x & 0xf0) >> 4;
                     let high = x & 0xf << 4;
                     self.reg.write8(reg, high | low);
-                    8
                 }
             },
             Instruction::Daa => {
@@ -528,34 +405,28 @@ impl CPU {
                 self.reg.set_flag(Flag::HalfCarry, false);
                 self.reg.set_flag(Flag::Carry, carry);
                 self.reg.write8(RegisterName::A, result);
-                4
             }
             Instruction::Cpl => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
                 self.reg.set_flag(Flag::AddSub, true);
                 self.reg.set_flag(Flag::HalfCarry, true);
                 self.reg.write8(RegisterName::A, !a);
-                4
             }
             Instruction::Ccf => {
                 self.reg.set_flag(Flag::AddSub, false);
                 self.reg.set_flag(Flag::HalfCarry, false);
                 self.reg.set_flag(Flag::Carry, !self.reg.flag(Flag::Carry));
-                4
             }
             Instruction::Scf => {
                 self.reg.set_flag(Flag::AddSub, false);
                 self.reg.set_flag(Flag::HalfCarry, false);
                 self.reg.set_flag(Flag::Carry, true);
-                4
             }
             Instruction::Di => {
                 self.state.di_pending = true;
-                4
             }
             Instruction::Ei => {
                 self.state.ei_pending = true;
-                4
             }
             Instruction::Rlca => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
@@ -563,7 +434,6 @@ impl CPU {
                 let result = a << 1 | (top >> 7);
                 self.reg.write8(RegisterName::A, result);
                 self.reg.set_flags(result == 0, false, false, top != 0);
-                4
             }
             Instruction::Rla => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
@@ -572,7 +442,6 @@ impl CPU {
                 let result = (a << 1) | c;
                 self.reg.write8(RegisterName::A, result);
                 self.reg.set_flags(result == 0, false, false, top != 0);
-                4
             }
             Instruction::Rrca => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
@@ -580,7 +449,6 @@ impl CPU {
                 let result = (bottom << 7) | a >> 1;
                 self.reg.write8(RegisterName::A, result);
                 self.reg.set_flags(result == 0, false, false, bottom != 0);
-                4
             }
             Instruction::Rra => {
                 let a = self.reg.read8(RegisterName::A).unwrap();
@@ -589,17 +457,15 @@ impl CPU {
                 let result = (a >> 1) | (c << 7);
                 self.reg.write8(RegisterName::A, result);
                 self.reg.set_flags(result == 0, false, false, bottom != 0);
-                4
             }
             Instruction::Rlc(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let top = r & 0x80;
                     let result = r << 1 | (top >> 7);
-                    self.mem.set_addr(hl, result);
+                    self.write8(hl, result);
                     self.reg.set_flags(result == 0, false, false, top != 0);
-                    16
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
@@ -607,19 +473,17 @@ impl CPU {
                     let result = r << 1 | (top >> 7);
                     self.reg.write8(reg, result);
                     self.reg.set_flags(result == 0, false, false, top != 0);
-                    8
                 }
             },
             Instruction::Rl(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let c = if self.reg.flag(Flag::Carry) { 1 } else { 0 };
                     let top = r & 0x80;
                     let result = (r << 1) | c;
-                    self.mem.set_addr(hl, result);
+                    self.write8(hl, result);
                     self.reg.set_flags(result == 0, false, false, top != 0);
-                    16
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
@@ -628,18 +492,16 @@ impl CPU {
                     let result = (r << 1) | c;
                     self.reg.write8(reg, result);
                     self.reg.set_flags(result == 0, false, false, top != 0);
-                    8
                 }
             },
             Instruction::Rrc(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let bottom = r & 1;
                     let result = (bottom << 7) | r >> 1;
-                    self.mem.set_addr(hl, result);
+                    self.write8(hl, result);
                     self.reg.set_flags(result == 0, false, false, bottom != 0);
-                    16
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
@@ -647,20 +509,18 @@ impl CPU {
                     let result = (bottom << 7) | r >> 1;
                     self.reg.write8(reg, result);
                     self.reg.set_flags(result == 0, false, false, bottom != 0);
-                    8
                 }
             },
             Instruction::Rr(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let c = if self.reg.flag(Flag::Carry) { 1 } else { 0 };
                     let bottom = r & 1;
                     let result = (c << 7) | (r >> 1);
 
-                    self.mem.set_addr(hl, result);
+                    self.write8(hl, result);
                     self.reg.set_flags(result == 0, false, false, bottom != 0);
-                    16
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
@@ -670,18 +530,16 @@ impl CPU {
 
                     self.reg.write8(reg, result);
                     self.reg.set_flags(result == 0, false, false, bottom != 0);
-                    8
                 }
             },
             Instruction::Sla(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let top = r & 0x80;
                     let result = r << 1;
-                    self.mem.set_addr(hl, result);
+                    self.write8(hl, result);
                     self.reg.set_flags(result == 0, false, false, top != 0);
-                    16
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
@@ -689,19 +547,17 @@ impl CPU {
                     let result = r << 1;
                     self.reg.write8(reg, result);
                     self.reg.set_flags(result == 0, false, false, top != 0);
-                    8
                 }
             },
             Instruction::Sra(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let top = r & 0x80;
                     let bottom = r & 1;
                     let result = top | r >> 1;
-                    self.mem.set_addr(hl, result);
+                    self.write8(hl, result);
                     self.reg.set_flags(result == 0, false, false, bottom != 0);
-                    16
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
@@ -710,18 +566,16 @@ impl CPU {
                     let result = top | r >> 1;
                     self.reg.write8(reg, result);
                     self.reg.set_flags(result == 0, false, false, bottom != 0);
-                    8
                 }
             },
             Instruction::Srl(reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let bottom = r & 1;
                     let result = r >> 1;
-                    self.mem.set_addr(hl, result);
+                    self.write8(hl, result);
                     self.reg.set_flags(result == 0, false, false, bottom != 0);
-                    16
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
@@ -729,227 +583,102 @@ impl CPU {
                     let result = r >> 1;
                     self.reg.write8(reg, result);
                     self.reg.set_flags(result == 0, false, false, bottom != 0);
-                    8
                 }
             },
             Instruction::Bit(b, reg) => {
-                let r = match reg {
-                    RegisterName::HLRef => self
-                        .mem
-                        .get_addr(self.reg.read16(RegisterName::HL).unwrap()),
-                    _ => self.reg.read8(reg).unwrap(),
-                };
+                let r = self.read_operand(reg);
 
                 self.reg.set_flag(Flag::Zero, r & (1 << b) == 0);
                 self.reg.set_flag(Flag::AddSub, false);
                 self.reg.set_flag(Flag::HalfCarry, true);
-                if reg == RegisterName::HL {
-                    16
-                } else {
-                    8
-                }
             }
             Instruction::Set(b, reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let result = r | (1 << b);
-                    self.mem.set_addr(hl, result);
-                    16
+                    self.write8(hl, result);
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
                     let result = r | (1 << b);
                     self.reg.write8(reg, result);
-                    8
                 }
             },
             Instruction::Res(b, reg) => match reg {
                 RegisterName::HLRef => {
                     let hl = self.reg.read16(RegisterName::HL).unwrap();
-                    let r = self.mem.get_addr(hl);
+                    let r = self.read8(hl);
                     let result = r & !(1 << b);
-                    self.mem.set_addr(hl, result);
-                    16
+                    self.write8(hl, result);
                 }
                 _ => {
                     let r = self.reg.read8(reg).unwrap();
                     let result = r & !(1 << b);
                     self.reg.write8(reg, result);
-                    8
                 }
             },
             Instruction::Jp(addr) => {
                 self.reg.pc = addr;
-                12
+                self.internal_cycle();
             }
             Instruction::JpCond(cond, addr) => {
-                match cond {
-                    JpCond::Carry => {
-                        if self.reg.flag(Flag::Carry) {
-                            self.reg.pc = addr;
-                        }
-                    }
-                    JpCond::NotCarry => {
-                        if !self.reg.flag(Flag::Carry) {
-                            self.reg.pc = addr;
-                        }
-                    }
-                    JpCond::Zero => {
-                        if self.reg.flag(Flag::Zero) {
-                            self.reg.pc = addr;
-                        }
-                    }
-                    JpCond::NotZero => {
-                        if !self.reg.flag(Flag::Zero) {
-                            self.reg.pc = addr;
-                        }
-                    }
+                if self.condition(cond) {
+                    self.reg.pc = addr;
+                    self.internal_cycle();
                 }
-                12
             }
             Instruction::Jr(rel) => {
-                let pc = self.reg.pc;
-                self.reg.pc = (pc as i32 + rel as i32) as u16;
-                println!("JR PC OLD :{:#x}, NEW {:#x}", pc, self.reg.pc);
-
-                8
+                self.reg.pc = (self.reg.pc as i32 + rel as i32) as u16;
+                self.internal_cycle();
             }
             Instruction::JrCond(cond, rel) => {
-                let pc = self.reg.pc;
-                let new_pc = (pc as i32 + rel as i32) as u16;
-
-                match cond {
-                    JpCond::Carry => {
-                        if self.reg.flag(Flag::Carry) {
-                            self.reg.pc = new_pc;
-                        }
-                    }
-                    JpCond::NotCarry => {
-                        if !self.reg.flag(Flag::Carry) {
-                            self.reg.pc = new_pc;
-                        }
-                    }
-                    JpCond::Zero => {
-                        if self.reg.flag(Flag::Zero) {
-                            self.reg.pc = new_pc;
-                        }
-                    }
-                    JpCond::NotZero => {
-                        if !self.reg.flag(Flag::Zero) {
-                            self.reg.pc = new_pc;
-                        }
-                    }
+                if self.condition(cond) {
+                    self.reg.pc = (self.reg.pc as i32 + rel as i32) as u16;
+                    self.internal_cycle();
                 }
-                8
             }
             Instruction::JpHL => {
                 self.reg.pc = self.reg.read16(RegisterName::HL).unwrap();
-                4
             }
             Instruction::Call(addr) => {
-                let pc = self.reg.pc.to_le_bytes();
-                let sp = self.reg.read16(RegisterName::SP).unwrap() - 2;
-                self.mem.set_addr(sp, pc[0]);
-                self.mem.set_addr(sp + 1, pc[1]);
+                self.internal_cycle();
+                self.push16(self.reg.pc);
+                self.calls.call(self.reg.pc.wrapping_sub(3), addr, self.reg.pc, self.reg.sp, false);
                 self.reg.pc = addr;
-                self.reg.write16(RegisterName::SP, sp);
-                12
             }
             Instruction::CallCond(cond, addr) => {
-                let pc = self.reg.pc.to_le_bytes();
-                let sp = self.reg.read16(RegisterName::SP).unwrap() - 2;
-                match cond {
-                    JpCond::Carry => {
-                        if self.reg.flag(Flag::Carry) {
-                            self.mem.set_addr(sp, pc[0]);
-                            self.mem.set_addr(sp + 1, pc[1]);
-                            self.reg.pc = addr;
-                            self.reg.write16(RegisterName::SP, sp);
-                        }
-                    }
-                    JpCond::NotCarry => {
-                        if !self.reg.flag(Flag::Carry) {
-                            self.mem.set_addr(sp, pc[0]);
-                            self.mem.set_addr(sp + 1, pc[1]);
-                            self.reg.pc = addr;
-                            self.reg.write16(RegisterName::SP, sp);
-                        }
-                    }
-                    JpCond::Zero => {
-                        if self.reg.flag(Flag::Zero) {
-                            self.mem.set_addr(sp, pc[0]);
-                            self.mem.set_addr(sp + 1, pc[1]);
-                            self.reg.pc = addr;
-                            self.reg.write16(RegisterName::SP, sp);
-                        }
-                    }
-                    JpCond::NotZero => {
-                        if !self.reg.flag(Flag::Zero) {
-                            self.mem.set_addr(sp, pc[0]);
-                            self.mem.set_addr(sp + 1, pc[1]);
-                            self.reg.pc = addr;
-                            self.reg.write16(RegisterName::SP, sp);
-                        }
-                    }
+                if self.condition(cond) {
+                    self.internal_cycle();
+                    self.push16(self.reg.pc);
+                    self.calls.call(self.reg.pc.wrapping_sub(3), addr, self.reg.pc, self.reg.sp, false);
+                    self.reg.pc = addr;
                 }
-                12
             }
             Instruction::Rst(addr) => {
-                let pc = self.reg.pc.to_le_bytes();
-                let sp = self.reg.read16(RegisterName::SP).unwrap() - 2;
-                self.mem.set_addr(sp, pc[0]);
-                self.mem.set_addr(sp + 1, pc[1]);
+                self.internal_cycle();
+                self.push16(self.reg.pc);
+                self.calls.call(self.reg.pc.wrapping_sub(1), addr as u16, self.reg.pc, self.reg.sp, false);
                 self.reg.pc = addr as u16;
-                self.reg.write16(RegisterName::SP, sp);
-                32
             }
             Instruction::Ret => {
-                let sp = self.reg.read16(RegisterName::SP).unwrap();
-                let pc = [self.mem.get_addr(sp), self.mem.get_addr(sp + 1)];
-                self.reg.pc = u16::from_le_bytes(pc);
-                self.reg.write16(RegisterName::SP, sp + 2);
-                8
+                self.ret();
+                self.internal_cycle();
             }
             Instruction::RetCond(cond) => {
-                let sp = self.reg.read16(RegisterName::SP).unwrap();
-                let pc = [self.mem.get_addr(sp), self.mem.get_addr(sp + 1)];
-                match cond {
-                    JpCond::Carry => {
-                        if self.reg.flag(Flag::Carry) {
-                            self.reg.pc = u16::from_le_bytes(pc);
-                            self.reg.write16(RegisterName::SP, sp + 2);
-                        }
-                    }
-                    JpCond::NotCarry => {
-                        if !self.reg.flag(Flag::Carry) {
-                            self.reg.pc = u16::from_le_bytes(pc);
-                            self.reg.write16(RegisterName::SP, sp + 2);
-                        }
-                    }
-                    JpCond::Zero => {
-                        if self.reg.flag(Flag::Zero) {
-                            self.reg.pc = u16::from_le_bytes(pc);
-                            self.reg.write16(RegisterName::SP, sp + 2);
-                        }
-                    }
-                    JpCond::NotZero => {
-                        if !self.reg.flag(Flag::Zero) {
-                            self.reg.pc = u16::from_le_bytes(pc);
-                            self.reg.write16(RegisterName::SP, sp + 2);
-                        }
-                    }
+                self.internal_cycle();
+                if self.condition(cond) {
+                    self.ret();
+                    self.internal_cycle();
                 }
-                8
             }
             Instruction::Reti => {
-                let sp = self.reg.read16(RegisterName::SP).unwrap();
-                let pc = [self.mem.get_addr(sp), self.mem.get_addr(sp + 1)];
-                self.reg.pc = u16::from_le_bytes(pc);
-                self.reg.write16(RegisterName::SP, sp + 2);
+                self.ret();
+                self.internal_cycle();
                 self.state.ei_pending = true;
-                8
             }
         }
+
+        (self.state.cycles - self.state.ins_start) as u8
     }
 }
@@ -1,11 +1,13 @@
 use super::*;
 
-const VBLANK: u16 = 0x0040;
-const LCDSTAT: u16 = 0x0048;
-const TIMER: u16 = 0x0050;
-const SERIAL: u16 = 0x0058;
-const JOYPAD: u16 = 0x0060;
+/// The addresses interrupts jump to
+pub const VBLANK: u16 = 0x0040;
+pub const LCDSTAT: u16 = 0x0048;
+pub const TIMER: u16 = 0x0050;
+pub const SERIAL: u16 = 0x0058;
+pub const JOYPAD: u16 = 0x0060;
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum Interrupt {
     Vblank,
@@ -25,8 +27,16 @@ impl CPU {
         self.mem.set_register(MemoryRegister::IF, iflags);
     }
 
+    /// Returns the interrupts that are both requested in IF and enabled in IE, regardless of IME.
+    /// A non-zero result is what wakes the CPU from HALT
+    pub fn pending_interrupts(&self) -> u8 {
+        let iflags = self.mem.get_register(MemoryRegister::IF);
+        let ieflags = self.mem.get_register(MemoryRegister::IE);
+        iflags & ieflags & 0x1F
+    }
+
     /// Attempts to service any pending interrupts
-    /// Returns true if an interrupt was serviced. The CPU should consume 20 clock cycles to match the ISR of the Z80 if this returns true
+    /// Returns true if an interrupt was serviced. Servicing an interrupt takes 5 M-cycles (20 clock cycles) on the bus
     pub fn handle_interrupts(&mut self) -> bool {
         let iflags = self.mem.get_register(MemoryRegister::IF);
         let ieflags = self.mem.get_register(MemoryRegister::IE);
@@ -42,13 +52,11 @@ impl CPU {
                 self.reg.ie = false; // CLI
                 self.mem
                     .set_register(MemoryRegister::IF, iflags & !(1 << i));
-                let pc = self.reg.pc.to_le_bytes();
-                let mut sp = self.reg.sp;
-                sp -= 2;
-                // PUSH PC
-                self.mem.set_addr(sp, pc[0]);
-                self.mem.set_addr(sp, pc[1]);
-                self.reg.sp = sp;
+                // Two wait states, then PUSH PC
+                self.internal_cycle();
+                self.internal_cycle();
+                let return_addr = self.reg.pc;
+                self.push16(return_addr);
 
                 // JMP to vector
                 match i {
@@ -69,6 +77,11 @@ impl CPU {
                     }
                     _ => unreachable!(),
                 }
+                self.calls.call(return_addr, self.reg.pc, return_addr, self.reg.sp, true);
+                if let Some(coverage) = &mut self.coverage {
+                    coverage.jump(self.reg.pc);
+                }
+                self.internal_cycle();
                 // Correctly handle interrupt priority, when one interrupt has matched it should execute regardless of any other set bits
                 return true;
             }
@@ -1,4 +1,4 @@
-pub static TABLE_R: [RegisterName; 8] = [
+pub const TABLE_R: [RegisterName; 8] = [
     RegisterName::B,
     RegisterName::C,
     RegisterName::D,
@@ -8,19 +8,19 @@ pub static TABLE_R: [RegisterName; 8] = [
     RegisterName::HLRef,
     RegisterName::A,
 ];
-pub static TABLE_RP: [RegisterName; 4] = [
+pub const TABLE_RP: [RegisterName; 4] = [
     RegisterName::BC,
     RegisterName::DE,
     RegisterName::HL,
     RegisterName::SP,
 ];
-pub static TABLE_RP2: [RegisterName; 4] = [
+pub const TABLE_RP2: [RegisterName; 4] = [
     RegisterName::BC,
     RegisterName::DE,
     RegisterName::HL,
     RegisterName::AF,
 ];
-pub static TABLE_CC: [JpCond; 4] = [
+pub const TABLE_CC: [JpCond; 4] = [
     JpCond::NotZero,
     JpCond::Zero,
     JpCond::NotCarry,
@@ -47,7 +47,7 @@ pub enum RegisterName {
     SP,
 }
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum JpCond {
     NotZero,
     Zero,
@@ -55,48 +55,7 @@ pub enum JpCond {
     Carry,
 }
 
-pub fn rot(idx: usize, reg: RegisterName) -> Instruction {
-    match idx {
-        0 => Instruction::Rlc(reg),
-        1 => Instruction::Rrc(reg),
-        2 => Instruction::Rl(reg),
-        3 => Instruction::Rr(reg),
-        4 => Instruction::Sla(reg),
-        5 => Instruction::Sra(reg),
-        7 => Instruction::Srl(reg),
-        _ => Instruction::IllInsn,
-    }
-}
-
-pub fn alu_reg(idx: usize, reg: RegisterName) -> Instruction {
-    match idx {
-        0 => Instruction::AddReg(reg),
-        1 => Instruction::AdcReg(reg),
-        2 => Instruction::SubReg(reg),
-        3 => Instruction::SbcReg(reg),
-        4 => Instruction::AndReg(reg),
-        5 => Instruction::XorReg(reg),
-        6 => Instruction::OrReg(reg),
-        7 => Instruction::CpReg(reg),
-        _ => Instruction::IllInsn,
-    }
-}
-
-pub fn alu_imm(idx: usize, oper: u8) -> Instruction {
-    match idx {
-        0 => Instruction::AddImm(oper),
-        1 => Instruction::AdcImm(oper),
-        2 => Instruction::SubImm(oper),
-        3 => Instruction::SbcImm(oper),
-        4 => Instruction::AndImm(oper),
-        5 => Instruction::XorImm(oper),
-        6 => Instruction::OrImm(oper),
-        7 => Instruction::CpImm(oper),
-        _ => Instruction::IllInsn,
-    }
-}
-
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Instruction {
     /// Any illegal opcode in decoding
     IllInsn,
@@ -254,3 +213,87 @@ pub enum Instruction {
     /// RETI
     Reti,
 }
+
+impl Instruction {
+    /// Replaces the immediate operand of the instruction, if it has one.
+    /// Byte immediates are taken from the low byte of `imm`
+    pub const fn with_immediate(self, imm: u16) -> Instruction {
+        let n = imm as u8;
+        match self {
+            Instruction::Ld8Imm(reg, _) => Instruction::Ld8Imm(reg, n),
+            Instruction::StoSP(_) => Instruction::StoSP(imm),
+            Instruction::LdhN(_) => Instruction::LdhN(n),
+            Instruction::LdhA(_) => Instruction::LdhA(n),
+            Instruction::Lda(_) => Instruction::Lda(imm),
+            Instruction::LdaNN(_) => Instruction::LdaNN(imm),
+            Instruction::Ld16Imm(reg, _) => Instruction::Ld16Imm(reg, imm),
+            Instruction::LdHLSPn(_) => Instruction::LdHLSPn(n as i8),
+            Instruction::LdnnSP(_) => Instruction::LdnnSP(imm),
+            Instruction::AddImm(_) => Instruction::AddImm(n),
+            Instruction::AdcImm(_) => Instruction::AdcImm(n),
+            Instruction::SubImm(_) => Instruction::SubImm(n),
+            Instruction::SbcImm(_) => Instruction::SbcImm(n),
+            Instruction::AndImm(_) => Instruction::AndImm(n),
+            Instruction::OrImm(_) => Instruction::OrImm(n),
+            Instruction::XorImm(_) => Instruction::XorImm(n),
+            Instruction::CpImm(_) => Instruction::CpImm(n),
+            Instruction::AddSP(_) => Instruction::AddSP(n as i8),
+            Instruction::Jp(_) => Instruction::Jp(imm),
+            Instruction::JpCond(cond, _) => Instruction::JpCond(cond, imm),
+            Instruction::Jr(_) => Instruction::Jr(n as i8),
+            Instruction::JrCond(cond, _) => Instruction::JrCond(cond, n as i8),
+            Instruction::Call(_) => Instruction::Call(imm),
+            Instruction::CallCond(cond, _) => Instruction::CallCond(cond, imm),
+            other => other,
+        }
+    }
+
+    /// The immediate operand of the instruction, or 0 if it has none.
+    /// Signed byte immediates are returned as their raw byte
+    pub const fn immediate(self) -> u16 {
+        match self {
+            Instruction::Ld8Imm(_, n)
+            | Instruction::LdhN(n)
+            | Instruction::LdhA(n)
+            | Instruction::AddImm(n)
+            | Instruction::AdcImm(n)
+            | Instruction::SubImm(n)
+            | Instruction::SbcImm(n)
+            | Instruction::AndImm(n)
+            | Instruction::OrImm(n)
+            | Instruction::XorImm(n)
+            | Instruction::CpImm(n) => n as u16,
+            Instruction::LdHLSPn(e)
+            | Instruction::AddSP(e)
+            | Instruction::Jr(e)
+            | Instruction::JrCond(_, e) => e as u8 as u16,
+            Instruction::StoSP(nn)
+            | Instruction::LdnnSP(nn)
+            | Instruction::Lda(nn)
+            | Instruction::LdaNN(nn)
+            | Instruction::Ld16Imm(_, nn)
+            | Instruction::Jp(nn)
+            | Instruction::JpCond(_, nn)
+            | Instruction::Call(nn)
+            | Instruction::CallCond(_, nn) => nn,
+            _ => 0,
+        }
+    }
+
+    /// Encodes the instruction back into machine code, including the CB prefix and any immediate operand.
+    /// STOP is followed by its $00 padding byte. Illegal instructions are encoded as $D3.
+    /// Returns `None` for instructions no opcode decodes to, such as loads between two memory operands
+    pub fn encode(self) -> Option<Vec<u8>> {
+        let (cb, opcode, info) = super::optable::lookup(self)?;
+        let mut bytes = Vec::with_capacity(info.length as usize);
+        if cb {
+            bytes.push(super::optable::CB_PREFIX);
+        }
+        bytes.push(opcode);
+
+        let imm = self.immediate().to_le_bytes();
+        bytes.extend_from_slice(&imm[..info.imm_len() as usize]);
+        bytes.resize(info.length as usize, 0x00);
+        Some(bytes)
+    }
+}
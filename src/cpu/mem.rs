@@ -1,4 +1,5 @@
 use crate::cpu::BOOTROM;
+use crate::debug::watch::{Access, Accessor, WatchHit, Watchpoint};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum MemoryRegister {
@@ -44,10 +45,70 @@ pub enum MemoryRegister {
     OBP1,
     WY,
     WX,
+    KEY1,
     IE,
 }
 
 impl MemoryRegister {
+    /// Every register with a name, in address order. Wave pattern RAM is left out
+    pub const NAMED: [MemoryRegister; 43] = [
+        MemoryRegister::P1,
+        MemoryRegister::SB,
+        MemoryRegister::SC,
+        MemoryRegister::DIV,
+        MemoryRegister::TIMA,
+        MemoryRegister::TMA,
+        MemoryRegister::TAC,
+        MemoryRegister::IF,
+        MemoryRegister::NR10,
+        MemoryRegister::NR11,
+        MemoryRegister::NR12,
+        MemoryRegister::NR13,
+        MemoryRegister::NR14,
+        MemoryRegister::NR21,
+        MemoryRegister::NR22,
+        MemoryRegister::NR23,
+        MemoryRegister::NR24,
+        MemoryRegister::NR30,
+        MemoryRegister::NR31,
+        MemoryRegister::NR32,
+        MemoryRegister::NR33,
+        MemoryRegister::NR34,
+        MemoryRegister::NR41,
+        MemoryRegister::NR42,
+        MemoryRegister::NR43,
+        MemoryRegister::NR44,
+        MemoryRegister::NR50,
+        MemoryRegister::NR51,
+        MemoryRegister::NR52,
+        MemoryRegister::LCDC,
+        MemoryRegister::STAT,
+        MemoryRegister::SCY,
+        MemoryRegister::SCX,
+        MemoryRegister::LY,
+        MemoryRegister::LYC,
+        MemoryRegister::DMA,
+        MemoryRegister::BGP,
+        MemoryRegister::OBP0,
+        MemoryRegister::OBP1,
+        MemoryRegister::WY,
+        MemoryRegister::WX,
+        MemoryRegister::KEY1,
+        MemoryRegister::IE,
+    ];
+
+    /// Looks up a register by its name, ignoring case
+    pub fn from_name(name: &str) -> Option<MemoryRegister> {
+        MemoryRegister::NAMED
+            .iter()
+            .find(|reg| reg.name().eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    pub fn name(self) -> String {
+        format!("{:?}", self)
+    }
+
     pub fn to_addr(self) -> u16 {
         match self {
             MemoryRegister::P1 => 0xFF00,
@@ -92,6 +153,7 @@ impl MemoryRegister {
             MemoryRegister::OBP1 => 0xFF49,
             MemoryRegister::WY => 0xFF4A,
             MemoryRegister::WX => 0xFF4B,
+            MemoryRegister::KEY1 => 0xFF4D,
             MemoryRegister::IE => 0xFFFF,
         }
     }
@@ -100,6 +162,9 @@ impl MemoryRegister {
 pub struct Memory {
     pub buffer: [u8; 0xFFFF + 1],
     pub bootrom_paged: bool,
+    pub watchpoints: Vec<Watchpoint>,
+    /// Accesses that triggered a watchpoint, waiting to be reported by the debugger
+    pub watch_hits: Vec<WatchHit>,
 }
 
 impl Memory {
@@ -107,6 +172,8 @@ impl Memory {
         Memory {
             buffer: [0; 0xFFFF + 1],
             bootrom_paged: true,
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
         }
     }
 
@@ -119,19 +186,51 @@ impl Memory {
     }
 
     pub fn get_addr(&self, addr: u16) -> u8 {
-        if self.bootrom_paged && addr < 0xff {
+        if self.bootrom_paged && addr < 0x100 {
             BOOTROM[addr as usize]
         } else {
             self.buffer[addr as usize]
         }
     }
 
-    pub fn get_u16_at(&self, idx: u16) -> u16 {
-        let bytes = [self.get_addr(idx), self.get_addr(idx + 1)];
-        u16::from_le_bytes(bytes)
+    /// Whether an address reads the cartridge ROM, rather than the boot ROM or RAM
+    pub fn is_cartridge_rom(&self, addr: u16) -> bool {
+        addr < 0x8000 && !(self.bootrom_paged && addr < 0x100)
     }
 
     pub fn set_addr(&mut self, addr: u16, value: u8) {
         self.buffer[addr as usize] = value;
     }
+
+    /// Reads a byte on behalf of the CPU or DMA, checking it against the watchpoints.
+    /// `get_addr` reads without doing so, for the PPU and debugger
+    pub fn read(&mut self, addr: u16, by: Accessor) -> u8 {
+        let value = self.get_addr(addr);
+        self.watch(addr, Access::Read, value, value, by);
+        value
+    }
+
+    /// Writes a byte on behalf of the CPU or DMA, checking it against the watchpoints
+    pub fn write(&mut self, addr: u16, value: u8, by: Accessor) {
+        self.watch(addr, Access::Write, self.get_addr(addr), value, by);
+        self.set_addr(addr, value);
+    }
+
+    /// Records an access if it triggers any watchpoints. `old` is the value before the access and `new` the value read or written
+    pub fn watch(&mut self, addr: u16, access: Access, old: u8, new: u8, by: Accessor) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        for wp in self.watchpoints.iter().filter(|wp| wp.triggers(addr, access, old, new)) {
+            self.watch_hits.push(WatchHit {
+                id: wp.id,
+                addr,
+                access,
+                by,
+                old,
+                new,
+                halt: wp.halt,
+            });
+        }
+    }
 }
@@ -0,0 +1,354 @@
+use super::isa::*;
+
+/// The kind of an operand, as written in the instruction's mnemonic
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Operand {
+    None,
+    /// A register, or one of the register indirect operands (BC), (DE), (HL)
+    Reg(RegisterName),
+    /// (HL+), used by LDI
+    HLInc,
+    /// (HL-), used by LDD
+    HLDec,
+    /// An unsigned byte immediate
+    Imm8,
+    /// A signed byte immediate, used by ADD SP,e
+    SImm8,
+    /// A word immediate
+    Imm16,
+    /// (nn), a word immediate used as an address
+    Addr16,
+    /// ($FF00+n), used by LDH
+    HighImm8,
+    /// ($FF00+C), used by LDH
+    HighC,
+    /// SP+e, used by LD HL,SP+e
+    SPRel8,
+    /// A signed byte offset from the address of the next instruction, used by JR
+    Rel8,
+    Cond(JpCond),
+    /// The bit index of BIT, RES and SET
+    Bit(u8),
+    /// The target of RST
+    Vector(u8),
+}
+
+impl Operand {
+    /// The number of immediate bytes following the opcode that this operand consumes
+    pub fn imm_len(self) -> u8 {
+        match self {
+            Operand::Imm8 | Operand::SImm8 | Operand::HighImm8 | Operand::SPRel8 | Operand::Rel8 => 1,
+            Operand::Imm16 | Operand::Addr16 => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Precomputed metadata for a single opcode, shared by the decoder, disassembler and profiler
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct OpInfo {
+    pub mnemonic: &'static str,
+    pub operands: [Operand; 2],
+    /// The length of the instruction in bytes, including the opcode and any CB prefix
+    pub length: u8,
+    /// Clock cycles taken, or for a conditional branch, the cycles taken when the branch is not taken
+    pub cycles: u8,
+    /// Clock cycles taken by a conditional branch when the branch is taken
+    pub cycles_branch: u8,
+    /// The decoded instruction, with any immediate operand left as zero
+    pub template: Instruction,
+}
+
+impl OpInfo {
+    /// The number of immediate bytes following the opcode
+    pub fn imm_len(&self) -> u8 {
+        self.operands[0].imm_len() + self.operands[1].imm_len()
+    }
+
+    /// The decoded instruction, with the immediate operand filled in
+    pub fn instruction(&self, imm: u16) -> Instruction {
+        self.template.with_immediate(imm)
+    }
+}
+
+/// Opcodes $00-$FF
+pub static BASE: [OpInfo; 256] = build_base();
+/// Opcodes $CB $00-$FF
+pub static CB: [OpInfo; 256] = build_cb();
+
+/// The opcode that selects the CB table for the following byte
+pub const CB_PREFIX: u8 = 0xCB;
+
+/// Decodes the instruction at `addr`, reading bytes through `read` rather than the bus.
+/// This is what tools such as the disassembler use, as it has no side effects and takes no cycles
+pub fn decode_at<F: Fn(u16) -> u8>(read: F, addr: u16) -> (Instruction, &'static OpInfo) {
+    let opcode = read(addr);
+    let info = if opcode == CB_PREFIX {
+        &CB[read(addr.wrapping_add(1)) as usize]
+    } else {
+        &BASE[opcode as usize]
+    };
+
+    let imm_start = addr.wrapping_add(if opcode == CB_PREFIX { 2 } else { 1 });
+    let imm = match info.imm_len() {
+        1 => read(imm_start) as u16,
+        2 => u16::from_le_bytes([read(imm_start), read(imm_start.wrapping_add(1))]),
+        _ => 0,
+    };
+    (info.instruction(imm), info)
+}
+
+/// Finds the table entry an instruction decodes from, ignoring its immediate operand.
+/// Returns whether the opcode is CB prefixed, the opcode, and its entry
+pub fn lookup(ins: Instruction) -> Option<(bool, u8, &'static OpInfo)> {
+    // LD (nn),SP has two variants in the ISA, only StoSP is produced by the decoder
+    let template = match ins {
+        Instruction::LdnnSP(_) => Instruction::StoSP(0),
+        _ => ins.with_immediate(0),
+    };
+
+    let base = BASE
+        .iter()
+        .enumerate()
+        .find(|(opcode, info)| *opcode as u8 != CB_PREFIX && info.template == template)
+        .map(|(opcode, info)| (false, opcode as u8, info));
+    base.or_else(|| {
+        CB.iter()
+            .enumerate()
+            .find(|(_, info)| info.template == template)
+            .map(|(opcode, info)| (true, opcode as u8, info))
+    })
+}
+
+const R: [RegisterName; 8] = TABLE_R;
+const RP: [RegisterName; 4] = TABLE_RP;
+const RP2: [RegisterName; 4] = TABLE_RP2;
+const CC: [JpCond; 4] = TABLE_CC;
+
+const ILLEGAL: OpInfo = op("ILLEGAL", Operand::None, Operand::None, 1, 4, Instruction::IllInsn);
+
+const fn op(mnemonic: &'static str, a: Operand, b: Operand, length: u8, cycles: u8, template: Instruction) -> OpInfo {
+    OpInfo {
+        mnemonic,
+        operands: [a, b],
+        length,
+        cycles,
+        cycles_branch: cycles,
+        template,
+    }
+}
+
+const fn branch(mnemonic: &'static str, a: Operand, b: Operand, length: u8, cycles: u8, cycles_branch: u8, template: Instruction) -> OpInfo {
+    OpInfo {
+        mnemonic,
+        operands: [a, b],
+        length,
+        cycles,
+        cycles_branch,
+        template,
+    }
+}
+
+const fn is_hl_ref(reg: RegisterName) -> bool {
+    matches!(reg, RegisterName::HLRef)
+}
+
+const fn build_base() -> [OpInfo; 256] {
+    let mut table = [ILLEGAL; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = base_op(i as u8);
+        i += 1;
+    }
+    table
+}
+
+const fn build_cb() -> [OpInfo; 256] {
+    let mut table = [ILLEGAL; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = cb_op(i as u8);
+        i += 1;
+    }
+    table
+}
+
+/// Decodes a base opcode by its x/y/z/p/q fields
+const fn base_op(opcode: u8) -> OpInfo {
+    use Operand::*;
+
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0x7) as usize;
+    let z = opcode & 0x7;
+    let p = y >> 1;
+    let q = y & 1;
+
+    match x {
+        0 => match z {
+            0 => match y {
+                0 => op("NOP", None, None, 1, 4, Instruction::Nop),
+                1 => op("LD", Addr16, Reg(RegisterName::SP), 3, 20, Instruction::StoSP(0)),
+                2 => op("STOP", None, None, 2, 4, Instruction::Stop),
+                3 => op("JR", Rel8, None, 2, 12, Instruction::Jr(0)),
+                _ => branch("JR", Cond(CC[y - 4]), Rel8, 2, 8, 12, Instruction::JrCond(CC[y - 4], 0)),
+            },
+            1 => {
+                if q == 0 {
+                    op("LD", Reg(RP[p]), Imm16, 3, 12, Instruction::Ld16Imm(RP[p], 0))
+                } else {
+                    op("ADD", Reg(RegisterName::HL), Reg(RP[p]), 1, 8, Instruction::Add16(RP[p]))
+                }
+            }
+            2 => match (q, p) {
+                (0, 0) => op("LD", Reg(RegisterName::BCRef), Reg(RegisterName::A), 1, 8, Instruction::Ld8Reg(RegisterName::BCRef, RegisterName::A)),
+                (0, 1) => op("LD", Reg(RegisterName::DERef), Reg(RegisterName::A), 1, 8, Instruction::Ld8Reg(RegisterName::DERef, RegisterName::A)),
+                (0, 2) => op("LD", HLInc, Reg(RegisterName::A), 1, 8, Instruction::LdiHLA),
+                (0, 3) => op("LD", HLDec, Reg(RegisterName::A), 1, 8, Instruction::LddHLA),
+                (1, 0) => op("LD", Reg(RegisterName::A), Reg(RegisterName::BCRef), 1, 8, Instruction::Ld8Reg(RegisterName::A, RegisterName::BCRef)),
+                (1, 1) => op("LD", Reg(RegisterName::A), Reg(RegisterName::DERef), 1, 8, Instruction::Ld8Reg(RegisterName::A, RegisterName::DERef)),
+                (1, 2) => op("LD", Reg(RegisterName::A), HLInc, 1, 8, Instruction::LdiAHL),
+                _ => op("LD", Reg(RegisterName::A), HLDec, 1, 8, Instruction::LddAHL),
+            },
+            3 => {
+                if q == 0 {
+                    op("INC", Reg(RP[p]), None, 1, 8, Instruction::Inc16(RP[p]))
+                } else {
+                    op("DEC", Reg(RP[p]), None, 1, 8, Instruction::Dec16(RP[p]))
+                }
+            }
+            4 => op("INC", Reg(R[y]), None, 1, if is_hl_ref(R[y]) { 12 } else { 4 }, Instruction::Inc(R[y])),
+            5 => op("DEC", Reg(R[y]), None, 1, if is_hl_ref(R[y]) { 12 } else { 4 }, Instruction::Dec(R[y])),
+            6 => op("LD", Reg(R[y]), Imm8, 2, if is_hl_ref(R[y]) { 12 } else { 8 }, Instruction::Ld8Imm(R[y], 0)),
+            _ => match y {
+                0 => op("RLCA", None, None, 1, 4, Instruction::Rlca),
+                1 => op("RRCA", None, None, 1, 4, Instruction::Rrca),
+                2 => op("RLA", None, None, 1, 4, Instruction::Rla),
+                3 => op("RRA", None, None, 1, 4, Instruction::Rra),
+                4 => op("DAA", None, None, 1, 4, Instruction::Daa),
+                5 => op("CPL", None, None, 1, 4, Instruction::Cpl),
+                6 => op("SCF", None, None, 1, 4, Instruction::Scf),
+                _ => op("CCF", None, None, 1, 4, Instruction::Ccf),
+            },
+        },
+        1 => {
+            if z == 6 && y == 6 {
+                op("HALT", None, None, 1, 4, Instruction::Halt)
+            } else {
+                let z = z as usize;
+                let cycles = if is_hl_ref(R[y]) || is_hl_ref(R[z]) { 8 } else { 4 };
+                op("LD", Reg(R[y]), Reg(R[z]), 1, cycles, Instruction::Ld8Reg(R[y], R[z]))
+            }
+        }
+        2 => {
+            let reg = R[z as usize];
+            let cycles = if is_hl_ref(reg) { 8 } else { 4 };
+            let (mnemonic, template) = match y {
+                0 => ("ADD", Instruction::AddReg(reg)),
+                1 => ("ADC", Instruction::AdcReg(reg)),
+                2 => ("SUB", Instruction::SubReg(reg)),
+                3 => ("SBC", Instruction::SbcReg(reg)),
+                4 => ("AND", Instruction::AndReg(reg)),
+                5 => ("XOR", Instruction::XorReg(reg)),
+                6 => ("OR", Instruction::OrReg(reg)),
+                _ => ("CP", Instruction::CpReg(reg)),
+            };
+            op(mnemonic, Reg(RegisterName::A), Reg(reg), 1, cycles, template)
+        }
+        _ => match z {
+            0 => match y {
+                4 => op("LDH", HighImm8, Reg(RegisterName::A), 2, 12, Instruction::LdhN(0)),
+                5 => op("ADD", Reg(RegisterName::SP), SImm8, 2, 16, Instruction::AddSP(0)),
+                6 => op("LDH", Reg(RegisterName::A), HighImm8, 2, 12, Instruction::LdhA(0)),
+                7 => op("LD", Reg(RegisterName::HL), SPRel8, 2, 12, Instruction::LdHLSPn(0)),
+                _ => branch("RET", Cond(CC[y]), None, 1, 8, 20, Instruction::RetCond(CC[y])),
+            },
+            1 => {
+                if q == 0 {
+                    op("POP", Reg(RP2[p]), None, 1, 12, Instruction::Pop(RP2[p]))
+                } else {
+                    match p {
+                        0 => op("RET", None, None, 1, 16, Instruction::Ret),
+                        1 => op("RETI", None, None, 1, 16, Instruction::Reti),
+                        2 => op("JP", Reg(RegisterName::HL), None, 1, 4, Instruction::JpHL),
+                        _ => op("LD", Reg(RegisterName::SP), Reg(RegisterName::HL), 1, 8, Instruction::LdSPHL),
+                    }
+                }
+            }
+            2 => match y {
+                4 => op("LDH", HighC, Reg(RegisterName::A), 1, 8, Instruction::Ldca),
+                5 => op("LD", Addr16, Reg(RegisterName::A), 3, 16, Instruction::LdaNN(0)),
+                6 => op("LDH", Reg(RegisterName::A), HighC, 1, 8, Instruction::Ldac),
+                7 => op("LD", Reg(RegisterName::A), Addr16, 3, 16, Instruction::Lda(0)),
+                _ => branch("JP", Cond(CC[y]), Imm16, 3, 12, 16, Instruction::JpCond(CC[y], 0)),
+            },
+            3 => match y {
+                0 => op("JP", Imm16, None, 3, 16, Instruction::Jp(0)),
+                // The CB prefix has its own table, this entry is only reached if the prefix is looked up directly
+                1 => op("PREFIX", None, None, 1, 4, Instruction::IllInsn),
+                6 => op("DI", None, None, 1, 4, Instruction::Di),
+                7 => op("EI", None, None, 1, 4, Instruction::Ei),
+                _ => ILLEGAL,
+            },
+            4 => {
+                if y < 4 {
+                    branch("CALL", Cond(CC[y]), Imm16, 3, 12, 24, Instruction::CallCond(CC[y], 0))
+                } else {
+                    ILLEGAL
+                }
+            }
+            5 => {
+                if q == 0 {
+                    op("PUSH", Reg(RP2[p]), None, 1, 16, Instruction::Push(RP2[p]))
+                } else if p == 0 {
+                    op("CALL", Imm16, None, 3, 24, Instruction::Call(0))
+                } else {
+                    ILLEGAL
+                }
+            }
+            6 => {
+                let (mnemonic, template) = match y {
+                    0 => ("ADD", Instruction::AddImm(0)),
+                    1 => ("ADC", Instruction::AdcImm(0)),
+                    2 => ("SUB", Instruction::SubImm(0)),
+                    3 => ("SBC", Instruction::SbcImm(0)),
+                    4 => ("AND", Instruction::AndImm(0)),
+                    5 => ("XOR", Instruction::XorImm(0)),
+                    6 => ("OR", Instruction::OrImm(0)),
+                    _ => ("CP", Instruction::CpImm(0)),
+                };
+                op(mnemonic, Reg(RegisterName::A), Imm8, 2, 8, template)
+            }
+            _ => op("RST", Vector(y as u8 * 8), None, 1, 16, Instruction::Rst(y as u8 * 8)),
+        },
+    }
+}
+
+/// Decodes the opcode following a CB prefix
+const fn cb_op(opcode: u8) -> OpInfo {
+    use Operand::*;
+
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 0x7;
+    let reg = R[(opcode & 0x7) as usize];
+    let cycles = if is_hl_ref(reg) { 16 } else { 8 };
+
+    match x {
+        0 => {
+            let (mnemonic, template) = match y {
+                0 => ("RLC", Instruction::Rlc(reg)),
+                1 => ("RRC", Instruction::Rrc(reg)),
+                2 => ("RL", Instruction::Rl(reg)),
+                3 => ("RR", Instruction::Rr(reg)),
+                4 => ("SLA", Instruction::Sla(reg)),
+                5 => ("SRA", Instruction::Sra(reg)),
+                6 => ("SWAP", Instruction::Swap(reg)),
+                _ => ("SRL", Instruction::Srl(reg)),
+            };
+            op(mnemonic, Reg(reg), None, 2, cycles, template)
+        }
+        // BIT only reads its operand, so (HL) takes one M-cycle less than the other CB instructions
+        1 => op("BIT", Bit(y), Reg(reg), 2, if is_hl_ref(reg) { 12 } else { 8 }, Instruction::Bit(y, reg)),
+        2 => op("RES", Bit(y), Reg(reg), 2, cycles, Instruction::Res(y, reg)),
+        _ => op("SET", Bit(y), Reg(reg), 2, cycles, Instruction::Set(y, reg)),
+    }
+}
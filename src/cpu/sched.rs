@@ -0,0 +1,88 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Something that happens in a peripheral at a known point in time
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum EventKind {
+    /// The PPU finishes its current mode and enters the next one
+    PpuMode,
+    /// TIMA overflowed on the previous M-cycle and is reloaded from TMA
+    TimerOverflow,
+    /// All 8 bits of a serial transfer have been shifted out
+    SerialComplete,
+    /// The 512Hz APU frame sequencer steps
+    FrameSequencer,
+    /// An OAM DMA transfer copies its next byte
+    DmaByte,
+}
+
+/// A queue of peripheral events ordered by the clock cycle they fire on.
+/// The CPU only calls into a peripheral when one of its events is due, instead of polling every peripheral each cycle
+#[derive(Clone)]
+pub struct Scheduler {
+    events: BinaryHeap<Reverse<(u64, EventKind)>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            events: BinaryHeap::new(),
+        }
+    }
+
+    /// Schedules an event to fire once the clock reaches `at`
+    pub fn schedule(&mut self, at: u64, kind: EventKind) {
+        self.events.push(Reverse((at, kind)));
+    }
+
+    /// Removes every pending event of the given kind
+    pub fn cancel(&mut self, kind: EventKind) {
+        self.events.retain(|Reverse((_, k))| *k != kind);
+    }
+
+    /// The cycle the next event fires on, if any are scheduled
+    pub fn next_at(&self) -> Option<u64> {
+        self.events.peek().map(|Reverse((at, _))| *at)
+    }
+
+    /// Removes and returns the next event due at or before `now`
+    pub fn pop_due(&mut self, now: u64) -> Option<(u64, EventKind)> {
+        match self.next_at() {
+            Some(at) if at <= now => self.events.pop().map(|Reverse(ev)| ev),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventKind::*;
+    use super::*;
+
+    #[test]
+    fn test_scheduler() {
+        let mut sched = Scheduler::new();
+        sched.schedule(100, DmaByte);
+        sched.schedule(50, SerialComplete);
+        sched.schedule(100, PpuMode);
+        sched.schedule(200, TimerOverflow);
+        assert_eq!(sched.next_at(), Some(50));
+        assert_eq!(sched.pop_due(49), None);
+        assert_eq!(sched.pop_due(100), Some((50, SerialComplete)));
+        // Events due on the same cycle fire in the order of their kinds
+        assert_eq!(sched.pop_due(100), Some((100, PpuMode)));
+        assert_eq!(sched.pop_due(100), Some((100, DmaByte)));
+        assert_eq!(sched.pop_due(100), None);
+
+        // An event is rescheduled by cancelling it and scheduling it again
+        sched.cancel(TimerOverflow);
+        assert_eq!(sched.next_at(), None);
+        sched.schedule(300, SerialComplete);
+        sched.schedule(150, TimerOverflow);
+        sched.cancel(TimerOverflow);
+        sched.schedule(250, TimerOverflow);
+        assert_eq!(sched.pop_due(1000), Some((250, TimerOverflow)));
+        assert_eq!(sched.pop_due(1000), Some((300, SerialComplete)));
+        assert_eq!(sched.pop_due(1000), None);
+    }
+}
@@ -0,0 +1,75 @@
+use super::calls::CallStack;
+use super::sched::Scheduler;
+use super::{CPUState, Registers, CPU};
+use crate::peripherals::audio::AudioDrv;
+use crate::peripherals::dma::DmaDrv;
+use crate::peripherals::joypad::JoypadDrv;
+use crate::peripherals::serial::SerialDrv;
+use crate::peripherals::timer::TimerDrv;
+use crate::peripherals::video::VideoDrv;
+use std::mem::{size_of, size_of_val};
+
+/// The state of the machine at a point in time, enough to carry on running from it.
+/// Watchpoints, the tracer and the SDL window and audio queue aren't part of it, so are left alone by a restore
+pub struct Snapshot {
+    reg: Registers,
+    state: CPUState,
+    memory: Box<[u8; 0x10000]>,
+    bootrom_paged: bool,
+    audio: AudioDrv,
+    video: VideoDrv,
+    joypad: JoypadDrv,
+    timer: TimerDrv,
+    dma: DmaDrv,
+    serial: SerialDrv,
+    sched: Scheduler,
+    calls: CallStack,
+}
+
+impl Snapshot {
+    /// Roughly how many bytes the snapshot takes up
+    pub fn size(&self) -> usize {
+        size_of::<Snapshot>()
+            + self.memory.len()
+            + size_of_val(&*self.video.framebuffer)
+            + self.serial.output.len()
+            + self.calls.frames.len() * size_of::<super::calls::Frame>()
+    }
+}
+
+impl CPU {
+    pub fn snapshot(&self) -> Snapshot {
+        let mut calls = self.calls.clone();
+        calls.anomalies.clear();
+        Snapshot {
+            reg: self.reg.clone(),
+            state: self.state.clone(),
+            memory: Box::new(self.mem.buffer),
+            bootrom_paged: self.mem.bootrom_paged,
+            audio: self.audio.snapshot(),
+            video: self.video.snapshot(),
+            joypad: self.joypad.clone(),
+            timer: self.timer.clone(),
+            dma: self.dma.clone(),
+            serial: self.serial.clone(),
+            sched: self.sched.clone(),
+            calls,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.reg = snapshot.reg.clone();
+        self.state = snapshot.state.clone();
+        self.mem.buffer = *snapshot.memory;
+        self.mem.bootrom_paged = snapshot.bootrom_paged;
+        self.mem.watch_hits.clear();
+        self.audio.restore(&snapshot.audio);
+        self.video.restore(&snapshot.video);
+        self.joypad = snapshot.joypad.clone();
+        self.timer = snapshot.timer.clone();
+        self.dma = snapshot.dma.clone();
+        self.serial = snapshot.serial.clone();
+        self.sched = snapshot.sched.clone();
+        self.calls = snapshot.calls.clone();
+    }
+}
@@ -0,0 +1,13 @@
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod expr;
+pub mod gdb;
+pub mod oam;
+pub mod profile;
+pub mod reverse;
+pub mod symbols;
+pub mod trace;
+pub mod vram;
+pub mod watch;
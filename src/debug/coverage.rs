@@ -0,0 +1,302 @@
+use crate::cpu::optable::{self, Operand};
+use crate::debug::disasm::{disassemble_at, format_line, rom_byte, rom_offset, target_comment, DisasmLine, BANK_SIZE};
+use crate::debug::symbols::{mapped_bank, Symbols};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// The first byte of an instruction that was executed
+pub const OPCODE: u8 = 1;
+/// A later byte of an instruction that was executed
+pub const OPERAND: u8 = 2;
+/// Read as data, by an instruction or OAM DMA
+pub const DATA: u8 = 4;
+/// Reached other than by running on from the instruction before, by a jump, call, return or interrupt
+pub const JUMP_TARGET: u8 = 8;
+
+/// The most bytes put in a `db` line of data
+const DATA_ROW: usize = 8;
+
+/// What each byte of ROM has been used for, as a set of flags for each byte of the ROM image.
+/// Saved as the flags alone, so the coverage from several runs is merged by combining the flags of each byte
+#[derive(Clone)]
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Coverage {
+    /// Coverage for a ROM image of `len` bytes. The two banks that can be mapped are always covered
+    pub fn new(len: usize) -> Coverage {
+        Coverage {
+            flags: vec![0; len.max(2 * BANK_SIZE)],
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Coverage, String> {
+        let flags = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Ok(Coverage { flags })
+    }
+
+    /// Adds in the coverage saved to a file, if it has been saved to yet
+    pub fn merge_file(&mut self, path: &Path) -> Result<(), String> {
+        if path.exists() {
+            self.merge(&Coverage::load(path)?);
+        }
+        Ok(())
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        if self.flags.len() < other.flags.len() {
+            self.flags.resize(other.flags.len(), 0);
+        }
+        for (flags, other) in self.flags.iter_mut().zip(&other.flags) {
+            *flags |= other;
+        }
+    }
+
+    /// Saves to a file, merged with the coverage already saved there
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut merged = self.clone();
+        merged.merge_file(path)?;
+        fs::write(path, &merged.flags).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+    }
+
+    /// The flags of an address in $0000-$7FFF, with `bank` mapped into $4000-$7FFF
+    pub fn flags(&self, bank: usize, addr: u16) -> u8 {
+        self.flags.get(rom_offset(bank, addr)).copied().unwrap_or(0)
+    }
+
+    fn mark(&mut self, addr: u16, flag: u8) {
+        if addr < 0x8000 {
+            if let Some(flags) = self.flags.get_mut(rom_offset(mapped_bank(addr) as usize, addr)) {
+                *flags |= flag;
+            }
+        }
+    }
+
+    /// Records an instruction of `len` bytes executed at `pc`
+    pub fn execute(&mut self, pc: u16, len: u16) {
+        self.mark(pc, OPCODE);
+        for i in 1..len {
+            self.mark(pc.wrapping_add(i), OPERAND);
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) {
+        self.mark(addr, DATA);
+    }
+
+    pub fn jump(&mut self, addr: u16) {
+        self.mark(addr, JUMP_TARGET);
+    }
+
+    /// Totals of how much of the ROM was executed and read, overall and by bank,
+    /// followed by the routines that were never run: labelled ones, and those called from code that was
+    pub fn summary(&self, rom: &[u8], symbols: &Symbols) -> String {
+        let count = |flags: &[u8], mask: u8| flags.iter().filter(|f| *f & mask != 0).count();
+        let percent = |n: usize, of: usize| n as f64 * 100.0 / of as f64;
+        let total = self.flags.len();
+        let code = count(&self.flags, OPCODE | OPERAND);
+        let data = count(&self.flags, DATA);
+        let unused = self.flags.iter().filter(|f| **f == 0).count();
+        let mut out = format!("Coverage of {} bytes of ROM:\n", total);
+        writeln!(out, "  Executed  {:>7} bytes  {:>5.1}%", code, percent(code, total)).unwrap();
+        writeln!(out, "  Data      {:>7} bytes  {:>5.1}%", data, percent(data, total)).unwrap();
+        writeln!(out, "  Unused    {:>7} bytes  {:>5.1}%", unused, percent(unused, total)).unwrap();
+        writeln!(out, "  {} instructions executed", count(&self.flags, OPCODE)).unwrap();
+        for (bank, flags) in self.flags.chunks(BANK_SIZE).enumerate() {
+            let (code, data) = (count(flags, OPCODE | OPERAND), count(flags, DATA));
+            writeln!(
+                out,
+                "  Bank {:02X}   {:>5.1}% executed  {:>5.1}% data",
+                bank,
+                percent(code, flags.len()),
+                percent(data, flags.len())
+            )
+            .unwrap();
+        }
+
+        // Each routine never run, with a call to it if one was found. Labels of data that was read aren't routines
+        let mut uncovered = BTreeMap::new();
+        for sym in symbols.iter().filter(|s| s.addr < 0x8000 && !s.name.contains('.')) {
+            let offset = rom_offset(sym.bank as usize, sym.addr);
+            if offset < total && self.flags[offset] & (OPCODE | DATA) == 0 {
+                uncovered.insert((sym.bank as usize, sym.addr), None);
+            }
+        }
+        for offset in (0..total).filter(|o| self.flags[*o] & OPCODE != 0) {
+            let bank = offset / BANK_SIZE;
+            let addr = match bank {
+                0 => offset as u16,
+                _ => (BANK_SIZE + offset % BANK_SIZE) as u16,
+            };
+            let (ins, info) = optable::decode_at(|a| rom_byte(rom, bank, a), addr);
+            let target = match info.operands[0] {
+                Operand::Vector(vec) => vec as u16,
+                _ if info.mnemonic == "CALL" => ins.immediate(),
+                _ => continue,
+            };
+            let target_bank = if target < BANK_SIZE as u16 { 0 } else { bank };
+            if target < 0x8000 && self.flags(target_bank, target) & OPCODE == 0 {
+                uncovered.entry((target_bank, target)).or_insert(None).get_or_insert((bank, addr));
+            }
+        }
+
+        writeln!(out, "Uncovered routines: {}", uncovered.len()).unwrap();
+        for ((bank, addr), caller) in uncovered {
+            write!(out, "  {:02X}:{:04X}", bank, addr).unwrap();
+            if let Some(label) = symbols.at_in(bank as u16, addr) {
+                write!(out, " <{}>", label).unwrap();
+            }
+            if let Some((bank, addr)) = caller {
+                write!(out, "  called from {:02X}:{:04X}", bank, addr).unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Disassembles `start..end` of a ROM image as `disassemble_rom` does, except that data that was never
+    /// executed is shown as `db` lines, and instructions never executed are cut short rather than run into code
+    pub fn disassemble(&self, rom: &[u8], bank: usize, start: u16, end: u16) -> Vec<DisasmLine> {
+        let read = |addr: u16| rom_byte(rom, bank, addr);
+        let flags = |addr: u32| self.flags(bank, addr as u16);
+        let is_data = |addr: u32| flags(addr) & DATA != 0 && flags(addr) & (OPCODE | OPERAND) == 0;
+        let data_line = |addr: u32, len: u32| {
+            let bytes = (0..len).map(|i| read((addr + i) as u16)).collect::<Vec<u8>>();
+            let text = bytes.iter().map(|b| format!("${:02X}", b)).collect::<Vec<String>>();
+            DisasmLine {
+                addr: addr as u16,
+                bytes,
+                text: format!("db {}", text.join(",")),
+                target: None,
+            }
+        };
+
+        let mut lines = Vec::new();
+        let mut addr = start as u32;
+        while addr < end as u32 {
+            let line = if is_data(addr) {
+                let more = (1..DATA_ROW as u32)
+                    .take_while(|i| addr + i < end as u32 && is_data(addr + i) && flags(addr + i) & JUMP_TARGET == 0)
+                    .count();
+                data_line(addr, 1 + more as u32)
+            } else {
+                let line = disassemble_at(read, addr as u16);
+                let len = line.bytes.len() as u32;
+                if flags(addr) & OPCODE == 0 && (1..len).any(|i| flags(addr + i) & OPCODE != 0) {
+                    data_line(addr, 1)
+                } else {
+                    line
+                }
+            };
+            addr += line.bytes.len() as u32;
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Marks a line with what its first byte was used for: `C` executed, `D` read as data, `>` jumped to
+    pub fn marker(&self, bank: usize, addr: u16) -> String {
+        let flags = self.flags(bank, addr);
+        let flag = |mask: u8, c: char| if flags & mask != 0 { c } else { ' ' };
+        [flag(OPCODE, 'C'), flag(DATA, 'D'), flag(JUMP_TARGET, '>')].iter().collect()
+    }
+
+    /// A disassembly of `start..end` annotated with the coverage of each line, in the format of `gbemu disasm`
+    pub fn listing(&self, rom: &[u8], bank: usize, start: u16, end: u16, symbols: &Symbols) -> String {
+        let mut out = "; C executed, D read as data, > jumped to\n".to_string();
+        for line in self.disassemble(rom, bank, start, end) {
+            let line_bank = if line.addr < BANK_SIZE as u16 { 0 } else { bank };
+            if let Some(label) = symbols.at_in(line_bank as u16, line.addr) {
+                writeln!(out, "{}:", label).unwrap();
+            }
+            writeln!(
+                out,
+                "{} {}{}",
+                self.marker(line_bank, line.addr),
+                format_line(line_bank, &line),
+                target_comment(symbols, bank as u16, &line)
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_program;
+    use crate::cpu::CPU;
+    use crate::debug::debugger::Debugger;
+
+    #[test]
+    fn test_coverage() {
+        let source = "
+main:
+    ld HL,table
+    ld A,[HL+]
+    ld B,[HL]
+    cp $FF
+    call z,never
+    call routine
+    .loop:
+        jr .loop
+routine:
+    ret
+unused:
+    ret
+never:
+    ret
+table:
+    db $01,$02
+";
+        let program = assemble_program(source).unwrap();
+        let label = |name: &str| program.labels[name];
+        let mut cpu = CPU::headless();
+        cpu.load_code(program.rom.clone());
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+        for (name, addr) in &program.labels {
+            dbg.symbols.add(0, *addr, name);
+        }
+
+        assert_eq!(dbg.command(&mut cpu, "coverage start"), "Recording coverage\n");
+        for _ in 0..20 {
+            cpu.tick();
+        }
+        let summary = dbg.command(&mut cpu, "coverage");
+        assert!(summary.contains("Uncovered routines: 2\n"), "{}", summary);
+        let call = label("main") + 7;
+        assert!(summary.contains(&format!("<never>  called from 00:{:04X}\n", call)), "{}", summary);
+        assert!(summary.contains("<unused>\n"), "{}", summary);
+
+        let coverage = cpu.coverage.as_ref().unwrap();
+        assert_eq!(coverage.flags(0, label("main")), OPCODE | JUMP_TARGET);
+        assert_eq!(coverage.flags(0, label("main") + 1), OPERAND);
+        assert_eq!(coverage.flags(0, label("routine")), OPCODE | JUMP_TARGET);
+        assert_eq!(coverage.flags(0, label("table")), DATA);
+        assert_eq!(coverage.flags(0, label("table") + 1), DATA);
+        assert_eq!(coverage.flags(0, label("never")), 0);
+
+        let listing = coverage.listing(&program.rom, 1, label("main"), label("table") + 2, &dbg.symbols);
+        assert!(listing.contains("C > 00:0150  21"), "{}", listing);
+        assert!(listing.contains("CALL Z,$"), "{}", listing);
+        assert!(listing.contains(" D  00:"), "{}", listing);
+        assert!(listing.ends_with("db $01,$02\n"), "{}", listing);
+
+        // Saving merges with what was saved before
+        let path = std::env::temp_dir().join(format!("gbemu-coverage-{}.cdl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        coverage.save(&path).unwrap();
+        let mut other = Coverage::new(program.rom.len());
+        other.execute(label("never"), 1);
+        other.save(&path).unwrap();
+        let merged = Coverage::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(merged.flags(0, label("never")), OPCODE);
+        assert_eq!(merged.flags(0, label("routine")), OPCODE | JUMP_TARGET);
+    }
+}
@@ -0,0 +1,701 @@
+//! A Debug Adapter Protocol server, so editors can debug the Game Boy program rather than the emulator
+
+use crate::asm::{assemble_program, Program};
+use crate::cpu::mem::MemoryRegister;
+use crate::cpu::CPU;
+use crate::debug::debugger::{format_registers, set_register, Debugger, Reverse, RunMode};
+use crate::debug::disasm::{back_up, disassemble_at};
+use crate::debug::expr;
+use crate::util::parse_hex;
+use serde_json::{json, Value};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+
+/// The only thread, as the SM83 has one
+const THREAD_ID: u64 = 1;
+/// Variable references for the scopes of the one stack frame
+const REGISTERS_REF: u64 = 1;
+const IO_REF: u64 = 2;
+
+/// Assembly the ROM was built from, for mapping between addresses and lines
+struct SourceMap {
+    path: PathBuf,
+    program: Program,
+}
+
+impl SourceMap {
+    /// The line of the statement at an address
+    fn line_at(&self, addr: u16) -> Option<usize> {
+        self.program.lines.iter().find(|(a, _)| *a == addr).map(|(_, line)| *line)
+    }
+
+    /// The first statement at or after a line, as GDB and most debuggers move breakpoints on blank lines down
+    fn addr_at(&self, line: usize) -> Option<(u16, usize)> {
+        self.program.lines.iter().find(|(_, l)| *l >= line).copied()
+    }
+
+    /// The nearest label at or before an address, with the offset from it
+    fn label_for(&self, addr: u16) -> Option<(&str, u16)> {
+        self.program
+            .labels
+            .iter()
+            .filter(|(name, a)| **a <= addr && !name.contains('.'))
+            .max_by_key(|(_, a)| **a)
+            .map(|(name, a)| (name.as_str(), addr - a))
+    }
+}
+
+/// Like the GDB stub, listens on a local TCP port, is polled from the main loop and works through the shared `Debugger`
+pub struct DapServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    buffer: Vec<u8>,
+    seq: u64,
+    source: Option<SourceMap>,
+    /// Whether execution was running when last polled, to notice it stopping or starting
+    was_running: bool,
+    /// Why execution is expected to stop next, given as the reason of the stopped event
+    stop_reason: &'static str,
+    /// Why a request that has just completed stopped execution, reported once it has been responded to
+    stopped_by: Option<&'static str>,
+    /// Addresses of breakpoints set in the source and by instruction, so each request can replace its own set
+    source_breakpoints: Vec<u16>,
+    instruction_breakpoints: Vec<u16>,
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Parses an address given as a memory or instruction reference
+fn parse_reference(v: &Value) -> Option<u16> {
+    parse_hex(v.as_str()?)
+}
+
+fn reference(addr: u16) -> String {
+    format!("0x{:04X}", addr)
+}
+
+impl DapServer {
+    pub fn listen(port: u16) -> io::Result<DapServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(DapServer {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            seq: 1,
+            source: None,
+            was_running: false,
+            stop_reason: "pause",
+            stopped_by: None,
+            source_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    /// Services the connection without blocking: accepts a client, handles any requests it has sent,
+    /// and tells it when execution stops or continues
+    pub fn poll(&mut self, cpu: &mut CPU, dbg: &mut Debugger) {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, addr)) if stream.set_nonblocking(true).is_ok() => {
+                    println!("Debug adapter client connected from {}", addr);
+                    self.client = Some(stream);
+                    self.buffer.clear();
+                    self.was_running = dbg.is_running();
+                }
+                _ => return,
+            }
+        }
+
+        if let Err(e) = self.receive(cpu, dbg) {
+            println!("Debug adapter client disconnected: {}", e);
+            self.client = None;
+            return;
+        }
+
+        let running = dbg.is_running();
+        if self.was_running && !running {
+            let reason = if dbg.last_watch.is_some() {
+                "data breakpoint"
+            } else {
+                self.stop_reason
+            };
+            self.event("stopped", json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}));
+        } else if !self.was_running && running {
+            self.event("continued", json!({"threadId": THREAD_ID, "allThreadsContinued": true}));
+        }
+        self.was_running = running;
+        // Anything that stops execution without the client asking is taken to be a breakpoint
+        if !running {
+            self.stop_reason = "breakpoint";
+        }
+    }
+
+    fn receive(&mut self, cpu: &mut CPU, dbg: &mut Debugger) -> io::Result<()> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.client.as_mut().unwrap().read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Each message is a JSON body, preceded by headers giving its length
+        while let Some(header_end) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            let headers = String::from_utf8_lossy(&self.buffer[..header_end]).into_owned();
+            let len = headers
+                .lines()
+                .find_map(|h| h.strip_prefix("Content-Length:"))
+                .and_then(|l| l.trim().parse::<usize>().ok())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "missing Content-Length"))?;
+            let start = header_end + 4;
+            if self.buffer.len() < start + len {
+                break;
+            }
+            let body = self.buffer[start..start + len].to_vec();
+            self.buffer.drain(..start + len);
+
+            let request = serde_json::from_slice::<Value>(&body)?;
+            if request["type"] == "request" {
+                self.request(cpu, dbg, &request)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        let client = self.client.as_mut().unwrap();
+        // The socket is non-blocking, but a large reply could fill the send buffer, so block while writing
+        client.set_nonblocking(false)?;
+        let result = write!(client, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        client.set_nonblocking(true)?;
+        result
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        if let Err(e) = self.send(json!({"type": "event", "event": event, "body": body})) {
+            println!("Debug adapter client disconnected: {}", e);
+            self.client = None;
+        }
+    }
+
+    fn request(&mut self, cpu: &mut CPU, dbg: &mut Debugger, request: &Value) -> io::Result<()> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let result = self.handle(cpu, dbg, command, args);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)?;
+
+        // Configuration starts once the client knows the adapter is initialized
+        if command == "initialize" {
+            self.send(json!({"type": "event", "event": "initialized"}))?;
+        }
+        // Stopping on entry and going back happen straight away, without ever running, so the stop is reported here
+        if let Some(reason) = self.stopped_by.take() {
+            self.send(json!({
+                "type": "event",
+                "event": "stopped",
+                "body": {"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true},
+            }))?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, dbg: &mut Debugger, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsLogPoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsSetVariable": true,
+                "supportsEvaluateForHovers": true,
+                "supportsSteppingGranularity": true,
+                "supportsStepBack": true,
+            })),
+            // Both attach to the ROM already loaded. `source` is the assembly it was built from with `gbemu asm`,
+            // reassembled to map between addresses and lines. Without it everything is shown as disassembly
+            "launch" | "attach" => {
+                if let Some(path) = args["source"].as_str() {
+                    let text = std::fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+                    let program = assemble_program(&text).map_err(|e| format!("{}: {}", path, e))?;
+                    self.source = Some(SourceMap {
+                        path: PathBuf::from(path),
+                        program,
+                    });
+                }
+                if args["stopOnEntry"].as_bool() == Some(true) {
+                    dbg.stop(cpu.reg.pc);
+                    self.stopped_by = Some("entry");
+                    self.was_running = false;
+                } else {
+                    dbg.mode = RunMode::Running;
+                }
+                Ok(Value::Null)
+            }
+            "configurationDone" | "setExceptionBreakpoints" => Ok(Value::Null),
+            "disconnect" => {
+                dbg.mode = RunMode::Running;
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({"threads": [{"id": THREAD_ID, "name": "SM83"}]})),
+            "setBreakpoints" => {
+                let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+                for addr in std::mem::take(&mut self.source_breakpoints) {
+                    dbg.remove_breakpoint_at(addr);
+                }
+                let mut set = Vec::new();
+                for bp in requested {
+                    let line = bp["line"].as_u64().unwrap_or(0) as usize;
+                    let found = self.source.as_ref().and_then(|s| s.addr_at(line));
+                    let result = match found {
+                        Some((addr, line)) => match self.add_breakpoint(dbg, addr, &bp) {
+                            Ok(id) => {
+                                self.source_breakpoints.push(addr);
+                                let addr = reference(addr);
+                                json!({"id": id, "verified": true, "line": line, "instructionReference": addr})
+                            }
+                            Err(e) => json!({"verified": false, "message": e}),
+                        },
+                        None => json!({"verified": false, "message": "No code at or after this line"}),
+                    };
+                    set.push(result);
+                }
+                Ok(json!({ "breakpoints": set }))
+            }
+            "setInstructionBreakpoints" => {
+                let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+                for addr in std::mem::take(&mut self.instruction_breakpoints) {
+                    dbg.remove_breakpoint_at(addr);
+                }
+                let mut set = Vec::new();
+                for bp in requested {
+                    let addr = parse_reference(&bp["instructionReference"])
+                        .map(|a| a.wrapping_add(bp["offset"].as_i64().unwrap_or(0) as u16));
+                    let result = match addr.map(|a| (a, self.add_breakpoint(dbg, a, &bp))) {
+                        Some((addr, Ok(id))) => {
+                            self.instruction_breakpoints.push(addr);
+                            json!({"id": id, "verified": true, "instructionReference": reference(addr)})
+                        }
+                        Some((_, Err(e))) => json!({"verified": false, "message": e}),
+                        None => json!({"verified": false, "message": "Invalid instruction reference"}),
+                    };
+                    set.push(result);
+                }
+                Ok(json!({ "breakpoints": set }))
+            }
+            "stackTrace" => {
+                // The innermost frame is at PC, and each call below it is at the instruction that made it
+                let mut pcs = vec![(cpu.reg.pc, false)];
+                for frame in cpu.calls.frames.iter().rev() {
+                    match frame.interrupt {
+                        true => pcs.push((frame.return_addr, true)),
+                        false => pcs.push((frame.caller, false)),
+                    }
+                }
+                let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+                let levels = match args["levels"].as_u64() {
+                    Some(0) | None => pcs.len(),
+                    Some(n) => n as usize,
+                };
+                let frames = pcs
+                    .iter()
+                    .enumerate()
+                    .skip(start)
+                    .take(levels)
+                    .map(|(id, (pc, interrupted))| {
+                        let mut name = self.frame_name(dbg, *pc);
+                        if *interrupted {
+                            name += " (interrupted)";
+                        }
+                        let mut frame = json!({
+                            "id": id,
+                            "name": name,
+                            "line": 0,
+                            "column": 0,
+                            "instructionPointerReference": reference(*pc),
+                        });
+                        if let Some((source, line)) = self.source.as_ref().and_then(|s| Some((s, s.line_at(*pc)?))) {
+                            frame["source"] = json!({"name": source_name(&source.path), "path": source.path});
+                            frame["line"] = json!(line);
+                            frame["column"] = json!(1);
+                        }
+                        frame
+                    })
+                    .collect::<Vec<Value>>();
+                Ok(json!({"stackFrames": frames, "totalFrames": pcs.len()}))
+            }
+            "scopes" => Ok(json!({"scopes": [
+                {"name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false},
+                {"name": "I/O", "variablesReference": IO_REF, "expensive": false},
+            ]})),
+            "variables" => {
+                let variables = match args["variablesReference"].as_u64() {
+                    Some(REGISTERS_REF) => registers(cpu),
+                    Some(IO_REF) => MemoryRegister::NAMED
+                        .iter()
+                        .map(|r| {
+                            let value = cpu.mem.get_register(*r);
+                            json!({
+                                "name": r.name(),
+                                "value": format!("${:02X}", value),
+                                "variablesReference": 0,
+                                "memoryReference": reference(r.to_addr()),
+                            })
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                Ok(json!({ "variables": variables }))
+            }
+            "setVariable" => {
+                if args["variablesReference"].as_u64() != Some(REGISTERS_REF) {
+                    return Err("Only registers can be set".to_string());
+                }
+                let name = args["name"].as_str().unwrap_or("").to_uppercase();
+                let value = args["value"].as_str().unwrap_or("");
+                let value = expr::parse(value).and_then(|e| e.eval(cpu))?;
+                set_register(cpu, &name, value as u16)?;
+                dbg.edited(cpu);
+                let value = registers(cpu)
+                    .into_iter()
+                    .find(|v| v["name"] == json!(name))
+                    .map(|v| v["value"].clone())
+                    .unwrap_or_default();
+                Ok(json!({ "value": value }))
+            }
+            "readMemory" => {
+                let addr = parse_reference(&args["memoryReference"]).ok_or("Invalid memory reference")?;
+                let addr = addr.wrapping_add(args["offset"].as_i64().unwrap_or(0) as u16);
+                let count = args["count"].as_u64().unwrap_or(0).min(0x10000 - addr as u64) as u32;
+                let data = (0..count).map(|i| cpu.mem.get_addr(addr + i as u16)).collect::<Vec<u8>>();
+                Ok(json!({"address": reference(addr), "data": base64(&data)}))
+            }
+            "disassemble" => {
+                let addr = parse_reference(&args["memoryReference"]).ok_or("Invalid memory reference")?;
+                let addr = addr.wrapping_add(args["offset"].as_i64().unwrap_or(0) as u16);
+                let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+                let count = args["instructionCount"].as_u64().unwrap_or(0);
+                let mut at = if offset < 0 {
+                    back_up(&cpu.mem, addr, (-offset).min(0x100) as u16)
+                } else {
+                    addr
+                };
+                let mut instructions = Vec::new();
+                for i in 0..offset.max(0) as u64 + count {
+                    let line = disassemble_at(|a| cpu.mem.get_addr(a), at);
+                    at = at.wrapping_add(line.bytes.len() as u16);
+                    if i < offset.max(0) as u64 {
+                        continue;
+                    }
+                    let bytes = line.bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>();
+                    let mut ins = json!({
+                        "address": reference(line.addr),
+                        "instructionBytes": bytes.join(" "),
+                        "instruction": line.text,
+                    });
+                    if let Some(source) = &self.source {
+                        if let Some(line) = source.line_at(line.addr) {
+                            ins["location"] = json!({"name": source_name(&source.path), "path": source.path});
+                            ins["line"] = json!(line);
+                        }
+                    }
+                    if let Some(label) = self.label_for(dbg, line.addr).filter(|(_, offset)| *offset == 0) {
+                        ins["symbol"] = json!(label.0);
+                    }
+                    instructions.push(ins);
+                }
+                Ok(json!({ "instructions": instructions }))
+            }
+            "continue" => {
+                dbg.mode = RunMode::Running;
+                Ok(json!({"allThreadsContinued": true}))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.stop_reason = "step";
+                match command {
+                    "next" => dbg.step_over(cpu),
+                    "stepIn" => dbg.mode = RunMode::Step(1),
+                    _ => dbg.step_out(cpu),
+                }
+                Ok(Value::Null)
+            }
+            "stepBack" | "reverseContinue" => {
+                let how = if command == "stepBack" {
+                    Reverse::Step(1)
+                } else {
+                    Reverse::Continue
+                };
+                dbg.reverse(cpu, how)?;
+                self.stopped_by = Some(match command {
+                    "stepBack" => "step",
+                    _ if dbg.last_watch.is_some() => "data breakpoint",
+                    _ => "breakpoint",
+                });
+                Ok(Value::Null)
+            }
+            "pause" => {
+                if dbg.is_running() {
+                    self.stop_reason = "pause";
+                    dbg.stop(cpu.reg.pc);
+                }
+                Ok(Value::Null)
+            }
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or("");
+                match expr::parse(expression).and_then(|e| e.eval(cpu)) {
+                    Ok(value) => Ok(json!({"result": format!("${:X} ({})", value, value), "variablesReference": 0})),
+                    // Anything typed into the debug console that isn't an expression is run as a debugger command
+                    Err(_) if args["context"] == "repl" => {
+                        let out = dbg.command(cpu, expression);
+                        Ok(json!({"result": out.trim_end(), "variablesReference": 0}))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            _ => Err(format!("Unsupported request {}", command)),
+        }
+    }
+
+    /// Adds a breakpoint with the condition and log message of a source or instruction breakpoint
+    fn add_breakpoint(&mut self, dbg: &mut Debugger, addr: u16, bp: &Value) -> Result<u32, String> {
+        let condition = match bp["condition"].as_str().filter(|c| !c.trim().is_empty()) {
+            Some(c) => Some((c.to_string(), expr::parse(c)?)),
+            None => None,
+        };
+        let id = dbg.add_breakpoint_at(addr, false, condition)?;
+        if let Some(message) = bp["logMessage"].as_str() {
+            if let Some(bp) = dbg.breakpoints.iter_mut().find(|bp| bp.id == id) {
+                bp.log = Some(message.to_string());
+            }
+        }
+        Ok(id)
+    }
+
+    /// The nearest label before an address, from the source if given or otherwise the symbols loaded
+    fn label_for<'a>(&'a self, dbg: &'a Debugger, addr: u16) -> Option<(&'a str, u16)> {
+        match &self.source {
+            Some(source) => source.label_for(addr),
+            None => dbg.symbols.nearest(addr),
+        }
+    }
+
+    fn frame_name(&self, dbg: &Debugger, pc: u16) -> String {
+        match self.label_for(dbg, pc) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, offset)) => format!("{}+${:X}", label, offset),
+            None => format!("${:04X}", pc),
+        }
+    }
+}
+
+fn source_name(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// The registers as variables, named as `set` expects them
+fn registers(cpu: &CPU) -> Vec<Value> {
+    let byte = |name: &str, v: u16| json!({"name": name, "value": format!("${:02X}", v), "variablesReference": 0});
+    let word = |name: &str, v: u16| {
+        json!({"name": name, "value": format!("${:04X}", v), "variablesReference": 0, "memoryReference": reference(v)})
+    };
+    let flags = format_registers(cpu);
+    let flags = flags.split_whitespace().find_map(|w| w.strip_prefix("Flags=")).unwrap_or("");
+    vec![
+        byte("A", cpu.reg.af >> 8),
+        json!({"name": "F", "value": format!("${:02X} {}", cpu.reg.af & 0xFF, flags), "variablesReference": 0}),
+        byte("B", cpu.reg.bc >> 8),
+        byte("C", cpu.reg.bc & 0xFF),
+        byte("D", cpu.reg.de >> 8),
+        byte("E", cpu.reg.de & 0xFF),
+        byte("H", cpu.reg.hl >> 8),
+        byte("L", cpu.reg.hl & 0xFF),
+        word("BC", cpu.reg.bc),
+        word("DE", cpu.reg.de),
+        word("HL", cpu.reg.hl),
+        word("SP", cpu.reg.sp),
+        word("PC", cpu.reg.pc),
+        json!({"name": "IME", "value": cpu.reg.ie.to_string(), "variablesReference": 0}),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    struct Client {
+        stream: TcpStream,
+        received: Vec<u8>,
+        seq: u64,
+    }
+
+    impl Client {
+        fn send(&mut self, command: &str, arguments: Value) {
+            let body = json!({"seq": self.seq, "type": "request", "command": command, "arguments": arguments});
+            self.seq += 1;
+            let body = body.to_string();
+            write!(self.stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+
+        /// Polls the server until a message matching `f` arrives, running the CPU whenever it is told to
+        fn wait(
+            &mut self,
+            dap: &mut DapServer,
+            cpu: &mut CPU,
+            dbg: &mut Debugger,
+            f: impl Fn(&Value) -> bool,
+        ) -> Value {
+            let mut buf = [0; 4096];
+            loop {
+                dap.poll(cpu, dbg);
+                while dbg.is_running() {
+                    dbg.step(cpu);
+                }
+                dap.poll(cpu, dbg);
+                if let Ok(n) = self.stream.read(&mut buf) {
+                    self.received.extend_from_slice(&buf[..n]);
+                }
+                while let Some(end) = self.received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    let headers = String::from_utf8_lossy(&self.received[..end]).into_owned();
+                    let len = headers.trim_start_matches("Content-Length: ").parse::<usize>().unwrap();
+                    if self.received.len() < end + 4 + len {
+                        break;
+                    }
+                    let message = serde_json::from_slice::<Value>(&self.received[end + 4..end + 4 + len]).unwrap();
+                    self.received.drain(..end + 4 + len);
+                    if f(&message) {
+                        return message;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dap_session() {
+        let source = "
+main:
+    ld A,$12
+    call routine
+    ld B,A
+    .loop:
+        jr .loop
+routine:
+    inc A
+    ret
+";
+        let path = std::env::temp_dir().join(format!("gbemu-dap-{}.s", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let program = assemble_program(source).unwrap();
+        let (addr, line) = program.lines[2];
+        let mut cpu = CPU::headless();
+        cpu.load_code(program.rom);
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+
+        let mut dap = DapServer::listen(0).unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", dap.port())).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let mut client = Client {
+            stream,
+            received: Vec::new(),
+            seq: 1,
+        };
+        let response = |command: &'static str| move |m: &Value| m["type"] == "response" && m["command"] == command;
+
+        client.send("initialize", json!({"adapterID": "gbemu"}));
+        let init = client.wait(&mut dap, &mut cpu, &mut dbg, response("initialize"));
+        assert_eq!(init["body"]["supportsDisassembleRequest"], true);
+        client.wait(&mut dap, &mut cpu, &mut dbg, |m| m["event"] == "initialized");
+
+        // The stop is only reported once the launch has been responded to
+        client.send("launch", json!({"source": path, "stopOnEntry": true}));
+        client.wait(&mut dap, &mut cpu, &mut dbg, response("launch"));
+        client.wait(&mut dap, &mut cpu, &mut dbg, |m| m["event"] == "stopped" && m["body"]["reason"] == "entry");
+
+        client.send("setBreakpoints", json!({"source": {"path": path}, "breakpoints": [{"line": line}]}));
+        let set = client.wait(&mut dap, &mut cpu, &mut dbg, response("setBreakpoints"));
+        assert_eq!(set["body"]["breakpoints"][0]["verified"], true);
+
+        client.send("continue", json!({"threadId": THREAD_ID}));
+        let stopped = client.wait(&mut dap, &mut cpu, &mut dbg, |m| m["event"] == "stopped");
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+        assert_eq!(cpu.reg.pc, addr);
+
+        client.send("stackTrace", json!({"threadId": THREAD_ID}));
+        let trace = client.wait(&mut dap, &mut cpu, &mut dbg, response("stackTrace"));
+        assert_eq!(trace["body"]["totalFrames"], 1);
+        assert_eq!(trace["body"]["stackFrames"][0]["line"], line);
+        assert_eq!(trace["body"]["stackFrames"][0]["instructionPointerReference"], reference(addr));
+
+        client.send("variables", json!({"variablesReference": REGISTERS_REF}));
+        let vars = client.wait(&mut dap, &mut cpu, &mut dbg, response("variables"));
+        let pc = vars["body"]["variables"].as_array().unwrap().iter().find(|v| v["name"] == "PC").unwrap().clone();
+        assert_eq!(pc["value"], format!("${:04X}", addr));
+
+        client.send("next", json!({"threadId": THREAD_ID}));
+        let stopped = client.wait(&mut dap, &mut cpu, &mut dbg, |m| m["event"] == "stopped");
+        assert_eq!(stopped["body"]["reason"], "step");
+
+        cpu.mem.set_addr(0xC000, 0xAB);
+        cpu.mem.set_addr(0xC001, 0xCD);
+        client.send("readMemory", json!({"memoryReference": "0xC000", "count": 2}));
+        let mem = client.wait(&mut dap, &mut cpu, &mut dbg, response("readMemory"));
+        assert_eq!(mem["body"]["data"], base64(&[0xAB, 0xCD]));
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(&[0xAB, 0xCD]), "q80=");
+        // Reads stop at the end of the address space, which can be read whole
+        client.send("readMemory", json!({"memoryReference": "0xFFFF", "count": 2}));
+        let mem = client.wait(&mut dap, &mut cpu, &mut dbg, response("readMemory"));
+        assert_eq!(mem["body"]["data"].as_str().unwrap().len(), 4);
+        client.send("readMemory", json!({"memoryReference": "0x0000", "count": 0x10000}));
+        let mem = client.wait(&mut dap, &mut cpu, &mut dbg, response("readMemory"));
+        assert_eq!(mem["body"]["data"].as_str().unwrap().len(), 0x10000 / 3 * 4 + 4);
+
+        client.send("evaluate", json!({"expression": "[$C000] + 1", "context": "watch"}));
+        let eval = client.wait(&mut dap, &mut cpu, &mut dbg, response("evaluate"));
+        assert_eq!(eval["body"]["result"], "$AC (172)");
+
+        client.send("disassemble", json!({"memoryReference": reference(addr), "instructionCount": 1}));
+        let dis = client.wait(&mut dap, &mut cpu, &mut dbg, response("disassemble"));
+        assert_eq!(dis["body"]["instructions"][0]["line"], line);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1262 @@
+use crate::cpu::isa::RegisterName;
+use crate::cpu::mem::MemoryRegister;
+use crate::cpu::optable;
+use crate::cpu::CPU;
+use crate::debug::coverage::Coverage;
+use crate::debug::disasm::{back_up, disassemble_at, target_comment};
+use crate::debug::expr::{self, Expr};
+use crate::debug::oam;
+use crate::debug::profile::Profiler;
+use crate::debug::reverse::{Recording, DEFAULT_BUDGET};
+use crate::debug::symbols::Symbols;
+use crate::debug::watch::{Access, Accessor, WatchHit, WatchKind, Watchpoint};
+use crate::util::parse_hex;
+use std::fmt::Write;
+
+const HELP: &str = "\
+Execution:
+  continue, c              Run until stopped
+  step, s [N]              Execute N instructions (default 1)
+  next, n                  Step over calls and RSTs
+  finish, out              Run until the current routine or interrupt handler returns
+  until, runto <addr>      Run until PC reaches an address
+Breakpoints:
+  break, b [add] <addr> [if <cond>]
+                           Add a breakpoint, which only stops when the condition holds
+  break rm <id|addr>       Remove a breakpoint
+  break list               List breakpoints
+  break enable <id>        Enable a breakpoint
+  break disable <id>       Disable a breakpoint
+  break ignore <id> [N]    Ignore the next N hits of a breakpoint
+  break cond <id> [cond]   Set or clear the condition of a breakpoint, eg break cond 1 LY == 144 && [HL] != 0
+  break log <id> [message] Log a message instead of stopping, or stop again if no message is given.
+                           {expr} in the message is replaced by its value, eg break log 1 A is {A}
+  tbreak <addr> [if <cond>] Add a breakpoint that is removed once hit
+Watchpoints:
+  watch [read|write|change|access] <addr>[-<end>] [log]
+                           Watch an address or range, by default for writes.
+                           change only triggers on writes of a different value, access on any read or write,
+                           and log reports accesses without stopping
+  watch rm <id>            Remove a watchpoint
+  watch list               List watchpoints
+Reverse execution:
+  record [stop]            Start or stop recording execution, so it can be stepped back through
+  record budget <MB>       Set the memory snapshots may use, limiting how far back can be gone
+  record info              Show how much has been recorded
+  rstep, rs [N]            Step back N instructions (default 1)
+  rnext, rn                Step back over calls and RSTs
+  rfinish                  Go back to the call of the current routine
+  rcontinue, rc            Go back to the previous breakpoint or watchpoint hit
+Inspection:
+  backtrace, bt            Show the calls and interrupts that led to PC
+  regs, r                  Show registers
+  set <reg> <value>        Set a register, eg set HL $C000
+  mem, x <addr> [len]      Dump memory
+  poke <addr> <byte>...    Write bytes to memory, without side effects
+  disasm, d [addr] [count] Disassemble, around PC by default
+  io [name]                Show I/O registers, all of them or by name, eg io LCDC
+  oam [N]                  List the sprites in OAM, or show sprite N drawn out
+  oam overlay [on|off]     Outline sprites on the screen, in red if dropped by the limit per line
+Profiling:
+  profile start            Start profiling, discarding any previous profile
+  profile stop             Stop profiling, keeping the profile to report on
+  profile report [file]    Show where time was spent by routine, interrupt, label, address and opcode
+  profile folded <file>    Write the time in each call stack as folded stacks, for flame graph tools
+Coverage:
+  coverage start           Record what each byte of ROM is used for: executed, read as data or jumped to
+  coverage stop            Stop recording coverage, keeping it to report on
+  coverage [summary]       Show how much of the ROM was covered, and the routines that weren't
+  coverage save <file>     Save coverage, merged with any already saved to the file
+  coverage load <file>     Merge coverage saved by an earlier run
+  coverage annotate <file> Write a disassembly of the ROM marked with the coverage of each line
+Symbols:
+  sym load <file>          Load labels from a .sym or .map file, as well as any found alongside the ROM
+  sym <name|addr>          Look up a symbol, or the nearest one before an address
+Other:
+  history                  List previous commands, repeat one with !N or the last with !!
+  help                     Show this message
+  quit, q                  Exit the emulator
+An empty line repeats the previous command. Numbers are hex, except counts, and addresses can be symbols";
+
+pub struct Breakpoint {
+    pub id: u32,
+    pub addr: u16,
+    pub enabled: bool,
+    /// Removed once it is hit
+    pub temporary: bool,
+    /// Times execution has reached the breakpoint, including ignored hits
+    pub hits: u32,
+    /// Hits left to ignore before the breakpoint stops execution
+    pub ignore: u32,
+    /// Only counts as a hit when this evaluates to non-zero, kept alongside its source to list it
+    pub condition: Option<(String, Expr)>,
+    /// Logged on a hit instead of stopping
+    pub log: Option<String>,
+}
+
+/// What the emulator is doing between commands
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RunMode {
+    /// Waiting for a command
+    Stopped,
+    /// Running freely
+    Running,
+    /// Stopping after the given number of instructions
+    Step(u32),
+    /// Stepping over a call, stopping once it returns to `addr` with the stack back at `sp`
+    StepOver { addr: u16, sp: u16 },
+    /// Stopping once the call stack is shallower than `depth`.
+    /// If no calls are known about it stops once a return leaves the stack above `sp` instead
+    StepOut { sp: u16, depth: usize },
+    /// Stopping when PC reaches an address
+    RunTo(u16),
+}
+
+/// How to go back through a recording
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Reverse {
+    /// Back the given number of instructions
+    Step(u64),
+    /// Back an instruction, skipping over any call it returned from
+    Next,
+    /// Back to the call of the current routine
+    Finish,
+    /// Back to the last breakpoint or watchpoint hit
+    Continue,
+}
+
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub mode: RunMode,
+    /// Set by the quit command
+    pub quit: bool,
+    /// The access that stopped execution, if it was stopped by a watchpoint
+    pub last_watch: Option<WatchHit>,
+    /// Labels for addresses, from the symbol files loaded
+    pub symbols: Symbols,
+    /// Execution recorded to go back through, if recording
+    pub recording: Option<Recording>,
+    /// The memory a recording may use for snapshots
+    record_budget: usize,
+    /// A profile that has been stopped, kept to report on. One still running is the CPU's
+    pub profile: Option<Profiler>,
+    /// Coverage that has been stopped, kept to report on and save. Coverage still being recorded is the CPU's
+    pub coverage: Option<Coverage>,
+    next_id: u32,
+    history: Vec<String>,
+    /// Where execution last stopped, so resuming doesn't immediately hit a breakpoint there again
+    stopped_at: Option<u16>,
+    /// Whether any watchpoints are set, in which case accesses are checked after each instruction
+    watching: bool,
+    /// Output produced while running, such as accesses reported by watchpoints that don't halt
+    messages: Vec<String>,
+}
+
+/// Parses an address or other hex number given to a command, or the name of a symbol.
+/// Symbols take priority, as a label such as `Cafe` is also valid hex
+fn parse_addr(symbols: &Symbols, s: Option<&str>) -> Result<u16, String> {
+    let s = s.ok_or("Missing address")?;
+    if symbols.lookup(s).is_some() {
+        return symbols.address(s);
+    }
+    match parse_hex(s) {
+        Some(addr) => Ok(addr),
+        None if symbols.is_empty() => Err(format!("Invalid address {}, expected hex such as $C000", s)),
+        None => Err(format!("No symbol named {}, and it isn't a hex address", s)),
+    }
+}
+
+/// Parses a breakpoint condition, split into words on the command line. No words means no condition
+fn parse_condition(words: &[&str]) -> Result<Option<(String, Expr)>, String> {
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let source = words.join(" ");
+    let cond = expr::parse(&source).map_err(|e| format!("Invalid condition {}: {}", source, e))?;
+    Ok(Some((source, cond)))
+}
+
+fn parse_count(s: Option<&str>, default: u32) -> Result<u32, String> {
+    match s {
+        Some(s) => s.parse().map_err(|_| format!("Invalid count {}", s)),
+        None => Ok(default),
+    }
+}
+
+/// Whether the opcode at an address is a call, which step over runs through
+fn is_call(opcode: u8) -> bool {
+    matches!(optable::BASE[opcode as usize].mnemonic, "CALL" | "RST")
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(optable::BASE[opcode as usize].mnemonic, "RET" | "RETI")
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            mode: RunMode::Stopped,
+            quit: false,
+            last_watch: None,
+            symbols: Symbols::new(),
+            recording: None,
+            record_budget: DEFAULT_BUDGET,
+            profile: None,
+            coverage: None,
+            next_id: 1,
+            history: Vec::new(),
+            stopped_at: None,
+            watching: false,
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.mode != RunMode::Stopped
+    }
+
+    /// Whether each instruction needs checking to see if execution should stop.
+    /// When it doesn't the CPU can be ticked directly, so free running isn't slowed down
+    pub fn is_stepping(&self) -> bool {
+        match self.mode {
+            RunMode::Stopped => false,
+            RunMode::Running => {
+                self.watching || self.recording.is_some() || self.breakpoints.iter().any(|bp| bp.enabled)
+            }
+            _ => true,
+        }
+    }
+
+    /// Takes the output produced while running
+    pub fn messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// Stops execution at the given PC
+    pub fn stop(&mut self, pc: u16) {
+        self.mode = RunMode::Stopped;
+        self.stopped_at = Some(pc);
+    }
+
+    /// Checks for a breakpoint on the instruction about to be fetched, returning why execution should stop
+    fn check_breakpoints(&mut self, cpu: &CPU) -> Option<String> {
+        let pc = cpu.reg.pc;
+        if cpu.state.halted || self.stopped_at.take() == Some(pc) {
+            return None;
+        }
+
+        let i = self.breakpoints.iter().position(|bp| bp.enabled && bp.addr == pc)?;
+        let bp = &mut self.breakpoints[i];
+        if let Some((source, cond)) = &bp.condition {
+            match cond.eval(cpu) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(format!("Breakpoint {} condition {} failed: {}", bp.id, source, e)),
+            }
+        }
+        bp.hits += 1;
+        if bp.ignore > 0 {
+            bp.ignore -= 1;
+            return None;
+        }
+
+        if let Some(message) = &bp.log {
+            let message = expr::interpolate(message, cpu).unwrap_or_else(|e| format!("{} ({})", message, e));
+            self.messages.push(format!("{}: {}", self.symbols.format_addr(pc), message));
+            if bp.temporary {
+                self.breakpoints.remove(i);
+            }
+            return None;
+        }
+
+        let times = if bp.hits == 1 { "once".to_string() } else { format!("{} times", bp.hits) };
+        if bp.temporary {
+            let bp = self.breakpoints.remove(i);
+            Some(format!("Temporary breakpoint {} hit", bp.id))
+        } else {
+            Some(format!("Breakpoint {} hit {}", bp.id, times))
+        }
+    }
+
+    /// Runs a single instruction while stepping, stopping if it completes the current step or reaches a breakpoint.
+    /// Returns the cycles taken, and why execution stopped if it did
+    pub fn step(&mut self, cpu: &mut CPU) -> (u32, Option<String>) {
+        self.last_watch = None;
+        if let Some(reason) = self.check_breakpoints(cpu) {
+            self.stop(cpu.reg.pc);
+            return (0, Some(reason));
+        }
+
+        let pc = cpu.reg.pc;
+        let opcode = cpu.mem.get_addr(pc);
+        let halted = cpu.state.halted;
+        if let Some(recording) = &mut self.recording {
+            recording.record(cpu);
+        }
+        let cycles = cpu.tick();
+        if let Some(recording) = &mut self.recording {
+            recording.advance();
+        }
+
+        let mut watch_stop = None;
+        for hit in std::mem::take(&mut cpu.mem.watch_hits) {
+            let report = format_watch_hit(cpu, &self.symbols, &hit, pc, halted);
+            if hit.halt {
+                if watch_stop.is_none() {
+                    watch_stop = Some(report);
+                    self.last_watch = Some(hit);
+                }
+            } else {
+                self.messages.push(report);
+            }
+        }
+        for anomaly in cpu.calls.take_anomalies() {
+            self.messages.push(format!("{}: {}", self.symbols.format_addr(anomaly.pc), anomaly.message));
+        }
+
+        let stop = watch_stop.or(match self.mode {
+            RunMode::Step(n) if n <= 1 => Some("Step complete".to_string()),
+            RunMode::Step(n) => {
+                self.mode = RunMode::Step(n - 1);
+                None
+            }
+            RunMode::StepOver { addr, sp } if cpu.reg.pc == addr && cpu.reg.sp >= sp => {
+                Some("Step complete".to_string())
+            }
+            RunMode::StepOut { depth, .. } if depth > 0 && cpu.calls.depth() < depth => {
+                Some("Returned".to_string())
+            }
+            RunMode::StepOut { sp, depth: 0 } if !halted && is_return(opcode) && cpu.reg.sp > sp => {
+                Some("Returned".to_string())
+            }
+            RunMode::RunTo(addr) if cpu.reg.pc == addr => Some(format!("Reached {}", self.symbols.format_addr(addr))),
+            _ => None,
+        });
+
+        if stop.is_some() {
+            self.stop(cpu.reg.pc);
+        }
+        (cycles, stop)
+    }
+
+    /// Steps over the instruction at PC, running any routine it calls through to its return
+    pub fn step_over(&mut self, cpu: &CPU) {
+        let pc = cpu.reg.pc;
+        let opcode = cpu.mem.get_addr(pc);
+        self.mode = if is_call(opcode) && !cpu.state.halted {
+            RunMode::StepOver {
+                addr: pc.wrapping_add(optable::BASE[opcode as usize].length as u16),
+                sp: cpu.reg.sp,
+            }
+        } else {
+            RunMode::Step(1)
+        };
+    }
+
+    /// Runs until the current routine or interrupt handler returns
+    pub fn step_out(&mut self, cpu: &CPU) {
+        self.mode = RunMode::StepOut {
+            sp: cpu.reg.sp,
+            depth: cpu.calls.depth(),
+        };
+    }
+
+    /// Tells the recording, if there is one, that the state was changed other than by running
+    pub fn edited(&mut self, cpu: &CPU) {
+        if let Some(recording) = &mut self.recording {
+            recording.take_snapshot(cpu);
+        }
+    }
+
+    /// Whether execution is at the earliest point recorded
+    pub fn at_recording_start(&self) -> bool {
+        self.recording.as_ref().is_some_and(|r| r.position == r.start())
+    }
+
+    /// Goes back through the recording, returning why it stopped where it did
+    pub fn reverse(&mut self, cpu: &mut CPU, how: Reverse) -> Result<String, String> {
+        let mut recording = self.recording.take().ok_or("Not recording, start with record")?;
+        let result = self.reverse_with(cpu, &mut recording, how);
+        self.recording = Some(recording);
+        let reason = result?;
+        self.stop(cpu.reg.pc);
+        Ok(reason)
+    }
+
+    fn reverse_with(&mut self, cpu: &mut CPU, recording: &mut Recording, how: Reverse) -> Result<String, String> {
+        self.last_watch = None;
+        let depth = cpu.calls.depth();
+        let found = match how {
+            Reverse::Step(n) => Some(recording.position.saturating_sub(n)).filter(|t| *t >= recording.start()),
+            Reverse::Next => recording.search(cpu, false, |cpu| cpu.calls.depth() <= depth),
+            Reverse::Finish if depth == 0 => return Err("Not in a call".to_string()),
+            Reverse::Finish => recording.search(cpu, false, |cpu| cpu.calls.depth() < depth),
+            Reverse::Continue => {
+                let breakpoints = &self.breakpoints;
+                recording.search(cpu, self.watching, |cpu| breakpoint_at(breakpoints, cpu).is_some())
+            }
+        };
+
+        let target = match found {
+            Some(target) => target,
+            None => {
+                recording.seek(cpu, 0);
+                return Ok("Reached the start of the recording".to_string());
+            }
+        };
+        recording.seek(cpu, target);
+        if how != Reverse::Continue {
+            return Ok("Stepped back".to_string());
+        }
+        if let Some(id) = breakpoint_at(&self.breakpoints, cpu) {
+            return Ok(format!("Breakpoint {} hit", id));
+        }
+
+        // Stopped by a watchpoint, so run the instruction that triggered it again to find out which
+        recording.seek(cpu, target - 1);
+        let pc = cpu.reg.pc;
+        let halted = cpu.state.halted;
+        recording.record(cpu);
+        cpu.tick();
+        recording.advance();
+        let hits = std::mem::take(&mut cpu.mem.watch_hits);
+        let hit = hits.into_iter().find(|hit| hit.halt).ok_or("Lost track of the watchpoint hit")?;
+        self.last_watch = Some(hit);
+        Ok(format_watch_hit(cpu, &self.symbols, &hit, pc, halted))
+    }
+
+    /// Describes where execution is, as the instruction at PC
+    pub fn location(&self, cpu: &CPU) -> String {
+        let line = disassemble_at(|addr| cpu.mem.get_addr(addr), cpu.reg.pc);
+        format!("{}: {}", self.symbols.format_addr(line.addr), line.text)
+    }
+
+    /// Runs a line of input, returning the output to show
+    pub fn command(&mut self, cpu: &mut CPU, line: &str) -> String {
+        let line = line.trim();
+        let line = if line.is_empty() {
+            // An empty line repeats the last command, as in GDB
+            match self.history.last() {
+                Some(last) => last.clone(),
+                None => return String::new(),
+            }
+        } else if line == "!!" {
+            match self.history.last() {
+                Some(last) => last.clone(),
+                None => return "No previous command\n".to_string(),
+            }
+        } else if let Some(n) = line.strip_prefix('!') {
+            match n.parse::<usize>().ok().and_then(|n| self.history.get(n.wrapping_sub(1))) {
+                Some(cmd) => cmd.clone(),
+                None => return format!("No command {} in history\n", n),
+            }
+        } else {
+            line.to_string()
+        };
+
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+
+        match self.execute(cpu, &line) {
+            Ok(out) => out,
+            Err(e) => format!("Error: {}\n", e),
+        }
+    }
+
+    fn execute(&mut self, cpu: &mut CPU, line: &str) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("").to_lowercase();
+        let args = words.collect::<Vec<&str>>();
+        let arg = |i: usize| args.get(i).copied();
+        let mut out = String::new();
+
+        match cmd.as_str() {
+            "continue" | "c" => self.mode = RunMode::Running,
+            "step" | "s" => {
+                let n = parse_count(arg(0), 1)?;
+                if n == 0 {
+                    return Err("Can't step 0 instructions".to_string());
+                }
+                self.mode = RunMode::Step(n);
+            }
+            "next" | "n" => self.step_over(cpu),
+            "finish" | "out" => self.step_out(cpu),
+            "until" | "runto" => self.mode = RunMode::RunTo(parse_addr(&self.symbols, arg(0))?),
+            "break" | "b" => return self.breakpoint_command(&args),
+            "tbreak" => return self.add_breakpoint(&args, true),
+            "watch" | "w" => return self.watch_command(cpu, &args),
+            "record" => out = self.record_command(cpu, &args)?,
+            "rstep" | "rs" => {
+                let n = parse_count(arg(0), 1)?;
+                let reason = self.reverse(cpu, Reverse::Step(n as u64))?;
+                out = format!("{} at {}\n", reason, self.location(cpu));
+            }
+            "rnext" | "rn" | "rfinish" | "rcontinue" | "rc" => {
+                let how = match cmd.as_str() {
+                    "rnext" | "rn" => Reverse::Next,
+                    "rfinish" => Reverse::Finish,
+                    _ => Reverse::Continue,
+                };
+                let reason = self.reverse(cpu, how)?;
+                out = format!("{} at {}\n", reason, self.location(cpu));
+            }
+            "backtrace" | "bt" => out = self.backtrace(cpu),
+            "regs" | "r" => out = format_registers(cpu),
+            "set" => {
+                let name = arg(0).ok_or("Missing register")?.to_uppercase();
+                let value = parse_addr(&self.symbols, arg(1))?;
+                set_register(cpu, &name, value)?;
+                self.edited(cpu);
+                out = format_registers(cpu);
+            }
+            "mem" | "x" => {
+                let addr = parse_addr(&self.symbols, arg(0))?;
+                let len = match arg(1) {
+                    Some(len) => parse_hex(len).ok_or_else(|| format!("Invalid length {}", len))?,
+                    None => 0x40,
+                };
+                out = dump_memory(cpu, addr, len);
+            }
+            "poke" => {
+                let addr = parse_addr(&self.symbols, arg(0))?;
+                if args.len() < 2 {
+                    return Err("Missing bytes to write".to_string());
+                }
+                for (i, b) in args[1..].iter().enumerate() {
+                    let value = parse_hex(b)
+                        .filter(|v| *v <= 0xFF)
+                        .ok_or_else(|| format!("Invalid byte {}", b))?;
+                    cpu.mem.set_addr(addr.wrapping_add(i as u16), value as u8);
+                }
+                self.edited(cpu);
+                out = dump_memory(cpu, addr, args.len() as u16 - 1);
+            }
+            "disasm" | "d" => {
+                let count = parse_count(arg(1), 10)?;
+                out = match arg(0) {
+                    Some(a) => disassemble(cpu, &self.symbols, parse_addr(&self.symbols, Some(a))?, count, 0),
+                    None => disassemble(cpu, &self.symbols, cpu.reg.pc, count, 4),
+                };
+            }
+            "io" => match arg(0) {
+                Some(name) => {
+                    let reg = MemoryRegister::from_name(name)
+                        .ok_or_else(|| format!("No I/O register named {}", name))?;
+                    out = format_io(cpu, reg);
+                }
+                None => {
+                    for reg in MemoryRegister::NAMED.iter() {
+                        out += &format_io(cpu, *reg);
+                    }
+                }
+            },
+            "oam" => out = oam_command(cpu, &args)?,
+            "sym" | "symbols" => out = self.symbol_command(&args)?,
+            "profile" | "prof" => out = self.profile_command(cpu, &args)?,
+            "coverage" | "cov" => out = self.coverage_command(cpu, &args)?,
+            "history" => {
+                for (i, cmd) in self.history.iter().enumerate() {
+                    writeln!(out, "{:>4}  {}", i + 1, cmd).unwrap();
+                }
+            }
+            "help" | "h" | "?" => out = format!("{}\n", HELP),
+            "quit" | "q" | "exit" => self.quit = true,
+            _ => return Err(format!("Unknown command {}, try help", cmd)),
+        }
+        Ok(out)
+    }
+
+    fn record_command(&mut self, cpu: &CPU, args: &[&str]) -> Result<String, String> {
+        let mb = |bytes: usize| bytes as f64 / (1 << 20) as f64;
+        match args {
+            [] | ["on"] | ["start"] => {
+                if self.recording.is_some() {
+                    return Err("Already recording".to_string());
+                }
+                self.recording = Some(Recording::new(cpu, self.record_budget));
+                Ok(format!("Recording, with up to {:.0}MB for snapshots\n", mb(self.record_budget)))
+            }
+            ["stop"] | ["off"] => match self.recording.take() {
+                Some(_) => Ok("Stopped recording\n".to_string()),
+                None => Err("Not recording".to_string()),
+            },
+            ["budget", n] => {
+                let n = n.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(|| format!("Invalid size {}", n))?;
+                self.record_budget = n << 20;
+                if let Some(recording) = &mut self.recording {
+                    recording.budget = self.record_budget;
+                }
+                Ok(format!("Snapshots can use up to {}MB\n", n))
+            }
+            ["info"] => Ok(match &self.recording {
+                Some(r) => format!(
+                    "Recorded {} instructions back, with {:.1}MB of {:.0}MB used for snapshots\n",
+                    r.position - r.start(),
+                    mb(r.used()),
+                    mb(r.budget)
+                ),
+                None => "Not recording\n".to_string(),
+            }),
+            _ => Err("Expected record, record stop, record budget <MB> or record info".to_string()),
+        }
+    }
+
+    fn profile_command(&mut self, cpu: &mut CPU, args: &[&str]) -> Result<String, String> {
+        match args {
+            ["start"] | ["on"] => {
+                self.profile = None;
+                cpu.profiler = Some(Profiler::new());
+                Ok("Profiling\n".to_string())
+            }
+            ["stop"] | ["off"] => match cpu.profiler.take() {
+                Some(profiler) => {
+                    self.profile = Some(profiler);
+                    Ok("Stopped profiling\n".to_string())
+                }
+                None => Err("Not profiling".to_string()),
+            },
+            [] | ["report"] | ["report", _] | ["folded", _] => {
+                let profiler = cpu
+                    .profiler
+                    .as_ref()
+                    .or(self.profile.as_ref())
+                    .ok_or("Nothing has been profiled, start with profile start")?;
+                let (path, text) = match args {
+                    ["report", path] => (path, profiler.report(&self.symbols)),
+                    ["folded", path] => (path, profiler.folded(&self.symbols)),
+                    _ => return Ok(profiler.report(&self.symbols)),
+                };
+                std::fs::write(path, text).map_err(|e| format!("Unable to write {}: {}", path, e))?;
+                Ok(format!("Wrote {}\n", path))
+            }
+            _ => Err("Expected profile start, profile stop, profile report [file] or profile folded <file>".into()),
+        }
+    }
+
+    fn coverage_command(&mut self, cpu: &mut CPU, args: &[&str]) -> Result<String, String> {
+        // There is no MBC, so the ROM mapped is all that can be covered
+        let rom = &cpu.mem.buffer[..0x8000];
+        match args {
+            ["start"] | ["on"] => {
+                if cpu.coverage.is_some() {
+                    return Err("Already recording coverage".to_string());
+                }
+                cpu.coverage = Some(self.coverage.take().unwrap_or_else(|| Coverage::new(rom.len())));
+                Ok("Recording coverage\n".to_string())
+            }
+            ["stop"] | ["off"] => match cpu.coverage.take() {
+                Some(coverage) => {
+                    self.coverage = Some(coverage);
+                    Ok("Stopped recording coverage\n".to_string())
+                }
+                None => Err("Not recording coverage".to_string()),
+            },
+            ["load", path] => {
+                let loaded = Coverage::load(std::path::Path::new(path))?;
+                match cpu.coverage.as_mut().or(self.coverage.as_mut()) {
+                    Some(coverage) => coverage.merge(&loaded),
+                    None => self.coverage = Some(loaded),
+                }
+                Ok(format!("Merged coverage from {}\n", path))
+            }
+            [] | ["summary"] | ["save", _] | ["annotate", _] => {
+                let coverage = cpu
+                    .coverage
+                    .as_ref()
+                    .or(self.coverage.as_ref())
+                    .ok_or("No coverage recorded, start with coverage start")?;
+                match args {
+                    ["save", path] => {
+                        coverage.save(std::path::Path::new(path))?;
+                        Ok(format!("Saved coverage to {}\n", path))
+                    }
+                    ["annotate", path] => {
+                        let listing = coverage.listing(rom, 1, 0x0000, 0x8000, &self.symbols);
+                        std::fs::write(path, listing).map_err(|e| format!("Unable to write {}: {}", path, e))?;
+                        Ok(format!("Wrote {}\n", path))
+                    }
+                    _ => Ok(coverage.summary(rom, &self.symbols)),
+                }
+            }
+            _ => Err("Expected coverage start, stop, summary, save <file>, load <file> or annotate <file>".into()),
+        }
+    }
+
+    fn backtrace(&self, cpu: &CPU) -> String {
+        let mut out = format!("#0  {}\n", self.symbols.format_addr(cpu.reg.pc));
+        for (i, frame) in cpu.calls.frames.iter().rev().enumerate() {
+            let target = self.symbols.format_addr(frame.target);
+            if frame.interrupt {
+                let at = self.symbols.format_addr(frame.return_addr);
+                writeln!(out, "#{:<2} {}  interrupted by {}", i + 1, at, target).unwrap();
+            } else {
+                let at = self.symbols.format_addr(frame.caller);
+                writeln!(out, "#{:<2} {}  called {}", i + 1, at, target).unwrap();
+            }
+        }
+        out
+    }
+
+    fn symbol_command(&mut self, args: &[&str]) -> Result<String, String> {
+        match args {
+            [] => Ok(format!("{} symbols loaded\n", self.symbols.len())),
+            ["load", path] => {
+                let loaded = Symbols::load(std::path::Path::new(path))?;
+                let n = loaded.len();
+                self.symbols.extend(loaded);
+                Ok(format!("Loaded {} symbols from {}\n", n, path))
+            }
+            [name] => match self.symbols.lookup(name) {
+                Some(sym) => Ok(format!("{} = {:02X}:{:04X}\n", sym.name, sym.bank, sym.addr)),
+                None => {
+                    let addr = parse_addr(&self.symbols, Some(name))?;
+                    Ok(format!("{}\n", self.symbols.format_addr(addr)))
+                }
+            },
+            _ => Err("Expected sym load <file> or sym <name|addr>".to_string()),
+        }
+    }
+
+    fn breakpoint_command(&mut self, args: &[&str]) -> Result<String, String> {
+        let symbols = &self.symbols;
+        let find = |bps: &[Breakpoint], s: Option<&str>| -> Result<usize, String> {
+            let s = s.ok_or("Missing breakpoint")?;
+            // Small numbers are IDs, anything else is an address
+            let by_id = s.parse::<u32>().ok().and_then(|id| bps.iter().position(|bp| bp.id == id));
+            let by_addr = parse_addr(symbols, Some(s))
+                .ok()
+                .and_then(|addr| bps.iter().position(|bp| bp.addr == addr));
+            by_id
+                .or(by_addr)
+                .ok_or_else(|| format!("No breakpoint {}", s))
+        };
+
+        let sub = args.first().map(|s| s.to_lowercase());
+        match sub.as_deref() {
+            Some("add") => self.add_breakpoint(&args[1..], false),
+            Some("temp") | Some("tbreak") => self.add_breakpoint(&args[1..], true),
+            Some("rm") | Some("delete") | Some("del") => {
+                let i = find(&self.breakpoints, args.get(1).copied())?;
+                let bp = self.breakpoints.remove(i);
+                Ok(format!("Removed breakpoint {} at {}\n", bp.id, symbols.format_addr(bp.addr)))
+            }
+            Some("enable") | Some("disable") => {
+                let i = find(&self.breakpoints, args.get(1).copied())?;
+                let bp = &mut self.breakpoints[i];
+                bp.enabled = sub.as_deref() == Some("enable");
+                let state = if bp.enabled { "Enabled" } else { "Disabled" };
+                Ok(format!("{} breakpoint {} at {}\n", state, bp.id, symbols.format_addr(bp.addr)))
+            }
+            Some("cond") | Some("condition") => {
+                let i = find(&self.breakpoints, args.get(1).copied())?;
+                let bp = &mut self.breakpoints[i];
+                bp.condition = parse_condition(&args[2..])?;
+                match &bp.condition {
+                    Some((source, _)) => Ok(format!("Breakpoint {} stops when {}\n", bp.id, source)),
+                    None => Ok(format!("Breakpoint {} is unconditional\n", bp.id)),
+                }
+            }
+            Some("log") => {
+                let i = find(&self.breakpoints, args.get(1).copied())?;
+                let bp = &mut self.breakpoints[i];
+                let message = args[2..].join(" ");
+                if message.is_empty() {
+                    bp.log = None;
+                    Ok(format!("Breakpoint {} stops execution\n", bp.id))
+                } else {
+                    bp.log = Some(message);
+                    Ok(format!("Breakpoint {} logs instead of stopping\n", bp.id))
+                }
+            }
+            Some("ignore") => {
+                let i = find(&self.breakpoints, args.get(1).copied())?;
+                let n = parse_count(args.get(2).copied(), 1)?;
+                let bp = &mut self.breakpoints[i];
+                bp.ignore = n;
+                let at = symbols.format_addr(bp.addr);
+                Ok(format!("Ignoring the next {} hits of breakpoint {} at {}\n", n, bp.id, at))
+            }
+            Some("list") | Some("ls") | None => {
+                if self.breakpoints.is_empty() {
+                    return Ok("No breakpoints\n".to_string());
+                }
+                let mut out = String::new();
+                for bp in &self.breakpoints {
+                    write!(out, "{:>3}  {}  hits {}", bp.id, symbols.format_addr(bp.addr), bp.hits).unwrap();
+                    if bp.ignore > 0 {
+                        write!(out, ", ignoring next {}", bp.ignore).unwrap();
+                    }
+                    if let Some((source, _)) = &bp.condition {
+                        write!(out, ", if {}", source).unwrap();
+                    }
+                    if let Some(message) = &bp.log {
+                        write!(out, ", logs \"{}\"", message).unwrap();
+                    }
+                    if bp.temporary {
+                        out += ", temporary";
+                    }
+                    if !bp.enabled {
+                        out += ", disabled";
+                    }
+                    out += "\n";
+                }
+                Ok(out)
+            }
+            Some(_) => self.add_breakpoint(args, false),
+        }
+    }
+
+    fn watch_command(&mut self, cpu: &mut CPU, args: &[&str]) -> Result<String, String> {
+        let mut out = String::new();
+        match args.first().map(|s| s.to_lowercase()).as_deref() {
+            Some("rm") | Some("delete") | Some("del") => {
+                let id = parse_count(args.get(1).copied(), 0)?;
+                let wp = self.remove_watchpoint(cpu, id).ok_or_else(|| format!("No watchpoint {}", id))?;
+                writeln!(out, "Removed watchpoint {} on {}", wp.id, wp.range()).unwrap();
+            }
+            Some("list") | Some("ls") | None => {
+                if cpu.mem.watchpoints.is_empty() {
+                    out += "No watchpoints\n";
+                }
+                for wp in cpu.mem.watchpoints.iter() {
+                    let action = if wp.halt { "" } else { ", log only" };
+                    writeln!(out, "{:>3}  {:?} {}{}", wp.id, wp.kind, wp.range(), action).unwrap();
+                }
+            }
+            Some(first) => {
+                let (kind, rest) = match first {
+                    "read" | "r" => (WatchKind::Read, &args[1..]),
+                    "write" | "w" => (WatchKind::Write, &args[1..]),
+                    "change" | "c" => (WatchKind::Change, &args[1..]),
+                    "access" | "a" => (WatchKind::Access, &args[1..]),
+                    _ => (WatchKind::Write, args),
+                };
+                let range = rest.first().copied().ok_or("Missing address")?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (
+                        parse_addr(&self.symbols, Some(start))?,
+                        parse_addr(&self.symbols, Some(end))?,
+                    ),
+                    None => {
+                        let addr = parse_addr(&self.symbols, Some(range))?;
+                        (addr, addr)
+                    }
+                };
+                if end < start {
+                    return Err(format!("The range {} ends before it starts", range));
+                }
+                let halt = match rest.get(1).map(|s| s.to_lowercase()).as_deref() {
+                    None => true,
+                    Some("log") => false,
+                    Some(other) => return Err(format!("Unknown watchpoint action {}, expected log", other)),
+                };
+
+                let id = self.add_watchpoint(cpu, start, end, kind, halt);
+                let wp = cpu.mem.watchpoints.last().unwrap();
+                writeln!(out, "Watchpoint {} on {:?} of {}", id, wp.kind, wp.range()).unwrap();
+            }
+        }
+        Ok(out)
+    }
+
+    /// Watches `start` to `end` inclusive, returning the ID of the new watchpoint
+    pub fn add_watchpoint(&mut self, cpu: &mut CPU, start: u16, end: u16, kind: WatchKind, halt: bool) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        cpu.mem.watchpoints.push(Watchpoint {
+            id,
+            start,
+            end,
+            kind,
+            halt,
+            enabled: true,
+        });
+        self.watching = true;
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, cpu: &mut CPU, id: u32) -> Option<Watchpoint> {
+        let i = cpu.mem.watchpoints.iter().position(|wp| wp.id == id)?;
+        let wp = cpu.mem.watchpoints.remove(i);
+        self.watching = !cpu.mem.watchpoints.is_empty();
+        Some(wp)
+    }
+
+    /// Adds a breakpoint from arguments of the form `<addr> [if <cond>]`
+    fn add_breakpoint(&mut self, args: &[&str], temporary: bool) -> Result<String, String> {
+        let addr = parse_addr(&self.symbols, args.first().copied())?;
+        let condition = match args.get(1) {
+            None => None,
+            Some(word) if word.eq_ignore_ascii_case("if") => parse_condition(&args[2..])?,
+            Some(word) => return Err(format!("Unexpected {}, expected if <condition>", word)),
+        };
+        let id = self.add_breakpoint_at(addr, temporary, condition)?;
+        let kind = if temporary { "Temporary breakpoint" } else { "Breakpoint" };
+        Ok(format!("{} {} at {}\n", kind, id, self.symbols.format_addr(addr)))
+    }
+
+    /// Adds a breakpoint, returning its ID
+    pub fn add_breakpoint_at(
+        &mut self,
+        addr: u16,
+        temporary: bool,
+        condition: Option<(String, Expr)>,
+    ) -> Result<u32, String> {
+        if let Some(bp) = self.breakpoints.iter().find(|bp| bp.addr == addr) {
+            return Err(format!("Breakpoint {} is already at ${:04X}", bp.id, addr));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            addr,
+            enabled: true,
+            temporary,
+            hits: 0,
+            ignore: 0,
+            condition,
+            log: None,
+        });
+        Ok(id)
+    }
+
+    /// Removes the breakpoint at an address, returning whether there was one
+    pub fn remove_breakpoint_at(&mut self, addr: u16) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|bp| bp.addr != addr);
+        self.breakpoints.len() != len
+    }
+}
+
+/// The breakpoint that would stop execution at PC, ignoring hit counts
+fn breakpoint_at(breakpoints: &[Breakpoint], cpu: &CPU) -> Option<u32> {
+    if cpu.state.halted {
+        return None;
+    }
+    let bp = breakpoints.iter().find(|bp| bp.enabled && bp.addr == cpu.reg.pc && bp.log.is_none())?;
+    match &bp.condition {
+        Some((_, cond)) if cond.eval(cpu) == Ok(0) => None,
+        _ => Some(bp.id),
+    }
+}
+
+/// Describes an access that triggered a watchpoint, made while running the instruction at `pc`
+fn format_watch_hit(cpu: &CPU, symbols: &Symbols, hit: &WatchHit, pc: u16, halted: bool) -> String {
+    let access = match hit.access {
+        Access::Read => format!("read ${:02X} from ${:04X}", hit.new, hit.addr),
+        Access::Write => format!("write ${:02X} -> ${:02X} to ${:04X}", hit.old, hit.new, hit.addr),
+    };
+    let by = match hit.by {
+        Accessor::Dma => "DMA".to_string(),
+        Accessor::Cpu if halted => format!("interrupt at {}", symbols.format_addr(pc)),
+        Accessor::Cpu => {
+            let line = disassemble_at(|addr| cpu.mem.get_addr(addr), pc);
+            format!("{}: {}", symbols.format_addr(pc), line.text)
+        }
+    };
+    format!("Watchpoint {}: {} by {}", hit.id, access, by)
+}
+
+pub fn format_registers(cpu: &CPU) -> String {
+    let f = cpu.reg.af as u8;
+    let flag = |bit: u8, c: char| if f & (1 << bit) != 0 { c } else { '-' };
+    format!(
+        "AF=${:04X} BC=${:04X} DE=${:04X} HL=${:04X} SP=${:04X} PC=${:04X} Flags={}{}{}{} IME={}{}\n",
+        cpu.reg.af,
+        cpu.reg.bc,
+        cpu.reg.de,
+        cpu.reg.hl,
+        cpu.reg.sp,
+        cpu.reg.pc,
+        flag(7, 'Z'),
+        flag(6, 'N'),
+        flag(5, 'H'),
+        flag(4, 'C'),
+        cpu.reg.ie as u8,
+        if cpu.state.halted { " HALTED" } else { "" }
+    )
+}
+
+/// Sets a register by its upper case name
+pub fn set_register(cpu: &mut CPU, name: &str, value: u16) -> Result<(), String> {
+    let reg = match name {
+        "PC" => {
+            cpu.reg.pc = value;
+            return Ok(());
+        }
+        "A" => RegisterName::A,
+        "F" => RegisterName::F,
+        "B" => RegisterName::B,
+        "C" => RegisterName::C,
+        "D" => RegisterName::D,
+        "E" => RegisterName::E,
+        "H" => RegisterName::H,
+        "L" => RegisterName::L,
+        "AF" => RegisterName::AF,
+        "BC" => RegisterName::BC,
+        "DE" => RegisterName::DE,
+        "HL" => RegisterName::HL,
+        "SP" => RegisterName::SP,
+        _ => return Err(format!("No register named {}", name)),
+    };
+
+    if name.len() == 1 {
+        if value > 0xFF {
+            return Err(format!("${:X} does not fit in {}", value, name));
+        }
+        cpu.reg.write8(reg, value as u8);
+    } else {
+        cpu.reg.write16(reg, value);
+    }
+    // The low nibble of F is always zero
+    cpu.reg.af &= 0xFFF0;
+    Ok(())
+}
+
+fn dump_memory(cpu: &CPU, addr: u16, len: u16) -> String {
+    let mut out = String::new();
+    let end = addr as u32 + len as u32;
+    let mut row = addr as u32;
+    while row < end {
+        let bytes = (row..end.min(row + 16))
+            .map(|a| cpu.mem.get_addr(a as u16))
+            .collect::<Vec<u8>>();
+        let hex = bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let ascii = bytes
+            .iter()
+            .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+            .collect::<String>();
+        writeln!(out, "${:04X}: {:<47}  {}", row, hex, ascii).unwrap();
+        row += 16;
+    }
+    out
+}
+
+/// Disassembles `count` instructions, starting up to `before` instructions ahead of `addr` and marking PC
+fn disassemble(cpu: &CPU, symbols: &Symbols, addr: u16, count: u32, before: u16) -> String {
+    let read = |a: u16| cpu.mem.get_addr(a);
+    let start = back_up(&cpu.mem, addr, before);
+
+    let mut out = String::new();
+    let mut at = start;
+    for _ in 0..count {
+        let line = disassemble_at(read, at);
+        let marker = if line.addr == cpu.reg.pc { "=>" } else { "  " };
+        let bytes = line
+            .bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        if let Some(label) = symbols.at(line.addr) {
+            writeln!(out, "{}:", label).unwrap();
+        }
+        let comment = target_comment(symbols, 1, &line);
+        writeln!(out, "{} ${:04X}  {:<9} {}{}", marker, line.addr, bytes, line.text, comment).unwrap();
+        at = at.wrapping_add(line.bytes.len() as u16);
+    }
+    out
+}
+
+fn format_io(cpu: &CPU, reg: MemoryRegister) -> String {
+    let value = cpu.mem.get_register(reg);
+    format!("{:<5} ${:04X}  ${:02X}  %{:08b}\n", reg.name(), reg.to_addr(), value, value)
+}
+
+fn oam_command(cpu: &mut CPU, args: &[&str]) -> Result<String, String> {
+    match args {
+        [] => Ok(oam::listing(&cpu.mem)),
+        ["overlay"] | ["overlay", "on"] | ["overlay", "off"] => {
+            let on = args.get(1).map_or(!cpu.video.sprite_overlay, |arg| *arg == "on");
+            cpu.video.sprite_overlay = on;
+            Ok(format!("Sprite overlay {}\n", if on { "on" } else { "off" }))
+        }
+        [index] => match index.parse::<usize>() {
+            Ok(index) if index < 40 => Ok(oam::preview(&cpu.mem, index)),
+            _ => Err("Expected a sprite from 0 to 39".to_string()),
+        },
+        _ => Err("Expected oam [N] or oam overlay [on|off]".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn test_commands() {
+        let source = "
+main:
+    ld A,$12
+    call routine
+    ld B,A
+    .loop:
+        jr .loop
+routine:
+    inc A
+    ret
+";
+        let mut cpu = CPU::headless();
+        cpu.load_code(assemble(source).unwrap());
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+
+        let run = |dbg: &mut Debugger, cpu: &mut CPU, line: &str| {
+            let out = dbg.command(cpu, line);
+            while dbg.is_running() {
+                dbg.step(cpu);
+            }
+            out
+        };
+
+        run(&mut dbg, &mut cpu, "step 3");
+        assert_eq!(cpu.reg.pc, 0x0152);
+        run(&mut dbg, &mut cpu, "next");
+        assert_eq!((cpu.reg.pc, cpu.reg.af >> 8), (0x0155, 0x13));
+        run(&mut dbg, &mut cpu, "set PC 150");
+        run(&mut dbg, &mut cpu, "until 158");
+        assert_eq!(cpu.reg.pc, 0x0158);
+        assert_eq!(dbg.location(&cpu), "$0158: INC A");
+        run(&mut dbg, &mut cpu, "finish");
+        assert_eq!(cpu.reg.pc, 0x0155);
+        run(&mut dbg, &mut cpu, "step 2");
+        assert_eq!(cpu.reg.pc, 0x0156);
+
+        assert_eq!(run(&mut dbg, &mut cpu, "break add $150"), "Breakpoint 1 at $0150\n");
+        run(&mut dbg, &mut cpu, "b disable 1");
+        assert_eq!(run(&mut dbg, &mut cpu, "break list"), "  1  $0150  hits 0, disabled\n");
+        assert!(run(&mut dbg, &mut cpu, "break rm").starts_with("Error"));
+
+        run(&mut dbg, &mut cpu, "set BC $1234");
+        run(&mut dbg, &mut cpu, "set B $56");
+        assert_eq!(cpu.reg.bc, 0x5634);
+        run(&mut dbg, &mut cpu, "poke C000 AB CD");
+        assert!(run(&mut dbg, &mut cpu, "x C000 2").starts_with("$C000: AB CD"));
+        assert!(run(&mut dbg, &mut cpu, "io ly").starts_with("LY    $FF44"));
+        assert!(run(&mut dbg, &mut cpu, "disasm").contains("=> $0156  18 FE     JR $0156"));
+        assert!(run(&mut dbg, &mut cpu, "frobnicate").starts_with("Error: Unknown command"));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let source = "
+main:
+    ld A,0
+    .loop:
+        call routine
+        jr .loop
+routine:
+    inc A
+    ret
+";
+        let mut cpu = CPU::headless();
+        cpu.load_code(assemble(source).unwrap());
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+
+        // Runs a command, returning why execution stopped
+        let run = |dbg: &mut Debugger, cpu: &mut CPU, line: &str| {
+            dbg.command(cpu, line);
+            let mut reason = None;
+            while dbg.is_running() {
+                assert!(dbg.is_stepping(), "Free running with no breakpoints");
+                reason = dbg.step(cpu).1.or(reason);
+            }
+            reason.unwrap_or_default()
+        };
+
+        run(&mut dbg, &mut cpu, "break add $157");
+        assert_eq!(run(&mut dbg, &mut cpu, "continue"), "Breakpoint 1 hit once");
+        assert_eq!((cpu.reg.pc, cpu.reg.af >> 8), (0x0157, 0));
+        // Resuming runs the instruction at the breakpoint rather than stopping on it again
+        assert_eq!(run(&mut dbg, &mut cpu, "continue"), "Breakpoint 1 hit 2 times");
+        assert_eq!(cpu.reg.af >> 8, 1);
+
+        run(&mut dbg, &mut cpu, "break ignore 1 2");
+        assert_eq!(run(&mut dbg, &mut cpu, "continue"), "Breakpoint 1 hit 5 times");
+        assert_eq!(cpu.reg.af >> 8, 4);
+
+        run(&mut dbg, &mut cpu, "break disable 1");
+        assert!(!dbg.is_stepping());
+        run(&mut dbg, &mut cpu, "tbreak 158");
+        assert_eq!(run(&mut dbg, &mut cpu, "c"), "Temporary breakpoint 2 hit");
+        assert_eq!((cpu.reg.pc, cpu.reg.af >> 8), (0x0158, 5));
+        assert_eq!(dbg.breakpoints.len(), 1);
+
+        run(&mut dbg, &mut cpu, "break enable 1");
+        run(&mut dbg, &mut cpu, "break cond 1 A == 8 && [SP] == $55");
+        assert_eq!(run(&mut dbg, &mut cpu, "continue"), "Breakpoint 1 hit 6 times");
+        assert_eq!(cpu.reg.af >> 8, 8);
+
+        run(&mut dbg, &mut cpu, "break cond 1 A >= 8");
+        run(&mut dbg, &mut cpu, "break log 1 A is {A}");
+        run(&mut dbg, &mut cpu, "tbreak 158 if a == 10");
+        assert_eq!(run(&mut dbg, &mut cpu, "continue"), "Temporary breakpoint 3 hit");
+        assert_eq!(dbg.messages(), vec!["$0157: A is $9".to_string()]);
+        assert!(dbg.command(&mut cpu, "break list").contains("if A >= 8, logs \"A is {A}\""));
+        assert!(dbg.command(&mut cpu, "break cond 1 A ==").starts_with("Error: Invalid condition"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let source = "
+main:
+    ld HL,$C000
+    ld (HL),1
+    ld (HL),1
+    ld A,(HL)
+    ld (HL),2
+    ld A,$C0
+    ld ($FF46),A
+    .loop:
+        jr .loop
+";
+        let mut cpu = CPU::headless();
+        cpu.load_code(assemble(source).unwrap());
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+
+        let run = |dbg: &mut Debugger, cpu: &mut CPU, line: &str| {
+            dbg.command(cpu, line);
+            let mut reason = None;
+            while dbg.is_running() {
+                reason = dbg.step(cpu).1.or(reason);
+            }
+            reason.unwrap_or_default()
+        };
+
+        run(&mut dbg, &mut cpu, "watch change C000");
+        assert_eq!(
+            run(&mut dbg, &mut cpu, "continue"),
+            "Watchpoint 1: write $00 -> $01 to $C000 by $0153: LD [HL],$01"
+        );
+        // Writing the same value again doesn't count as a change
+        assert_eq!(
+            run(&mut dbg, &mut cpu, "continue"),
+            "Watchpoint 1: write $01 -> $02 to $C000 by $0158: LD [HL],$02"
+        );
+
+        // OAM DMA reads the watched range and writes OAM without going through the CPU.
+        // A byte is copied each M-cycle, so only those copied during the instruction that was running are logged
+        run(&mut dbg, &mut cpu, "watch rm 1");
+        run(&mut dbg, &mut cpu, "watch read C000-C09F");
+        run(&mut dbg, &mut cpu, "watch write FE00-FE9F log");
+        assert_eq!(
+            run(&mut dbg, &mut cpu, "continue"),
+            "Watchpoint 2: read $02 from $C000 by DMA"
+        );
+        let messages = dbg.messages();
+        assert!(!messages.is_empty() && messages.len() < 0xA0, "{:?}", messages);
+        assert_eq!(messages[0], "Watchpoint 3: write $00 -> $02 to $FE00 by DMA");
+        let out = run(&mut dbg, &mut cpu, "continue");
+        assert!(out.starts_with("Watchpoint 2: read") && !out.contains("$C000"), "{}", out);
+        assert!(dbg.command(&mut cpu, "watch list").contains("  3  Write $FE00-$FE9F, log only"));
+    }
+}
@@ -0,0 +1,379 @@
+use crate::cpu::isa::{Instruction, JpCond, RegisterName};
+use crate::cpu::mem::Memory;
+use crate::cpu::optable::{self, OpInfo, Operand};
+use crate::debug::coverage::Coverage;
+use crate::debug::symbols::{mapped_bank, Symbols};
+use crate::util::parse_hex;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+/// The size of a switchable ROM bank
+pub const BANK_SIZE: usize = 0x4000;
+
+/// A single disassembled instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct DisasmLine {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    /// The address an operand refers to, such as the target of a jump, for labelling it
+    pub target: Option<u16>,
+}
+
+/// Renders an instruction in RGBDS syntax, using the table entry it was decoded from
+pub fn format_op(info: &OpInfo, ins: &Instruction, addr: u16) -> String {
+    if let Instruction::IllInsn = ins {
+        return "ILLEGAL".to_string();
+    }
+
+    let operands = info
+        .operands
+        .iter()
+        .filter(|op| **op != Operand::None)
+        .map(|op| format_operand(*op, ins, addr.wrapping_add(info.length as u16)))
+        .collect::<Vec<String>>();
+
+    with_operands(info.mnemonic, &operands)
+}
+
+/// Renders a table entry with placeholders for its immediate operand, as `LD A,n8`
+pub fn format_template(info: &OpInfo) -> String {
+    let operands = info
+        .operands
+        .iter()
+        .filter(|op| **op != Operand::None)
+        .map(|op| match op {
+            Operand::Imm8 => "n8".to_string(),
+            Operand::SImm8 | Operand::Rel8 => "e8".to_string(),
+            Operand::Imm16 => "n16".to_string(),
+            Operand::Addr16 => "[n16]".to_string(),
+            Operand::HighImm8 => "[$FF00+n8]".to_string(),
+            Operand::SPRel8 => "SP+e8".to_string(),
+            _ => format_operand(*op, &info.template, 0),
+        })
+        .collect::<Vec<String>>();
+    with_operands(info.mnemonic, &operands)
+}
+
+fn with_operands(mnemonic: &str, operands: &[String]) -> String {
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operands.join(","))
+    }
+}
+
+/// Renders a single operand. `next` is the address of the following instruction, which JR offsets are relative to
+fn format_operand(op: Operand, ins: &Instruction, next: u16) -> String {
+    match op {
+        Operand::None => String::new(),
+        Operand::Reg(reg) => register_name(reg).to_string(),
+        Operand::HLInc => "[HL+]".to_string(),
+        Operand::HLDec => "[HL-]".to_string(),
+        Operand::Imm8 => format!("${:02X}", ins.immediate() as u8),
+        Operand::SImm8 => signed(ins.immediate() as i8),
+        Operand::Imm16 => format!("${:04X}", ins.immediate()),
+        Operand::Addr16 => format!("[${:04X}]", ins.immediate()),
+        Operand::HighImm8 => format!("[$FF{:02X}]", ins.immediate() as u8),
+        Operand::HighC => "[C]".to_string(),
+        Operand::SPRel8 => format!("SP{}", signed_offset(ins.immediate() as i8)),
+        Operand::Rel8 => {
+            let target = (next as i32 + ins.immediate() as i8 as i32) as u16;
+            format!("${:04X}", target)
+        }
+        Operand::Cond(cond) => cond_name(cond).to_string(),
+        Operand::Bit(bit) => bit.to_string(),
+        Operand::Vector(vec) => format!("${:02X}", vec),
+    }
+}
+
+pub fn register_name(reg: RegisterName) -> &'static str {
+    match reg {
+        RegisterName::A => "A",
+        RegisterName::F => "F",
+        RegisterName::AF => "AF",
+        RegisterName::B => "B",
+        RegisterName::C => "C",
+        RegisterName::BC => "BC",
+        RegisterName::D => "D",
+        RegisterName::E => "E",
+        RegisterName::DE => "DE",
+        RegisterName::H => "H",
+        RegisterName::L => "L",
+        RegisterName::HL => "HL",
+        RegisterName::BCRef => "[BC]",
+        RegisterName::DERef => "[DE]",
+        RegisterName::HLRef => "[HL]",
+        RegisterName::SP => "SP",
+    }
+}
+
+pub fn cond_name(cond: JpCond) -> &'static str {
+    match cond {
+        JpCond::NotZero => "NZ",
+        JpCond::Zero => "Z",
+        JpCond::NotCarry => "NC",
+        JpCond::Carry => "C",
+    }
+}
+
+fn signed(n: i8) -> String {
+    if n < 0 {
+        format!("-${:02X}", -(n as i16))
+    } else {
+        format!("${:02X}", n)
+    }
+}
+
+fn signed_offset(n: i8) -> String {
+    if n < 0 {
+        signed(n)
+    } else {
+        format!("+{}", signed(n))
+    }
+}
+
+/// Disassembles the instruction at `addr`, reading bytes through `read`
+pub fn disassemble_at<F: Fn(u16) -> u8>(read: F, addr: u16) -> DisasmLine {
+    let (ins, info) = optable::decode_at(&read, addr);
+    let bytes = (0..info.length as u16)
+        .map(|i| read(addr.wrapping_add(i)))
+        .collect::<Vec<u8>>();
+    // Illegal opcodes are emitted as data so the output can be reassembled
+    let text = match ins {
+        Instruction::IllInsn => format!("db ${:02X}", bytes[0]),
+        _ => format_op(info, &ins, addr),
+    };
+    let next = addr.wrapping_add(info.length as u16);
+    let target = info.operands.iter().find_map(|op| match op {
+        Operand::Imm16 | Operand::Addr16 => Some(ins.immediate()),
+        Operand::HighImm8 => Some(0xFF00 | ins.immediate() as u8 as u16),
+        Operand::Rel8 => Some((next as i32 + ins.immediate() as i8 as i32) as u16),
+        Operand::Vector(vec) => Some(*vec as u16),
+        _ => None,
+    });
+    DisasmLine {
+        addr,
+        bytes,
+        text,
+        target,
+    }
+}
+
+/// Disassembles every instruction starting in `start..end`
+pub fn disassemble_with<F: Fn(u16) -> u8>(read: F, start: u16, end: u16) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let mut addr = start as u32;
+    while addr < end as u32 {
+        let line = disassemble_at(&read, addr as u16);
+        addr += line.bytes.len() as u32;
+        lines.push(line);
+    }
+    lines
+}
+
+/// Disassembles every instruction starting in `start..end` of the address space
+pub fn disassemble_range(mem: &Memory, start: u16, end: u16) -> Vec<DisasmLine> {
+    disassemble_with(|addr| mem.get_addr(addr), start, end)
+}
+
+/// Finds where to start disassembling to show up to `count` instructions before `addr`.
+/// Instructions are variable length, so this looks for a starting point that decodes into a run ending exactly at `addr`
+pub fn back_up(mem: &Memory, addr: u16, count: u16) -> u16 {
+    for back in (1..=count * 3).rev() {
+        let start = addr.wrapping_sub(back);
+        let lines = disassemble_range(mem, start, addr);
+        let lands = lines
+            .last()
+            .is_some_and(|l| l.addr as u32 + l.bytes.len() as u32 == addr as u32);
+        if lands && lines.len() <= count as usize {
+            return start;
+        }
+    }
+    addr
+}
+
+/// Disassembles `start..end` of a ROM image, with `bank` mapped into $4000-$7FFF.
+/// Bank 0 is always mapped into $0000-$3FFF. Bytes past the end of the image read as $FF
+pub fn disassemble_rom(rom: &[u8], bank: usize, start: u16, end: u16) -> Vec<DisasmLine> {
+    disassemble_with(|addr| rom_byte(rom, bank, addr), start, end)
+}
+
+/// The offset into a ROM image of an address in $0000-$7FFF, with `bank` mapped into $4000-$7FFF
+pub fn rom_offset(bank: usize, addr: u16) -> usize {
+    match addr as usize {
+        a if a < BANK_SIZE => a,
+        a => bank * BANK_SIZE + (a - BANK_SIZE),
+    }
+}
+
+/// Reads a byte of a ROM image as `disassemble_rom` does
+pub fn rom_byte(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    rom.get(rom_offset(bank, addr)).copied().unwrap_or(0xFF)
+}
+
+/// Formats a line for display, as `BB:AAAA  XX XX XX  TEXT`
+pub fn format_line(bank: usize, line: &DisasmLine) -> String {
+    let bytes = line
+        .bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ");
+    format!("{:02X}:{:04X}  {:<9} {}", bank, line.addr, bytes, line.text)
+}
+
+/// Names the address an instruction refers to, as a comment to add after it
+pub fn target_comment(symbols: &Symbols, bank: u16, line: &DisasmLine) -> String {
+    let target = line.target.and_then(|t| {
+        let bank = if (0x4000..0x8000).contains(&t) { bank } else { mapped_bank(t) };
+        symbols.at_in(bank, t)
+    });
+    match target {
+        Some(name) => format!("  ; {}", name),
+        None => String::new(),
+    }
+}
+
+/// Entry point for `gbemu disasm [--coverage FILE] <rom> [bank] [start] [end]`
+/// The bank and addresses are hex, as in the output. Bank defaults to 1.
+/// Without a range, the whole bank is disassembled: $0000-$3FFF for bank 0, $4000-$7FFF otherwise.
+/// Labels are taken from the `.sym` or `.map` file alongside the ROM, if there is one.
+/// With a coverage file saved by `--coverage`, each line is marked with its coverage and data is shown as data
+pub fn cli(args: &[String]) {
+    let usage = "usage: gbemu disasm [--coverage FILE] <rom> [bank] [start] [end]\n\
+        The bank and addresses are hex, eg gbemu disasm game.gb 1F 4000 4100";
+    let (coverage, args) = match args {
+        [opt, file, rest @ ..] if opt == "--coverage" => match Coverage::load(Path::new(file)) {
+            Ok(coverage) => (Some(coverage), rest),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+        _ => (None, args),
+    };
+    let path = match args.first() {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", usage);
+            process::exit(1);
+        }
+    };
+    let rom = match fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let parse = |idx: usize, default: u16| match args.get(idx) {
+        Some(s) => parse_hex(s).unwrap_or_else(|| {
+            eprintln!("Invalid hex number {}\n{}", s, usage);
+            process::exit(1);
+        }),
+        None => default,
+    };
+    let bank = parse(1, 1) as usize;
+    let (default_start, default_end) = if bank == 0 {
+        (0x0000, 0x4000)
+    } else {
+        (0x4000, 0x8000)
+    };
+    let start = parse(2, default_start);
+    let end = parse(3, default_end);
+
+    if bank * BANK_SIZE >= rom.len().max(2 * BANK_SIZE) {
+        eprintln!("{} has no bank {}", path, bank);
+        process::exit(1);
+    }
+
+    let symbols = Symbols::for_rom(Path::new(path)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        Symbols::new()
+    });
+
+    if let Some(coverage) = coverage {
+        print!("{}", coverage.listing(&rom, bank, start, end, &symbols));
+        return;
+    }
+    for line in disassemble_rom(&rom, bank, start, end) {
+        let line_bank = if line.addr < BANK_SIZE as u16 { 0 } else { bank };
+        if let Some(label) = symbols.at_in(line_bank as u16, line.addr) {
+            println!("{}:", label);
+        }
+        println!("{}{}", format_line(line_bank, &line), target_comment(&symbols, bank as u16, &line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let code = [
+            0x18, 0xFE, 0x20, 0x05, 0xE0, 0x44, 0xF0, 0x0F, 0xE2, 0xF8, 0xFE, 0xE8, 0x05, 0xCB, 0x7C, 0xCB, 0x36, 0xD3,
+            0xFA, 0x34, 0x12,
+        ];
+        let lines = disassemble_with(|addr| code.get(addr as usize - 0x150).copied().unwrap_or(0), 0x150, 0x165);
+        let text = lines.iter().map(|l| l.text.as_str()).collect::<Vec<&str>>();
+        assert_eq!(
+            text,
+            [
+                "JR $0150",
+                "JR NZ,$0159",
+                "LDH [$FF44],A",
+                "LDH A,[$FF0F]",
+                "LDH [C],A",
+                "LD HL,SP-$02",
+                "ADD SP,$05",
+                "BIT 7,H",
+                "SWAP [HL]",
+                "db $D3",
+                "LD A,[$1234]",
+            ]
+        );
+        assert_eq!(lines[1].target, Some(0x159));
+        assert_eq!(lines[2].target, Some(0xFF44));
+        assert_eq!(format_line(1, &lines[10]), "01:0162  FA 34 12  LD A,[$1234]");
+
+        assert_eq!(format_template(&optable::BASE[0xE0]), "LDH [$FF00+n8],A");
+        assert_eq!(format_template(&optable::BASE[0xF8]), "LD HL,SP+e8");
+        assert_eq!(format_template(&optable::BASE[0x20]), "JR NZ,e8");
+        assert_eq!(format_template(&optable::CB[0x7C]), "BIT 7,H");
+    }
+
+    #[test]
+    fn test_rom_banks() {
+        // Four banks, each starting with LD A,<bank>
+        let mut rom = vec![0; 4 * BANK_SIZE];
+        for bank in 0..4 {
+            rom[bank * BANK_SIZE..][..2].copy_from_slice(&[0x3E, bank as u8]);
+        }
+        assert_eq!(rom_offset(2, 0x4000), 0x8000);
+        assert_eq!(rom_offset(2, 0x0100), 0x0100);
+        assert_eq!(disassemble_rom(&rom, 0, 0x0000, 0x0001)[0].text, "LD A,$00");
+        assert_eq!(disassemble_rom(&rom, 2, 0x0000, 0x0001)[0].text, "LD A,$00");
+        assert_eq!(disassemble_rom(&rom, 2, 0x4000, 0x4001)[0].text, "LD A,$02");
+        assert_eq!(disassemble_rom(&rom, 3, 0x4000, 0x4001)[0].text, "LD A,$03");
+        // Past the end of the image reads as $FF
+        assert_eq!(rom_byte(&rom, 4, 0x4000), 0xFF);
+        assert_eq!(disassemble_rom(&rom, 4, 0x4000, 0x4001)[0].text, "RST $38");
+    }
+
+    #[test]
+    fn test_back_up() {
+        // LD A,$01; LD HL,$C000; NOP; JP $C000
+        let mut mem = Memory::new();
+        for (i, b) in [0x3E, 0x01, 0x21, 0x00, 0xC0, 0x00, 0xC3, 0x00, 0xC0].iter().enumerate() {
+            mem.set_addr(0xC000 + i as u16, *b);
+        }
+        // Starting at $C001 also lands on $C006, but takes three instructions to
+        assert_eq!(back_up(&mem, 0xC006, 2), 0xC002);
+        assert_eq!(back_up(&mem, 0xC006, 3), 0xC000);
+        assert_eq!(back_up(&mem, 0xC002, 1), 0xC000);
+    }
+}
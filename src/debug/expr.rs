@@ -0,0 +1,357 @@
+//! Expressions over the machine state, used as breakpoint conditions and in breakpoint log messages
+
+use crate::cpu::isa::RegisterName;
+use crate::cpu::mem::MemoryRegister;
+use crate::cpu::CPU;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Var {
+    Reg(RegisterName),
+    Pc,
+    /// A flag, by its bit in F
+    Flag(u8),
+    Ime,
+    Cycles,
+    Io(MemoryRegister),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Expr {
+    Num(i64),
+    Var(Var),
+    /// A byte read from memory
+    Mem(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 24] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*", "/", "%", "+", "-", "<", ">", "&", "^", "|", "!", "~", "(",
+    ")", "[", "]",
+];
+
+impl BinaryOp {
+    /// The operator for a token and its precedence, higher binding tighter
+    fn from_token(op: &str) -> Option<(BinaryOp, u8)> {
+        Some(match op {
+            "*" => (BinaryOp::Mul, 10),
+            "/" => (BinaryOp::Div, 10),
+            "%" => (BinaryOp::Rem, 10),
+            "+" => (BinaryOp::Add, 9),
+            "-" => (BinaryOp::Sub, 9),
+            "<<" => (BinaryOp::Shl, 8),
+            ">>" => (BinaryOp::Shr, 8),
+            "<" => (BinaryOp::Lt, 7),
+            "<=" => (BinaryOp::Le, 7),
+            ">" => (BinaryOp::Gt, 7),
+            ">=" => (BinaryOp::Ge, 7),
+            "==" => (BinaryOp::Eq, 6),
+            "!=" => (BinaryOp::Ne, 6),
+            "&" => (BinaryOp::BitAnd, 5),
+            "^" => (BinaryOp::BitXor, 4),
+            "|" => (BinaryOp::BitOr, 3),
+            "&&" => (BinaryOp::And, 2),
+            "||" => (BinaryOp::Or, 1),
+            _ => return None,
+        })
+    }
+}
+
+impl Var {
+    /// Looks up a register, flag (`ZF`, `NF`, `HF`, `CF`), `IME`, `CYCLES` or I/O register by name, ignoring case
+    pub fn from_name(name: &str) -> Option<Var> {
+        let name = name.to_uppercase();
+        let reg = match name.as_str() {
+            "A" => RegisterName::A,
+            "F" => RegisterName::F,
+            "B" => RegisterName::B,
+            "C" => RegisterName::C,
+            "D" => RegisterName::D,
+            "E" => RegisterName::E,
+            "H" => RegisterName::H,
+            "L" => RegisterName::L,
+            "AF" => RegisterName::AF,
+            "BC" => RegisterName::BC,
+            "DE" => RegisterName::DE,
+            "HL" => RegisterName::HL,
+            "SP" => RegisterName::SP,
+            "PC" => return Some(Var::Pc),
+            "ZF" => return Some(Var::Flag(7)),
+            "NF" => return Some(Var::Flag(6)),
+            "HF" => return Some(Var::Flag(5)),
+            "CF" => return Some(Var::Flag(4)),
+            "IME" => return Some(Var::Ime),
+            "CYCLES" => return Some(Var::Cycles),
+            _ => return MemoryRegister::from_name(&name).map(Var::Io),
+        };
+        Some(Var::Reg(reg))
+    }
+
+    fn value(self, cpu: &CPU) -> i64 {
+        match self {
+            Var::Reg(reg) => match cpu.reg.read8(reg) {
+                Some(v) => v as i64,
+                None => cpu.reg.read16(reg).unwrap_or(0) as i64,
+            },
+            Var::Pc => cpu.reg.pc as i64,
+            Var::Flag(bit) => ((cpu.reg.af >> bit) & 1) as i64,
+            Var::Ime => cpu.reg.ie as i64,
+            Var::Cycles => cpu.state.cycles as i64,
+            Var::Io(reg) => cpu.mem.get_register(reg) as i64,
+        }
+    }
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    fn take_while(chars: &mut Peekable<Chars>, f: impl Fn(char) -> bool) -> String {
+        let mut s = String::new();
+        while let Some(c) = chars.next_if(|c| f(*c)) {
+            s.push(c);
+        }
+        s
+    }
+    fn number(digits: &str, radix: u32) -> Result<Token, String> {
+        i64::from_str_radix(digits, radix)
+            .map(Token::Num)
+            .map_err(|_| format!("Invalid number {}", digits))
+    }
+
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '$' {
+            chars.next();
+            tokens.push(number(&take_while(&mut chars, |c| c.is_ascii_hexdigit()), 16)?);
+        } else if c == '%' && matches!(tokens.last(), None | Some(Token::Op(_))) {
+            // `%` is binary where a value is expected, and the remainder operator otherwise
+            chars.next();
+            tokens.push(number(&take_while(&mut chars, |c| c == '0' || c == '1'), 2)?);
+        } else if c.is_ascii_digit() {
+            let digits = take_while(&mut chars, |c| c.is_ascii_alphanumeric());
+            match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+                Some(hex) => tokens.push(number(hex, 16)?),
+                None => tokens.push(number(&digits, 10)?),
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            tokens.push(Token::Name(take_while(&mut chars, |c| c.is_ascii_alphanumeric() || c == '_')));
+        } else {
+            let rest = chars.clone().collect::<String>();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("Unexpected {}", c))?;
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push(Token::Op(op));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            _ => Err(format!("Expected {}", op)),
+        }
+    }
+
+    /// Parses operators binding at least as tightly as `min`, by precedence climbing
+    fn binary(&mut self, min: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            match BinaryOp::from_token(op) {
+                Some((op, prec)) if prec >= min => {
+                    self.pos += 1;
+                    let rhs = self.binary(prec + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                }
+                _ => break,
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Name(name)) => Var::from_name(&name)
+                .map(Expr::Var)
+                .ok_or_else(|| format!("Unknown name {}", name)),
+            Some(Token::Op("(")) => {
+                let e = self.binary(0)?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Some(Token::Op("[")) => {
+                let e = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Mem(Box::new(e)))
+            }
+            Some(Token::Op(op)) => {
+                let op = match op {
+                    "-" => UnaryOp::Neg,
+                    "!" => UnaryOp::Not,
+                    "~" => UnaryOp::BitNot,
+                    _ => return Err(format!("Unexpected {}", op)),
+                };
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+/// Numbers are decimal, hex with `$` or `0x`, or binary with `%`, and `[addr]` reads a byte of memory.
+/// The operators are those of C, with comparisons and logical operators giving 1 or 0
+pub fn parse(src: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let e = parser.binary(0)?;
+    match parser.next() {
+        None => Ok(e),
+        Some(Token::Num(n)) => Err(format!("Unexpected {}", n)),
+        Some(Token::Name(s)) => Err(format!("Unexpected {}", s)),
+        Some(Token::Op(s)) => Err(format!("Unexpected {}", s)),
+    }
+}
+
+impl Expr {
+    pub fn eval(&self, cpu: &CPU) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Num(n) => *n,
+            Expr::Var(v) => v.value(cpu),
+            Expr::Mem(addr) => cpu.mem.get_addr(addr.eval(cpu)? as u16) as i64,
+            Expr::Unary(op, e) => {
+                let v = e.eval(cpu)?;
+                match op {
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::Not => (v == 0) as i64,
+                    UnaryOp::BitNot => !v,
+                }
+            }
+            // Short circuit, so `[HL] == 0 || ...` style conditions don't do more work than needed
+            Expr::Binary(BinaryOp::And, l, r) => (l.eval(cpu)? != 0 && r.eval(cpu)? != 0) as i64,
+            Expr::Binary(BinaryOp::Or, l, r) => (l.eval(cpu)? != 0 || r.eval(cpu)? != 0) as i64,
+            Expr::Binary(op, l, r) => {
+                let (l, r) = (l.eval(cpu)?, r.eval(cpu)?);
+                match op {
+                    BinaryOp::Mul => l.wrapping_mul(r),
+                    BinaryOp::Div => l.checked_div(r).ok_or("Division by zero")?,
+                    BinaryOp::Rem => l.checked_rem(r).ok_or("Division by zero")?,
+                    BinaryOp::Add => l.wrapping_add(r),
+                    BinaryOp::Sub => l.wrapping_sub(r),
+                    BinaryOp::Shl => l.wrapping_shl(r as u32),
+                    BinaryOp::Shr => l.wrapping_shr(r as u32),
+                    BinaryOp::Lt => (l < r) as i64,
+                    BinaryOp::Le => (l <= r) as i64,
+                    BinaryOp::Gt => (l > r) as i64,
+                    BinaryOp::Ge => (l >= r) as i64,
+                    BinaryOp::Eq => (l == r) as i64,
+                    BinaryOp::Ne => (l != r) as i64,
+                    BinaryOp::BitAnd => l & r,
+                    BinaryOp::BitXor => l ^ r,
+                    BinaryOp::BitOr => l | r,
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+/// Formats a log message, replacing each `{expr}` with the value of the expression in hex
+pub fn interpolate(message: &str, cpu: &CPU) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        out += &rest[..start];
+        let end = rest[start..].find('}').ok_or("Unclosed { in message")? + start;
+        let value = parse(&rest[start + 1..end])?.eval(cpu)?;
+        out += &format!("${:X}", value);
+        rest = &rest[end + 1..];
+    }
+    out += rest;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expressions() {
+        let mut cpu = CPU::headless();
+        cpu.skip_bootrom();
+        cpu.reg.hl = 0xC000;
+        cpu.mem.set_addr(0xC000, 0x42);
+        cpu.mem.set_register(MemoryRegister::LY, 144);
+
+        let eval = |src: &str| parse(src).and_then(|e| e.eval(&cpu));
+        assert_eq!(eval("A == 1 && ZF"), Ok(1));
+        assert_eq!(eval("[HL] == $42 && [$C000] == 0x42"), Ok(1));
+        assert_eq!(eval("ly == 144"), Ok(1));
+        assert_eq!(eval("1 + 2 * 3 - (4 >> 1)"), Ok(5));
+        assert_eq!(eval("%1010 % 4"), Ok(2));
+        assert_eq!(eval("CF || [HL + 1] / 0"), Ok(1));
+        assert_eq!(eval("SP - 2 == $FFFC && cycles >= 0"), Ok(1));
+        assert_eq!(eval("1 / 0"), Err("Division by zero".to_string()));
+        assert_eq!(eval("A =="), Err("Unexpected end of expression".to_string()));
+        assert_eq!(eval("[HL"), Err("Expected ]".to_string()));
+        assert_eq!(eval("Q"), Err("Unknown name Q".to_string()));
+        assert_eq!(interpolate("HL={HL}, [HL]={[HL]}", &cpu), Ok("HL=$C000, [HL]=$42".to_string()));
+    }
+}
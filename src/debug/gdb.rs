@@ -0,0 +1,502 @@
+//! A GDB remote serial protocol stub, so GDB compatible front ends can drive the emulator over TCP
+
+use crate::cpu::isa::RegisterName;
+use crate::cpu::CPU;
+use crate::debug::debugger::{Debugger, Reverse, RunMode};
+use crate::debug::watch::{Access, WatchKind};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// GDB has no SM83 target, so the registers are described to it: A to L as bytes, then SP and PC as words
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.sm83.core">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="f" bitsize="8"/>
+    <reg name="b" bitsize="8"/>
+    <reg name="c" bitsize="8"/>
+    <reg name="d" bitsize="8"/>
+    <reg name="e" bitsize="8"/>
+    <reg name="h" bitsize="8"/>
+    <reg name="l" bitsize="8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: [RegisterName; 8] = [
+    RegisterName::A,
+    RegisterName::F,
+    RegisterName::B,
+    RegisterName::C,
+    RegisterName::D,
+    RegisterName::E,
+    RegisterName::H,
+    RegisterName::L,
+];
+
+/// GDB's numbers for the stop signals reported
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// A watchpoint set by the client, and the ID of the debugger watchpoint behind it
+struct GdbWatch {
+    kind: WatchKind,
+    addr: u16,
+    len: u16,
+    id: u32,
+}
+
+/// Polled from the main loop, working through the same `Debugger` as the command line so breakpoints are shared
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    /// Bytes received that don't yet make up a whole packet
+    buffer: Vec<u8>,
+    /// Whether the client is waiting for a stop reply, after a continue or step
+    waiting: bool,
+    /// Whether execution was stopped by the client sending an interrupt
+    interrupted: bool,
+    watches: Vec<GdbWatch>,
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_num(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+/// Parses `addr,len`
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_num(addr)?, parse_num(len)?))
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+/// The registers in the order given by the target description
+fn read_registers(cpu: &CPU) -> Vec<u8> {
+    let mut bytes = REGISTERS
+        .iter()
+        .map(|r| cpu.reg.read8(*r).unwrap())
+        .collect::<Vec<u8>>();
+    bytes.extend_from_slice(&cpu.reg.sp.to_le_bytes());
+    bytes.extend_from_slice(&cpu.reg.pc.to_le_bytes());
+    bytes
+}
+
+/// Sets register `n` of the target description from its bytes, returning false if there is no such register
+fn write_register(cpu: &mut CPU, n: usize, bytes: &[u8]) -> bool {
+    match (n, bytes) {
+        (0..=7, [v]) => cpu.reg.write8(REGISTERS[n], *v),
+        (8, [lo, hi]) => cpu.reg.sp = u16::from_le_bytes([*lo, *hi]),
+        (9, [lo, hi]) => cpu.reg.pc = u16::from_le_bytes([*lo, *hi]),
+        _ => return false,
+    }
+    // The low nibble of F is always zero
+    cpu.reg.af &= 0xFFF0;
+    true
+}
+
+impl GdbStub {
+    /// Listens for a client on a local port
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            waiting: false,
+            interrupted: false,
+            watches: Vec::new(),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    /// Services the connection without blocking: accepts a client, handles any packets it has sent,
+    /// and sends a stop reply once a continue or step it asked for has stopped
+    pub fn poll(&mut self, cpu: &mut CPU, dbg: &mut Debugger) {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, addr)) if stream.set_nonblocking(true).is_ok() => {
+                    println!("GDB connected from {}", addr);
+                    self.client = Some(stream);
+                    self.buffer.clear();
+                    // GDB expects the target to be stopped when it attaches
+                    if dbg.is_running() {
+                        dbg.stop(cpu.reg.pc);
+                    }
+                }
+                _ => return,
+            }
+        }
+
+        if let Err(e) = self.receive(cpu, dbg) {
+            println!("GDB disconnected: {}", e);
+            self.client = None;
+            self.waiting = false;
+            return;
+        }
+
+        if self.waiting && !dbg.is_running() {
+            self.waiting = false;
+            let reply = self.stop_reply(dbg);
+            self.send(&reply);
+        }
+    }
+
+    fn receive(&mut self, cpu: &mut CPU, dbg: &mut Debugger) -> io::Result<()> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.client.as_mut().unwrap().read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while let Some(&first) = self.buffer.first() {
+            match first {
+                // An interrupt, sent outside of any packet
+                0x03 => {
+                    self.buffer.remove(0);
+                    if dbg.is_running() {
+                        dbg.stop(cpu.reg.pc);
+                        self.interrupted = true;
+                    }
+                }
+                b'$' => {
+                    // Wait for the checksum, which follows the #
+                    let end = match self.buffer.iter().position(|b| *b == b'#') {
+                        Some(end) if self.buffer.len() >= end + 3 => end,
+                        _ => break,
+                    };
+                    let packet = String::from_utf8_lossy(&self.buffer[1..end]).into_owned();
+                    let sum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+                        .ok()
+                        .and_then(|s| u8::from_str_radix(s, 16).ok());
+                    self.buffer.drain(..end + 3);
+
+                    if sum != Some(checksum(&packet)) {
+                        self.write_raw(b"-")?;
+                        continue;
+                    }
+                    self.write_raw(b"+")?;
+                    if let Some(reply) = self.handle(cpu, dbg, &packet) {
+                        self.write_raw(format!("${}#{:02x}", reply, checksum(&reply)).as_bytes())?;
+                    }
+                }
+                // Acknowledgements, and anything else between packets, are ignored
+                _ => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        let client = self.client.as_mut().unwrap();
+        // The socket is non-blocking, but replies are small enough that they are never expected to block
+        client.set_nonblocking(false)?;
+        let result = client.write_all(data);
+        client.set_nonblocking(true)?;
+        result
+    }
+
+    fn send(&mut self, reply: &str) {
+        let packet = format!("${}#{:02x}", reply, checksum(reply));
+        if let Err(e) = self.write_raw(packet.as_bytes()) {
+            println!("GDB disconnected: {}", e);
+            self.client = None;
+        }
+    }
+
+    /// Describes why execution stopped, as a stop reply packet
+    fn stop_reply(&mut self, dbg: &mut Debugger) -> String {
+        if std::mem::take(&mut self.interrupted) {
+            return format!("S{:02x}", SIGINT);
+        }
+        match dbg.last_watch.take() {
+            Some(hit) => {
+                let kind = self.watches.iter().find(|w| w.id == hit.id).map(|w| w.kind);
+                let name = match (kind, hit.access) {
+                    (Some(WatchKind::Access), _) => "awatch",
+                    (_, Access::Read) => "rwatch",
+                    (_, Access::Write) => "watch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, hit.addr)
+            }
+            None => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    /// Handles a packet, returning the reply. Continue and step are replied to once execution stops
+    fn handle(&mut self, cpu: &mut CPU, dbg: &mut Debugger, packet: &str) -> Option<String> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let ok = || Some("OK".to_string());
+        let error = || Some("E01".to_string());
+
+        match cmd {
+            "?" => Some(self.stop_reply(dbg)),
+            "g" => Some(hex_bytes(&read_registers(cpu))),
+            "G" => {
+                let bytes = match parse_hex_bytes(args) {
+                    Some(bytes) if bytes.len() == 12 => bytes,
+                    _ => return error(),
+                };
+                for n in 0..8 {
+                    write_register(cpu, n, &bytes[n..n + 1]);
+                }
+                write_register(cpu, 8, &bytes[8..10]);
+                write_register(cpu, 9, &bytes[10..12]);
+                dbg.edited(cpu);
+                ok()
+            }
+            "p" => {
+                let bytes = read_registers(cpu);
+                match usize::from_str_radix(args, 16) {
+                    Ok(n @ 0..=7) => Some(hex_bytes(&bytes[n..n + 1])),
+                    Ok(n @ 8..=9) => Some(hex_bytes(&bytes[8 + (n - 8) * 2..10 + (n - 8) * 2])),
+                    _ => error(),
+                }
+            }
+            "P" => {
+                let written = args.split_once('=').and_then(|(n, v)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    Some(write_register(cpu, n, &parse_hex_bytes(v)?))
+                });
+                if written == Some(true) {
+                    dbg.edited(cpu);
+                    ok()
+                } else {
+                    error()
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) => {
+                    let bytes = (0..len)
+                        .map(|i| cpu.mem.get_addr(addr.wrapping_add(i)))
+                        .collect::<Vec<u8>>();
+                    Some(hex_bytes(&bytes))
+                }
+                None => error(),
+            },
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let bytes = parse_hex_bytes(data).filter(|b| b.len() == len as usize)?;
+                    for (i, b) in bytes.into_iter().enumerate() {
+                        cpu.mem.set_addr(addr.wrapping_add(i as u16), b);
+                    }
+                    Some(())
+                });
+                if written.is_some() {
+                    dbg.edited(cpu);
+                }
+                written.map_or_else(error, |_| ok())
+            }
+            "c" | "s" => {
+                if let Some(addr) = parse_num(args) {
+                    cpu.reg.pc = addr;
+                }
+                dbg.mode = if cmd == "c" {
+                    RunMode::Running
+                } else {
+                    RunMode::Step(1)
+                };
+                self.waiting = true;
+                None
+            }
+            // Reverse continue and step, which GDB sends for reverse-continue and reverse-stepi once recording
+            "b" => {
+                let how = match args {
+                    "c" => Reverse::Continue,
+                    "s" => Reverse::Step(1),
+                    _ => return Some(String::new()),
+                };
+                match dbg.reverse(cpu, how) {
+                    Ok(_) if dbg.at_recording_start() => Some(format!("T{:02x}replaylog:begin;", SIGTRAP)),
+                    Ok(_) => Some(self.stop_reply(dbg)),
+                    Err(_) => error(),
+                }
+            }
+            "Z" | "z" => {
+                let (kind, range) = match args.split_once(',') {
+                    Some((kind, range)) => (kind, range),
+                    None => return error(),
+                };
+                let (addr, len) = match parse_range(range) {
+                    Some(range) => range,
+                    None => return error(),
+                };
+                let insert = cmd == "Z";
+                let watch = match kind {
+                    // Software and hardware breakpoints are the same thing here
+                    "0" | "1" => {
+                        if insert {
+                            // Inserting a breakpoint where there already is one is not an error to GDB
+                            let _ = dbg.add_breakpoint_at(addr, false, None);
+                        } else {
+                            dbg.remove_breakpoint_at(addr);
+                        }
+                        return ok();
+                    }
+                    "2" => WatchKind::Write,
+                    "3" => WatchKind::Read,
+                    "4" => WatchKind::Access,
+                    _ => return Some(String::new()),
+                };
+                if insert {
+                    let id = dbg.add_watchpoint(cpu, addr, addr.saturating_add(len.max(1) - 1), watch, true);
+                    self.watches.push(GdbWatch {
+                        kind: watch,
+                        addr,
+                        len,
+                        id,
+                    });
+                } else if let Some(i) = self
+                    .watches
+                    .iter()
+                    .position(|w| w.kind == watch && w.addr == addr && w.len == len)
+                {
+                    let w = self.watches.remove(i);
+                    dbg.remove_watchpoint(cpu, w.id);
+                }
+                ok()
+            }
+            "k" => {
+                dbg.quit = true;
+                None
+            }
+            "D" => {
+                dbg.mode = RunMode::Running;
+                self.waiting = false;
+                ok()
+            }
+            "H" | "T" => ok(),
+            "q" => Some(self.query(args)),
+            _ => Some(String::new()),
+        }
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=1000;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string()
+        } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let (offset, len) = match range.split_once(',') {
+                Some((o, l)) => (usize::from_str_radix(o, 16).unwrap_or(0), usize::from_str_radix(l, 16).unwrap_or(0)),
+                None => return "E01".to_string(),
+            };
+            let chunk = TARGET_XML.get(offset..).unwrap_or("");
+            if chunk.len() > len {
+                format!("m{}", &chunk[..len])
+            } else {
+                format!("l{}", chunk)
+            }
+        } else {
+            match query {
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use std::time::Duration;
+
+    /// Sends a packet and polls the stub until it replies
+    fn exchange(stub: &mut GdbStub, cpu: &mut CPU, dbg: &mut Debugger, client: &mut TcpStream, packet: &str) -> String {
+        write!(client, "${}#{:02x}", packet, checksum(packet)).unwrap();
+        let mut reply = Vec::new();
+        let mut buf = [0; 256];
+        loop {
+            stub.poll(cpu, dbg);
+            while dbg.is_running() {
+                dbg.step(cpu);
+            }
+            stub.poll(cpu, dbg);
+            if let Ok(n) = client.read(&mut buf) {
+                reply.extend_from_slice(&buf[..n]);
+            }
+            let text = String::from_utf8_lossy(&reply).into_owned();
+            if let Some(start) = text.find('$') {
+                if let Some(end) = text[start..].find('#') {
+                    assert!(text.starts_with('+'));
+                    return text[start + 1..start + end].to_string();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let source = "
+main:
+    ld HL,$C000
+    ld (HL),1
+    .loop:
+        jr .loop
+";
+        let mut cpu = CPU::headless();
+        cpu.load_code(assemble(source).unwrap());
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+
+        let mut stub = GdbStub::listen(0).unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", stub.port())).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let mut send = |cpu: &mut CPU, dbg: &mut Debugger, packet: &str| {
+            exchange(&mut stub, cpu, dbg, &mut client, packet)
+        };
+
+        assert!(send(&mut cpu, &mut dbg, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(send(&mut cpu, &mut dbg, "qXfer:features:read:target.xml:0,ffff").starts_with("l<?xml"));
+        assert_eq!(send(&mut cpu, &mut dbg, "g"), "01b0001300d8014dfeff0001");
+        assert_eq!(send(&mut cpu, &mut dbg, "p9"), "0001");
+
+        assert_eq!(send(&mut cpu, &mut dbg, "Z0,150,1"), "OK");
+        assert_eq!(send(&mut cpu, &mut dbg, "c"), "S05");
+        assert_eq!(cpu.reg.pc, 0x0150);
+        assert_eq!(send(&mut cpu, &mut dbg, "z0,150,1"), "OK");
+        assert_eq!(send(&mut cpu, &mut dbg, "s"), "S05");
+        assert_eq!(cpu.reg.hl, 0xC000);
+
+        assert_eq!(send(&mut cpu, &mut dbg, "Z2,c000,1"), "OK");
+        assert_eq!(send(&mut cpu, &mut dbg, "c"), "T05watch:c000;");
+        assert_eq!(send(&mut cpu, &mut dbg, "mc000,2"), "0100");
+        assert_eq!(send(&mut cpu, &mut dbg, "Mc000,2:abcd"), "OK");
+        assert_eq!(cpu.mem.get_addr(0xC001), 0xCD);
+        assert_eq!(send(&mut cpu, &mut dbg, "P0=42"), "OK");
+        assert_eq!(cpu.reg.af >> 8, 0x42);
+        assert_eq!(send(&mut cpu, &mut dbg, "vCont?"), "");
+    }
+}
@@ -0,0 +1,244 @@
+use crate::cpu::mem::{Memory, MemoryRegister};
+use crate::peripherals::video::oam::{dropped_lines, sprite_height, sprites, Sprite, OAM_START, SPRITE_COUNT};
+use crate::peripherals::video::{shades, GbColor};
+use crate::util::color_to_sdl;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::WindowCanvas;
+use sdl2::VideoSubsystem;
+use std::fmt::Write;
+
+/// Sprites across the OAM viewer
+const SPRITES_WIDE: usize = 8;
+/// The space each sprite is shown in, with a border around it
+const CELL_WIDTH: usize = 12;
+const CELL_HEIGHT: usize = 20;
+const SCALE: u32 = 4;
+/// Where transparent pixels are seen through to
+const TRANSPARENT_COLOR: Color = Color::MAGENTA;
+const DROPPED_COLOR: Color = Color::RED;
+
+const HEADER: &str = " #  Addr    Y   X  Tile Attr  Pal  Flip Priority Bank CGB   Position";
+
+/// The shades a sprite is drawn with, from OBP0 or OBP1
+fn palette(mem: &Memory, sprite: &Sprite) -> [GbColor; 4] {
+    if sprite.obp1() {
+        shades(mem.get_register(MemoryRegister::OBP1))
+    } else {
+        shades(mem.get_register(MemoryRegister::OBP0))
+    }
+}
+
+/// Describes an OAM entry in a line, noting whether it is off screen or dropped from any lines
+pub fn describe(sprite: &Sprite, height: u8, dropped: u8) -> String {
+    let flip = match (sprite.flip_x(), sprite.flip_y()) {
+        (false, false) => "--",
+        (true, false) => "X-",
+        (false, true) => "-Y",
+        (true, true) => "XY",
+    };
+    let mut text = format!(
+        "{:>2}  ${:04X}  {:>3} {:>3}  ${:02X}  ${:02X}  {}  {}   {:<8}  {}    {}  {:>4},{:<4}",
+        sprite.index,
+        OAM_START + 4 * sprite.index as u16,
+        sprite.y,
+        sprite.x,
+        sprite.tile,
+        sprite.attrs,
+        if sprite.obp1() { "OBP1" } else { "OBP0" },
+        flip,
+        if sprite.behind_bg() { "behind" } else { "above" },
+        sprite.cgb_bank(),
+        sprite.cgb_palette(),
+        sprite.screen_x(),
+        sprite.screen_y(),
+    );
+    if !sprite.on_screen(height) {
+        text += " off screen";
+    } else if dropped > 0 {
+        write!(text, " dropped from {} line{}", dropped, if dropped == 1 { "" } else { "s" }).unwrap();
+    }
+    text.trim_end().to_string()
+}
+
+/// Lists all 40 OAM entries
+pub fn listing(mem: &Memory) -> String {
+    let height = sprite_height(mem);
+    let sprites = sprites(mem);
+    let dropped = dropped_lines(&sprites, height);
+    let mut out = format!("8x{} sprites\n{}\n", height, HEADER);
+    for sprite in sprites.iter() {
+        writeln!(out, "{}", describe(sprite, height, dropped[sprite.index])).unwrap();
+    }
+    out
+}
+
+/// Describes one OAM entry with its pixels drawn as colour numbers, transparent ones as dots
+pub fn preview(mem: &Memory, index: usize) -> String {
+    let height = sprite_height(mem);
+    let sprites = sprites(mem);
+    let dropped = dropped_lines(&sprites, height);
+    let sprite = &sprites[index];
+    let mut out = format!("{}\n{}\n", HEADER, describe(sprite, height, dropped[index]));
+    for (i, c) in render_sprite(mem, sprite, height).iter().enumerate() {
+        out.push(if *c == 0 { '.' } else { (b'0' + c) as char });
+        if i % 8 == 7 {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Renders a sprite as it is drawn, flipped, as colour numbers
+pub fn render_sprite(mem: &Memory, sprite: &Sprite, height: u8) -> Vec<u8> {
+    (0..8 * height as usize)
+        .map(|i| sprite.pixel(mem, height, (i % 8) as u8, (i / 8) as u8))
+        .collect()
+}
+
+/// A window showing each sprite in OAM as it is drawn, framed in red if dropped from any lines by the limit of
+/// sprites per line. Hovering over a sprite shows its entry in the title
+pub struct OamViewer {
+    canvas: WindowCanvas,
+    /// Set once the window is closed
+    pub closed: bool,
+    /// The sprite under the mouse
+    hover: Option<usize>,
+}
+
+impl OamViewer {
+    pub fn open(video: &VideoSubsystem) -> Result<OamViewer, String> {
+        let (width, height) = (SPRITES_WIDE * CELL_WIDTH, SPRITE_COUNT / SPRITES_WIDE * CELL_HEIGHT);
+        let window = video
+            .window("gbemu oam", width as u32 * SCALE, height as u32 * SCALE)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        canvas.set_scale(SCALE as f32, SCALE as f32)?;
+        Ok(OamViewer {
+            canvas,
+            closed: false,
+            hover: None,
+        })
+    }
+
+    /// Handles an event if it is for the viewer's window, returning whether it was
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        let id = self.canvas.window().id();
+        match *event {
+            Event::Window {
+                window_id,
+                win_event: WindowEvent::Close,
+                ..
+            } if window_id == id => self.closed = true,
+            Event::Window {
+                window_id,
+                win_event: WindowEvent::Leave,
+                ..
+            } if window_id == id => self.hover = None,
+            Event::MouseMotion { window_id, x, y, .. } if window_id == id => {
+                let (x, y) = (x.max(0) as usize / SCALE as usize, y.max(0) as usize / SCALE as usize);
+                let (col, row) = (x / CELL_WIDTH, y / CELL_HEIGHT);
+                let index = row * SPRITES_WIDE + col;
+                self.hover = if col < SPRITES_WIDE && index < SPRITE_COUNT { Some(index) } else { None };
+            }
+            Event::KeyDown {
+                window_id,
+                keycode: Some(Keycode::Escape),
+                ..
+            } if window_id == id => self.closed = true,
+            Event::KeyDown { window_id, .. } | Event::KeyUp { window_id, .. } if window_id == id => {}
+            _ => return false,
+        }
+        true
+    }
+
+    /// Redraws the window from the current contents of OAM and VRAM
+    pub fn update(&mut self, mem: &Memory) {
+        let height = sprite_height(mem);
+        let sprites = sprites(mem);
+        let dropped = dropped_lines(&sprites, height);
+
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        for sprite in sprites.iter() {
+            let left = (sprite.index % SPRITES_WIDE * CELL_WIDTH + 2) as i32;
+            let top = (sprite.index / SPRITES_WIDE * CELL_HEIGHT + 2) as i32;
+            if dropped[sprite.index] > 0 {
+                self.canvas.set_draw_color(DROPPED_COLOR);
+                self.canvas.draw_rect(Rect::new(left - 1, top - 1, 10, height as u32 + 2)).unwrap();
+            }
+            self.canvas.set_draw_color(TRANSPARENT_COLOR);
+            self.canvas.fill_rect(Rect::new(left, top, 8, height as u32)).unwrap();
+
+            let mut points = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+            for (i, c) in render_sprite(mem, sprite, height).iter().enumerate() {
+                points[*c as usize].push(Point::new(left + (i % 8) as i32, top + (i / 8) as i32));
+            }
+            // Colour 0 is transparent
+            for (shade, points) in palette(mem, sprite).iter().zip(points.iter()).skip(1) {
+                self.canvas.set_draw_color(color_to_sdl(*shade));
+                self.canvas.draw_points(&points[..]).unwrap();
+            }
+        }
+        self.canvas.present();
+
+        let mut title = format!("gbemu oam - 8x{}", height);
+        if let Some(index) = self.hover {
+            write!(title, " - {}", describe(&sprites[index], height, dropped[index])).unwrap();
+        }
+        // Only a changed title is set, as setting one can be slow
+        if self.canvas.window().title() != title {
+            self.canvas.window_mut().set_title(&title).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::debug::debugger::Debugger;
+
+    #[test]
+    fn test_oam_inspector() {
+        let mut cpu = CPU::headless();
+        let mem = &mut cpu.mem;
+        // Eleven sprites on lines 0-7, so the last is dropped, and one flipped sprite using OBP1 behind the background
+        for i in 0..11 {
+            mem.set_addr(OAM_START + 4 * i, 16);
+            mem.set_addr(OAM_START + 4 * i + 1, 8 + 8 * i as u8);
+        }
+        mem.set_addr(OAM_START + 4 * 11, 40);
+        mem.set_addr(OAM_START + 4 * 11 + 1, 30);
+        mem.set_addr(OAM_START + 4 * 11 + 2, 1);
+        mem.set_addr(OAM_START + 4 * 11 + 3, 0b1011_0000);
+        // Tile 1 has its top row coloured 1 on the left and 2 on the right
+        mem.set_addr(0x8010, 0xF0);
+        mem.set_addr(0x8011, 0x0F);
+
+        let out = listing(mem);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2 + SPRITE_COUNT);
+        assert_eq!(lines[0], "8x8 sprites");
+        assert!(lines[2].starts_with(" 0  $FE00   16   8  $00  $00  OBP0  --   above"), "{}", lines[2]);
+        assert!(lines[2].ends_with("0,0"), "{}", lines[2]);
+        assert!(lines[12].ends_with("dropped from 8 lines"), "{}", lines[12]);
+        assert!(lines[13].contains("$01  $B0  OBP1  X-   behind"), "{}", lines[13]);
+        assert!(lines[13].ends_with("22,24"), "{}", lines[13]);
+        assert!(lines[14].ends_with("off screen"), "{}", lines[14]);
+
+        // Flipped in X, the colour 2 half is on the left
+        let sprite = sprites(mem)[11];
+        assert_eq!(render_sprite(mem, &sprite, 8)[..8], [2, 2, 2, 2, 1, 1, 1, 1]);
+
+        let mut dbg = Debugger::new();
+        let out = dbg.command(&mut cpu, "oam 11");
+        assert!(out.contains("\n22221111\n........\n"), "{}", out);
+        dbg.command(&mut cpu, "oam overlay on");
+        assert!(cpu.video.sprite_overlay);
+        assert!(dbg.command(&mut cpu, "oam 40").contains("0 to 39"));
+    }
+}
@@ -0,0 +1,350 @@
+use crate::cpu::calls::CallStack;
+use crate::cpu::int::{JOYPAD, LCDSTAT, SERIAL, TIMER, VBLANK};
+use crate::cpu::optable::{BASE, CB};
+use crate::cpu::CYCLES_PER_FRAME;
+use crate::debug::disasm::format_template;
+use crate::debug::symbols::Symbols;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// How many of the busiest addresses the report lists
+const TOP_ADDRESSES: usize = 50;
+/// The interrupt vectors, with the names handlers are given when there is no label for them
+const INTERRUPTS: [(u16, &str); 5] = [
+    (VBLANK, "VBlank"),
+    (LCDSTAT, "LCD STAT"),
+    (TIMER, "Timer"),
+    (SERIAL, "Serial"),
+    (JOYPAD, "Joypad"),
+];
+
+/// A routine on the call stack, as the address it was called at and whether it was an interrupt
+type Node = (u16, bool);
+
+#[derive(Copy, Clone, Default)]
+struct Count {
+    count: u64,
+    cycles: u64,
+}
+
+/// A call stack seen while profiling, with the time spent with it as the current stack
+struct Stack {
+    nodes: Vec<Node>,
+    cycles: u64,
+    halted: u64,
+}
+
+/// Accumulates where execution time goes: per address, per opcode, and per call stack using the shadow call
+/// stack, from which the time in each routine and interrupt handler is worked out.
+/// Instructions are charged to the routine they are executed in, so a call is charged to the caller and its
+/// return to the routine returning. Symbols are only needed to name things in the reports
+pub struct Profiler {
+    by_pc: Vec<Count>,
+    /// CB prefixed opcodes follow the 256 others
+    by_opcode: Vec<Count>,
+    stacks: Vec<Stack>,
+    stack_ids: HashMap<Vec<Node>, usize>,
+    /// The index of the current call stack in `stacks`
+    current: usize,
+    /// The times each routine was called or interrupt handled
+    entries: HashMap<Node, u64>,
+    instructions: u64,
+    cycles: u64,
+    halted: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+fn interrupt_name(vector: u16) -> Option<&'static str> {
+    INTERRUPTS.iter().find(|(v, _)| *v == vector).map(|(_, name)| *name)
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+/// Names a routine by its label, or by its address if there is none
+fn node_name(symbols: &Symbols, (addr, interrupt): Node) -> String {
+    match symbols.at(addr) {
+        Some(label) => label.to_string(),
+        None => match interrupt_name(addr).filter(|_| interrupt) {
+            Some(name) => format!("({})", name),
+            None => format!("${:04X}", addr),
+        },
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        let mut stack_ids = HashMap::new();
+        stack_ids.insert(Vec::new(), 0);
+        Profiler {
+            by_pc: vec![Count::default(); 0x10000],
+            by_opcode: vec![Count::default(); 0x200],
+            stacks: vec![Stack {
+                nodes: Vec::new(),
+                cycles: 0,
+                halted: 0,
+            }],
+            stack_ids,
+            current: 0,
+            entries: HashMap::new(),
+            instructions: 0,
+            cycles: 0,
+            halted: 0,
+        }
+    }
+
+    /// Catches up with the call stack, which should be done before an instruction executes,
+    /// so that calls and returns are charged to the routine they are made from
+    pub fn enter(&mut self, calls: &CallStack) {
+        let nodes = calls.frames.iter().map(|f| (f.target, f.interrupt));
+        let known = &self.stacks[self.current].nodes;
+        if nodes.clone().eq(known.iter().copied()) {
+            return;
+        }
+
+        let common = nodes.clone().zip(known).take_while(|(node, known)| node == *known).count();
+        for node in nodes.clone().skip(common) {
+            *self.entries.entry(node).or_insert(0) += 1;
+        }
+        let nodes = nodes.collect::<Vec<Node>>();
+        self.current = match self.stack_ids.get(&nodes) {
+            Some(id) => *id,
+            None => {
+                let id = self.stacks.len();
+                self.stack_ids.insert(nodes.clone(), id);
+                self.stacks.push(Stack {
+                    nodes,
+                    cycles: 0,
+                    halted: 0,
+                });
+                id
+            }
+        };
+    }
+
+    /// Records an instruction at `pc` taking `cycles`. CB prefixed opcodes are given as $100 plus the second byte
+    pub fn instruction(&mut self, pc: u16, opcode: usize, cycles: u32) {
+        let cycles = cycles as u64;
+        let at = &mut self.by_pc[pc as usize];
+        at.count += 1;
+        at.cycles += cycles;
+        let op = &mut self.by_opcode[opcode];
+        op.count += 1;
+        op.cycles += cycles;
+        self.stacks[self.current].cycles += cycles;
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+
+    /// Records the cycles taken to dispatch an interrupt, charged to its handler
+    pub fn interrupt(&mut self, calls: &CallStack, cycles: u32) {
+        self.enter(calls);
+        self.stacks[self.current].cycles += cycles as u64;
+        self.cycles += cycles as u64;
+    }
+
+    /// Records cycles spent halted, waiting for an interrupt
+    pub fn halt(&mut self, calls: &CallStack, cycles: u32) {
+        self.enter(calls);
+        self.stacks[self.current].halted += cycles as u64;
+        self.halted += cycles as u64;
+        self.cycles += cycles as u64;
+    }
+
+    /// The self and total cycles of each routine, along with the root as None
+    fn routines(&self) -> HashMap<Option<Node>, (u64, u64)> {
+        let mut routines = HashMap::new();
+        for stack in &self.stacks {
+            let all = stack.cycles + stack.halted;
+            routines.entry(stack.nodes.last().copied()).or_insert((0, 0)).0 += stack.cycles;
+            routines.entry(None).or_insert((0, 0)).1 += all;
+            // A recursive routine appears more than once, but its total only counts the time once
+            for (i, node) in stack.nodes.iter().enumerate() {
+                if !stack.nodes[..i].contains(node) {
+                    routines.entry(Some(*node)).or_insert((0, 0)).1 += all;
+                }
+            }
+        }
+        routines
+    }
+
+    /// A report of where time went, each section sorted busiest first
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let total = self.cycles;
+        let mut out = format!(
+            "Profiled {} cycles ({:.1} frames) and {} instructions\nHalted for {} cycles ({:.1}%)\n",
+            total,
+            total as f64 / CYCLES_PER_FRAME as f64,
+            self.instructions,
+            self.halted,
+            percent(self.halted, total)
+        );
+
+        // The innermost interrupt on a stack is the handler running
+        let mut handlers = [0; INTERRUPTS.len()];
+        for stack in &self.stacks {
+            let vector = stack.nodes.iter().rev().find(|(_, interrupt)| *interrupt).map(|(addr, _)| *addr);
+            if let Some(i) = INTERRUPTS.iter().position(|(v, _)| Some(*v) == vector) {
+                handlers[i] += stack.cycles + stack.halted;
+            }
+        }
+        out += "\nInterrupts:\n     Count      Cycles       %  Handler\n";
+        for (i, (vector, name)) in INTERRUPTS.iter().enumerate() {
+            let count = self.entries.get(&(*vector, true)).copied().unwrap_or(0);
+            if count > 0 {
+                let cycles = handlers[i];
+                writeln!(out, "{:>10}  {:>10}  {:>5.1}%  {}", count, cycles, percent(cycles, total), name).unwrap();
+            }
+        }
+
+        let mut routines = self.routines().into_iter().collect::<Vec<_>>();
+        routines.sort_by_key(|(node, (own, all))| (Reverse(*all), Reverse(*own), *node));
+        out += "\nRoutines:\n      Self       %       Total       %     Calls  Routine\n";
+        for (node, (own, all)) in routines {
+            let (calls, name) = match node {
+                Some(node) => (self.entries.get(&node).copied().unwrap_or(0), node_name(symbols, node)),
+                None => (0, "(top level)".to_string()),
+            };
+            writeln!(
+                out,
+                "{:>10}  {:>5.1}%  {:>10}  {:>5.1}%  {:>8}  {}",
+                own,
+                percent(own, total),
+                all,
+                percent(all, total),
+                calls,
+                name
+            )
+            .unwrap();
+        }
+
+        // Time by label regardless of how the code was reached, which catches code jumped to rather than called
+        if !symbols.is_empty() {
+            let mut labels = HashMap::new();
+            for (pc, at) in self.by_pc.iter().enumerate().filter(|(_, at)| at.count > 0) {
+                let label = symbols.nearest(pc as u16).map(|(name, _)| name.split('.').next().unwrap());
+                *labels.entry(label).or_insert(0) += at.cycles;
+            }
+            let mut labels = labels.into_iter().collect::<Vec<_>>();
+            labels.sort_by_key(|(label, cycles)| (Reverse(*cycles), *label));
+            out += "\nLabels:\n    Cycles       %  Label\n";
+            for (label, cycles) in labels {
+                let label = label.unwrap_or("(unlabelled)");
+                writeln!(out, "{:>10}  {:>5.1}%  {}", cycles, percent(cycles, total), label).unwrap();
+            }
+        }
+
+        let mut addresses = self.by_pc.iter().enumerate().filter(|(_, at)| at.count > 0).collect::<Vec<_>>();
+        addresses.sort_by_key(|(_, at)| Reverse(at.cycles));
+        out += "\nAddresses:\n    Cycles       %       Count  Address\n";
+        for (pc, at) in addresses.into_iter().take(TOP_ADDRESSES) {
+            writeln!(
+                out,
+                "{:>10}  {:>5.1}%  {:>10}  {}",
+                at.cycles,
+                percent(at.cycles, total),
+                at.count,
+                symbols.format_addr(pc as u16)
+            )
+            .unwrap();
+        }
+
+        let mut opcodes = self.by_opcode.iter().enumerate().filter(|(_, op)| op.count > 0).collect::<Vec<_>>();
+        opcodes.sort_by_key(|(_, op)| Reverse(op.count));
+        out += "\nOpcodes:\n     Count      Cycles  Opcode  Instruction\n";
+        for (opcode, op) in opcodes {
+            let (code, info) = match opcode {
+                0..=0xFF => (format!("${:02X}", opcode), &BASE[opcode]),
+                _ => (format!("$CB{:02X}", opcode & 0xFF), &CB[opcode & 0xFF]),
+            };
+            writeln!(out, "{:>10}  {:>10}  {:<6}  {}", op.count, op.cycles, code, format_template(info)).unwrap();
+        }
+        out
+    }
+
+    /// The time spent in each call stack in the folded format read by flame graph tools,
+    /// a line of `outer;inner cycles` per stack. Time halted is shown as a `(halt)` frame
+    pub fn folded(&self, symbols: &Symbols) -> String {
+        let mut lines = Vec::new();
+        for stack in &self.stacks {
+            let mut names = vec!["(top level)".to_string()];
+            names.extend(stack.nodes.iter().map(|node| node_name(symbols, *node)));
+            let names = names.join(";");
+            if stack.cycles > 0 {
+                lines.push(format!("{} {}", names, stack.cycles));
+            }
+            if stack.halted > 0 {
+                lines.push(format!("{};(halt) {}", names, stack.halted));
+            }
+        }
+        lines.sort();
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble_program;
+    use crate::cpu::CPU;
+    use crate::debug::debugger::Debugger;
+
+    #[test]
+    fn test_profiler() {
+        let source = "
+main:
+    ld B,10
+    .loop:
+        call routine
+        dec B
+        jr nz,.loop
+    halt
+routine:
+    ld A,[HL]
+    ret
+";
+        let program = assemble_program(source).unwrap();
+        let mut cpu = CPU::headless();
+        cpu.load_code(program.rom);
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+        for (name, addr) in &program.labels {
+            dbg.symbols.add(0, *addr, name);
+        }
+
+        assert_eq!(dbg.command(&mut cpu, "profile start"), "Profiling\n");
+        for _ in 0..100 {
+            cpu.tick();
+        }
+        assert!(cpu.state.halted);
+        assert_eq!(dbg.command(&mut cpu, "profile stop"), "Stopped profiling\n");
+        assert!(cpu.profiler.is_none());
+
+        let report = dbg.command(&mut cpu, "profile");
+        assert!(report.contains("Halted for"), "{}", report);
+        // Every call and return is counted, and the routine only runs its own two instructions
+        assert!(report.contains("        10         240  $CD     CALL n16"), "{}", report);
+        assert!(report.contains("        10         160  $C9     RET"), "{}", report);
+        let routine = report.lines().find(|l| l.ends_with(" routine")).unwrap();
+        let columns = routine.split_whitespace().collect::<Vec<_>>();
+        assert_eq!((columns[0], columns[2], columns[4]), ("240", "240", "10"), "{}", routine);
+
+        let folded = dbg.symbols.clone();
+        let folded = dbg.profile.as_ref().unwrap().folded(&folded);
+        let lines = folded.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{}", folded);
+        assert!(lines[0].starts_with("(top level) "));
+        assert!(lines[1].starts_with("(top level);(halt) "));
+        assert_eq!(lines[2], "(top level);routine 240");
+    }
+}
@@ -0,0 +1,227 @@
+use crate::cpu::snapshot::Snapshot;
+use crate::cpu::CPU;
+use std::collections::VecDeque;
+
+/// The memory snapshots may use by default
+pub const DEFAULT_BUDGET: usize = 64 << 20;
+/// Ticks between snapshots. Going back replays at most this many ticks from the snapshot before
+pub const SNAPSHOT_INTERVAL: u64 = 10_000;
+
+/// A record of execution that can be rewound, kept as periodic snapshots that are replayed forward from.
+/// Positions count the ticks made while recording, a tick being one instruction or, while halted, one wait.
+/// Replaying from a snapshot is deterministic, as the only input that isn't part of the machine state,
+/// the joypad, is recorded as it changes
+pub struct Recording {
+    /// Snapshots with the position they were taken at, oldest first
+    snapshots: VecDeque<(u64, Snapshot)>,
+    /// The position of the machine now, so the number of ticks recorded
+    pub position: u64,
+    /// The memory snapshots may use. Once it is used up the oldest are dropped
+    pub budget: usize,
+    used: usize,
+    /// The buttons held from each position they changed at
+    inputs: Vec<(u64, u8)>,
+    last_input: u8,
+}
+
+impl Recording {
+    pub fn new(cpu: &CPU, budget: usize) -> Recording {
+        let mut recording = Recording {
+            snapshots: VecDeque::new(),
+            position: 0,
+            budget,
+            used: 0,
+            inputs: Vec::new(),
+            last_input: cpu.joypad.pressed(),
+        };
+        recording.take_snapshot(cpu);
+        recording
+    }
+
+    /// The earliest position that can be gone back to
+    pub fn start(&self) -> u64 {
+        self.snapshots.front().map_or(self.position, |(pos, _)| *pos)
+    }
+
+    /// The memory used by snapshots
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Snapshots the machine at the current position, replacing any snapshot already taken there.
+    /// Needed whenever the state is changed other than by running, such as by setting a register
+    pub fn take_snapshot(&mut self, cpu: &CPU) {
+        if self.snapshots.back().is_some_and(|(pos, _)| *pos == self.position) {
+            let (_, old) = self.snapshots.pop_back().unwrap();
+            self.used -= old.size();
+        }
+        let snapshot = cpu.snapshot();
+        self.used += snapshot.size();
+        self.snapshots.push_back((self.position, snapshot));
+        while self.used > self.budget && self.snapshots.len() > 1 {
+            let (_, old) = self.snapshots.pop_front().unwrap();
+            self.used -= old.size();
+        }
+        let start = self.start();
+        self.inputs.retain(|(pos, _)| *pos >= start);
+    }
+
+    /// Records the state before a tick, which should then be made and followed by `advance`
+    pub fn record(&mut self, cpu: &CPU) {
+        let pressed = cpu.joypad.pressed();
+        if pressed != self.last_input {
+            self.inputs.push((self.position, pressed));
+            self.last_input = pressed;
+        }
+        let last = self.snapshots.back().map_or(0, |(pos, _)| *pos);
+        if self.position >= last + SNAPSHOT_INTERVAL {
+            self.take_snapshot(cpu);
+        }
+    }
+
+    pub fn advance(&mut self) {
+        self.position += 1;
+    }
+
+    /// Restores the snapshot at `index` and replays from it up to `to`.
+    /// `visit` is called with the state at each position, including the first, along with whether reaching it
+    /// triggered a watchpoint that halts. The tracer and profiler are left out, as the replay was already seen
+    fn replay(&self, cpu: &mut CPU, index: usize, to: u64, mut visit: impl FnMut(&CPU, u64, bool)) {
+        let tracer = cpu.tracer.take();
+        let profiler = cpu.profiler.take();
+        let (start, snapshot) = &self.snapshots[index];
+        cpu.restore(snapshot);
+        let mut inputs = self.inputs.iter().filter(|(p, _)| p >= start).peekable();
+        let mut pos = *start;
+        let mut watched = false;
+        loop {
+            visit(cpu, pos, watched);
+            if pos >= to {
+                break;
+            }
+            while let Some((_, pressed)) = inputs.next_if(|(p, _)| *p == pos) {
+                cpu.joypad.set_pressed(*pressed);
+            }
+            cpu.tick();
+            watched = cpu.mem.watch_hits.drain(..).any(|hit| hit.halt);
+            cpu.calls.take_anomalies();
+            pos += 1;
+        }
+        cpu.tracer = tracer;
+        cpu.profiler = profiler;
+    }
+
+    /// Goes back to an earlier position, discarding everything recorded after it
+    pub fn seek(&mut self, cpu: &mut CPU, target: u64) {
+        let target = target.max(self.start()).min(self.position);
+        let index = self.snapshots.iter().rposition(|(pos, _)| *pos <= target).unwrap();
+        self.replay(cpu, index, target, |_, _, _| {});
+
+        while self.snapshots.len() > index + 1 {
+            let (_, old) = self.snapshots.pop_back().unwrap();
+            self.used -= old.size();
+        }
+        self.inputs.retain(|(pos, _)| *pos < target);
+        self.last_input = cpu.joypad.pressed();
+        self.position = target;
+    }
+
+    /// Finds the latest position before the current one where `matches` holds of the state,
+    /// or that was reached by an instruction triggering a watchpoint that halts if `watch` is set.
+    /// The machine is left in an unspecified state, so should be moved with `seek` afterwards
+    pub fn search(&self, cpu: &mut CPU, watch: bool, mut matches: impl FnMut(&CPU) -> bool) -> Option<u64> {
+        let current = self.position;
+        for index in (0..self.snapshots.len()).rev() {
+            let end = self.snapshots.get(index + 1).map_or(current, |(pos, _)| *pos);
+            let mut found = None;
+            self.replay(cpu, index, end, |cpu, pos, watched| {
+                if pos < current && ((watch && watched) || matches(cpu)) {
+                    found = Some(pos);
+                }
+            });
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::debug::debugger::Debugger;
+
+    #[test]
+    fn test_reverse() {
+        let source = "
+main:
+    ld HL,$C000
+    .loop:
+        inc [HL]
+        call routine
+        jr .loop
+routine:
+    ld A,[HL]
+    ld B,A
+    ret
+";
+        let mut cpu = CPU::headless();
+        cpu.load_code(assemble(source).unwrap());
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+        let run = |dbg: &mut Debugger, cpu: &mut CPU, line: &str| {
+            let out = dbg.command(cpu, line);
+            while dbg.is_running() {
+                dbg.step(cpu);
+            }
+            out
+        };
+
+        assert!(run(&mut dbg, &mut cpu, "rstep").contains("Not recording"));
+        assert!(run(&mut dbg, &mut cpu, "record").starts_with("Recording"));
+        // Enough for several snapshots, so going back crosses from one to another
+        for _ in 0..5 {
+            run(&mut dbg, &mut cpu, "step 5000");
+        }
+        let counter = cpu.mem.get_addr(0xC000);
+        let pc = cpu.reg.pc;
+        let cycles = cpu.state.cycles;
+
+        run(&mut dbg, &mut cpu, "rstep 2500");
+        run(&mut dbg, &mut cpu, "step 2500");
+        assert_eq!((cpu.mem.get_addr(0xC000), cpu.reg.pc, cpu.state.cycles), (counter, pc, cycles));
+
+        // The counter was written by the instruction just run, so going back to a write finds the one before
+        assert_eq!(cpu.reg.pc, 0x154);
+        run(&mut dbg, &mut cpu, "watch write C000");
+        let out = run(&mut dbg, &mut cpu, "rcontinue");
+        assert!(out.contains("Watchpoint"), "{}", out);
+        assert_eq!(cpu.mem.get_addr(0xC000), counter.wrapping_sub(1));
+        assert_eq!(cpu.mem.get_addr(cpu.reg.pc.wrapping_sub(1)), 0x34);
+        run(&mut dbg, &mut cpu, "watch rm 1");
+
+        // Back into the routine that just returned, then out of it to the call
+        run(&mut dbg, &mut cpu, "break add 159");
+        let out = run(&mut dbg, &mut cpu, "rcontinue");
+        assert!(out.starts_with("Breakpoint 2"), "{}", out);
+        assert_eq!(cpu.reg.pc, 0x159);
+        assert_eq!(cpu.calls.depth(), 1);
+        let out = run(&mut dbg, &mut cpu, "rfinish");
+        assert_eq!(cpu.calls.depth(), 0, "{}", out);
+        assert_eq!(cpu.reg.pc, 0x154);
+
+        // Stepping back over a call skips the routine
+        run(&mut dbg, &mut cpu, "break rm 2");
+        run(&mut dbg, &mut cpu, "step 4");
+        assert_eq!(cpu.reg.pc, 0x157);
+        run(&mut dbg, &mut cpu, "rnext");
+        assert_eq!(cpu.reg.pc, 0x154);
+
+        // Going back stops at the start of the recording
+        let out = run(&mut dbg, &mut cpu, "rcontinue");
+        assert!(out.contains("start of the recording"), "{}", out);
+        assert_eq!(cpu.reg.pc, 0x100);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Where each memory region starts, so a label is only used to describe addresses in its own region
+const REGIONS: [u16; 11] = [
+    0x0000, 0x4000, 0x8000, 0xA000, 0xC000, 0xD000, 0xE000, 0xFE00, 0xFEA0, 0xFF00, 0xFF80,
+];
+
+/// A label from a symbol or map file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub bank: u16,
+    pub addr: u16,
+    pub name: String,
+}
+
+/// Labels loaded from the `.sym` and `.map` files written by toolchains such as RGBDS
+#[derive(Clone, Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+    by_name: HashMap<String, usize>,
+    /// The symbols by bank and address, for describing addresses.
+    /// Where several share an address the first global label is used
+    located: BTreeMap<(u16, u16), usize>,
+}
+
+/// The bank mapped at an address. There is no MBC, so ROM bank 1 and WRAM bank 1 are always mapped
+pub fn mapped_bank(addr: u16) -> u16 {
+    match addr {
+        0x4000..=0x7FFF | 0xD000..=0xDFFF => 1,
+        _ => 0,
+    }
+}
+
+fn region_start(addr: u16) -> u16 {
+    REGIONS.iter().rev().copied().find(|start| *start <= addr).unwrap_or(0)
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Loads a symbol file, read as a map file if it has a `.map` extension
+    pub fn load(path: &Path) -> Result<Symbols, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let parsed = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("map")) {
+            Symbols::parse_map(&text)
+        } else {
+            Symbols::parse_sym(&text)
+        };
+        parsed.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Loads the `.sym` and `.map` files alongside a ROM, if there are any
+    pub fn for_rom(rom: &Path) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for ext in ["sym", "map"].iter() {
+            let path = rom.with_extension(ext);
+            if path.exists() {
+                symbols.extend(Symbols::load(&path)?);
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Parses a symbol file of `bank:addr label` lines, as written by `rgblink -n`.
+    /// Comments start with `;`. WLA-DX splits the file into sections, of which only `[labels]` is read
+    pub fn parse_sym(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        let mut in_labels = true;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.starts_with('[') {
+                in_labels = line.eq_ignore_ascii_case("[labels]");
+                continue;
+            }
+            if line.is_empty() || !in_labels {
+                continue;
+            }
+            let err = || format!("line {}: expected bank:addr label, found {}", i + 1, line);
+            let (location, name) = line.split_once(char::is_whitespace).ok_or_else(err)?;
+            let (bank, addr) = location.split_once(':').ok_or_else(err)?;
+            let bank = u16::from_str_radix(bank, 16).map_err(|_| err())?;
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| err())?;
+            symbols.add(bank, addr, name.trim());
+        }
+        Ok(symbols)
+    }
+
+    /// Parses the symbols from a map file written by `rgblink -m`, which lists them as `$addr = label`
+    /// under a `<type> bank #N:` heading for each bank
+    pub fn parse_map(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        let mut bank = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some((_, n)) = line.split_once("bank #").or_else(|| line.split_once("Bank #")) {
+                let digits = n.chars().take_while(char::is_ascii_digit).collect::<String>();
+                bank = digits.parse().map_err(|_| format!("line {}: invalid bank in {}", i + 1, line))?;
+            } else if let Some((addr, name)) = line.strip_prefix('$').and_then(|l| l.split_once(" = ")) {
+                let addr = u16::from_str_radix(addr.trim(), 16)
+                    .map_err(|_| format!("line {}: invalid address in {}", i + 1, line))?;
+                symbols.add(bank, addr, name.trim());
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn add(&mut self, bank: u16, addr: u16, name: &str) {
+        let i = self.symbols.len();
+        self.symbols.push(Symbol {
+            bank,
+            addr,
+            name: name.to_string(),
+        });
+        self.by_name.entry(name.to_string()).or_insert(i);
+        let symbols = &self.symbols;
+        let local = |i: usize| symbols[i].name.contains('.');
+        match self.located.get(&(bank, addr)) {
+            Some(&existing) if !local(existing) || local(i) => {}
+            _ => {
+                self.located.insert((bank, addr), i);
+            }
+        }
+    }
+
+    pub fn extend(&mut self, other: Symbols) {
+        for sym in other.symbols {
+            self.add(sym.bank, sym.addr, &sym.name);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name).map(|i| &self.symbols[*i])
+    }
+
+    /// The address of a label, if it is in a bank that is mapped
+    pub fn address(&self, name: &str) -> Result<u16, String> {
+        let sym = self.lookup(name).ok_or_else(|| format!("No symbol named {}", name))?;
+        if sym.bank != mapped_bank(sym.addr) {
+            return Err(format!("{} is in bank {}, which is never mapped", name, sym.bank));
+        }
+        Ok(sym.addr)
+    }
+
+    /// The label at an address
+    pub fn at(&self, addr: u16) -> Option<&str> {
+        self.at_in(mapped_bank(addr), addr)
+    }
+
+    /// The label at an address in a bank, which need not be mapped
+    pub fn at_in(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.located.get(&(bank, addr)).map(|i| self.symbols[*i].name.as_str())
+    }
+
+    /// The nearest label at or before an address in the same memory region, with the offset from it
+    pub fn nearest(&self, addr: u16) -> Option<(&str, u16)> {
+        let bank = mapped_bank(addr);
+        let ((_, at), i) = self.located.range((bank, region_start(addr))..=(bank, addr)).next_back()?;
+        Some((self.symbols[*i].name.as_str(), addr - at))
+    }
+
+    /// Describes an address relative to the nearest label, as `Main` or `Main+$3`
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        match self.nearest(addr)? {
+            (name, 0) => Some(name.to_string()),
+            (name, offset) => Some(format!("{}+${:X}", name, offset)),
+        }
+    }
+
+    /// Formats an address for display. Without symbols this is `$0150`, otherwise it is qualified by its bank
+    /// and followed by the nearest label, as `00:0150 <Main+$3>`
+    pub fn format_addr(&self, addr: u16) -> String {
+        if self.is_empty() {
+            return format!("${:04X}", addr);
+        }
+        match self.describe(addr) {
+            Some(label) => format!("{:02X}:{:04X} <{}>", mapped_bank(addr), addr, label),
+            None => format!("{:02X}:{:04X}", mapped_bank(addr), addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::debug::debugger::Debugger;
+
+    #[test]
+    fn test_symbols() {
+        let sym = "\
+; File generated by rgblink
+[labels]
+00:0150 Main
+00:0153 Main.loop
+00:0155 Routine
+01:4000 Far
+02:4000 OtherBank
+00:c000 wCounter
+
+[definitions]
+0000000a MAX_LIVES
+";
+        let symbols = Symbols::parse_sym(sym).unwrap();
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(symbols.address("Main.loop"), Ok(0x153));
+        assert!(symbols.address("OtherBank").unwrap_err().contains("bank 2"));
+        assert_eq!(symbols.at(0x4000), Some("Far"));
+        assert_eq!(symbols.describe(0x154), Some("Main.loop+$1".to_string()));
+        assert_eq!(symbols.format_addr(0x157), "00:0157 <Routine+$2>");
+        // Labels in ROM don't describe RAM
+        assert_eq!(symbols.format_addr(0xA000), "00:A000");
+        assert!(Symbols::parse_sym("0150 Main").is_err());
+
+        let map = "\
+ROM0 bank #0:
+\tSECTION: $0150-$0156 ($0007 bytes) [\"Main\"]
+\t         $0150 = Main
+\t         $0155 = Routine
+\tEMPTY: $0157-$3fff ($3ea9 bytes)
+
+ROMX bank #1:
+\tSECTION: $4000-$4000 ($0001 bytes) [\"Far\"]
+\t         $4000 = Far
+";
+        let symbols = Symbols::parse_map(map).unwrap();
+        assert_eq!(symbols.lookup("Far").map(|s| s.bank), Some(1));
+        assert_eq!(symbols.address("Routine"), Ok(0x155));
+
+        let source = "
+main:
+    ld A,$12
+    call routine
+    .loop:
+        jr .loop
+routine:
+    inc A
+    ret
+";
+        let mut cpu = CPU::headless();
+        cpu.load_code(assemble(source).unwrap());
+        cpu.skip_bootrom();
+        let mut dbg = Debugger::new();
+        dbg.symbols = Symbols::parse_sym("00:0150 Main\n00:0155 Main.loop\n00:0157 Routine\n").unwrap();
+
+        assert_eq!(dbg.command(&mut cpu, "break add Routine"), "Breakpoint 1 at 00:0157 <Routine>\n");
+        dbg.command(&mut cpu, "continue");
+        while dbg.is_running() {
+            dbg.step(&mut cpu);
+        }
+        assert_eq!(dbg.location(&cpu), "00:0157 <Routine>: INC A");
+        assert!(dbg.command(&mut cpu, "disasm Main 3").contains("CALL $0157  ; Routine"));
+        assert_eq!(dbg.command(&mut cpu, "sym Main.loop"), "Main.loop = 00:0155\n");
+        assert!(dbg.command(&mut cpu, "break add Nowhere").contains("No symbol named Nowhere"));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::cpu::mem::Memory;
+use crate::cpu::Registers;
+use crate::debug::symbols::Symbols;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The value LY is forced to by Gameboy Doctor, so that traces don't depend on PPU timing
+pub const DOCTOR_LY: u8 = 0x90;
+
+/// Writes a line per instruction in the format used by Gameboy Doctor:
+/// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+pub struct Tracer {
+    out: Box<dyn Write>,
+    /// Labels written on a line of their own, as `Main:`, before the instruction they label.
+    /// There are none unless asked for with `--trace-labels`, so traces can still be compared with Gameboy Doctor's
+    pub symbols: Symbols,
+    /// Tracing begins the first time PC reaches this address. If not set, it begins once the boot ROM is unmapped
+    start_pc: Option<u16>,
+    started: bool,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, start_pc: Option<u16>) -> Tracer {
+        Tracer {
+            out,
+            symbols: Symbols::new(),
+            start_pc,
+            started: false,
+        }
+    }
+
+    pub fn to_file(path: &Path, start_pc: Option<u16>) -> io::Result<Tracer> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Tracer::new(Box::new(file), start_pc))
+    }
+
+    /// Records the state before the instruction at PC is executed
+    pub fn trace(&mut self, reg: &Registers, mem: &Memory) -> io::Result<()> {
+        if !self.started {
+            self.started = match self.start_pc {
+                Some(pc) => reg.pc == pc,
+                None => !mem.bootrom_paged,
+            };
+            if !self.started {
+                return Ok(());
+            }
+        }
+        if let Some(label) = self.symbols.at(reg.pc) {
+            writeln!(self.out, "{}:", label)?;
+        }
+        writeln!(self.out, "{}", format_line(reg, mem))
+    }
+}
+
+pub fn format_line(reg: &Registers, mem: &Memory) -> String {
+    let [a, f] = reg.af.to_be_bytes();
+    let [b, c] = reg.bc.to_be_bytes();
+    let [d, e] = reg.de.to_be_bytes();
+    let [h, l] = reg.hl.to_be_bytes();
+    let pcmem = (0..4)
+        .map(|i| format!("{:02X}", mem.get_addr(reg.pc.wrapping_add(i))))
+        .collect::<Vec<String>>()
+        .join(",");
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        a, f, b, c, d, e, h, l, reg.sp, reg.pc, pcmem
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_doctor_format() {
+        let mut cpu = CPU::headless();
+        cpu.load_code(assemble("main:\n ldh A,($44)\n").unwrap());
+        cpu.skip_bootrom();
+        cpu.force_ly = Some(DOCTOR_LY);
+
+        // The state Gameboy Doctor expects at the entry point of every test ROM
+        assert_eq!(
+            format_line(&cpu.reg, &cpu.mem),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01"
+        );
+
+        cpu.tick();
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.reg.af >> 8, DOCTOR_LY as u16);
+    }
+}
@@ -0,0 +1,374 @@
+use crate::cpu::mem::{Memory, MemoryRegister};
+use crate::peripherals::video::{shades, tile_addr, tile_pixel, GbColor};
+use crate::util::{check_bit, color_to_sdl};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Point;
+use sdl2::render::WindowCanvas;
+use sdl2::VideoSubsystem;
+
+/// The tiles in $8000-$97FF
+pub const TILE_COUNT: usize = 384;
+/// Tiles across the tile viewer
+const TILES_WIDE: usize = 16;
+/// The size of the tile viewer in pixels, before scaling
+pub const TILES_WIDTH: usize = TILES_WIDE * 8;
+pub const TILES_HEIGHT: usize = TILE_COUNT / TILES_WIDE * 8;
+/// The size of a background map in pixels
+pub const MAP_SIZE: usize = 256;
+/// The gap between the two maps in the map viewer
+const MAP_GAP: usize = 8;
+const TILES_SCALE: u32 = 3;
+const MAPS_SCALE: u32 = 2;
+const VIEWPORT_COLOR: Color = Color::RED;
+const WINDOW_COLOR: Color = Color::BLUE;
+
+/// The palette tiles are shown with
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Palette {
+    Bgp,
+    Obp0,
+    Obp1,
+    /// Colour numbers 0-3 as white to black, regardless of the palette registers
+    Raw,
+}
+
+impl Palette {
+    pub fn next(self) -> Palette {
+        match self {
+            Palette::Bgp => Palette::Obp0,
+            Palette::Obp0 => Palette::Obp1,
+            Palette::Obp1 => Palette::Raw,
+            Palette::Raw => Palette::Bgp,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Palette::Bgp => "BGP",
+            Palette::Obp0 => "OBP0",
+            Palette::Obp1 => "OBP1",
+            Palette::Raw => "raw",
+        }
+    }
+
+    pub fn shades(self, mem: &Memory) -> [GbColor; 4] {
+        match self {
+            Palette::Bgp => shades(mem.get_register(MemoryRegister::BGP)),
+            Palette::Obp0 => shades(mem.get_register(MemoryRegister::OBP0)),
+            Palette::Obp1 => shades(mem.get_register(MemoryRegister::OBP1)),
+            Palette::Raw => shades(0b11_10_01_00),
+        }
+    }
+}
+
+/// The tile a map entry refers to, addressed from $8000 if bit 4 of LCDC is set and $9000 signed otherwise
+pub fn map_tile(lcdc: u8, n: u8) -> usize {
+    if check_bit(lcdc, 4) {
+        n as usize
+    } else {
+        (256 + n as i8 as isize) as usize
+    }
+}
+
+/// Renders all 384 tiles, 16 to a row, as colour numbers
+pub fn render_tiles(mem: &Memory) -> Vec<u8> {
+    let mut pixels = vec![0; TILES_WIDTH * TILES_HEIGHT];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let (x, y) = (i % TILES_WIDTH, i / TILES_WIDTH);
+        let index = y / 8 * TILES_WIDE + x / 8;
+        *pixel = tile_pixel(mem, index, x % 8, y % 8);
+    }
+    pixels
+}
+
+/// Renders the 32x32 tile map at `base` as colour numbers, using the tile addressing selected by LCDC
+pub fn render_map(mem: &Memory, base: u16) -> Vec<u8> {
+    let lcdc = mem.get_register(MemoryRegister::LCDC);
+    let mut pixels = vec![0; MAP_SIZE * MAP_SIZE];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let (x, y) = (i % MAP_SIZE, i / MAP_SIZE);
+        let n = mem.get_addr(base + (y / 8 * 32 + x / 8) as u16);
+        *pixel = tile_pixel(mem, map_tile(lcdc, n), x % 8, y % 8);
+    }
+    pixels
+}
+
+/// An area of one of the maps, which wraps around its edges as the background does
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Area {
+    /// 0 for the map at $9800, 1 for $9C00
+    pub map: usize,
+    pub x: u8,
+    pub y: u8,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Area {
+    /// The pixels of the map around the edge of the area
+    pub fn outline(&self) -> Vec<(u8, u8)> {
+        let (right, bottom) = ((self.width - 1) as u8, (self.height - 1) as u8);
+        let mut points = Vec::new();
+        for i in 0..self.width as u8 {
+            points.push((self.x.wrapping_add(i), self.y));
+            points.push((self.x.wrapping_add(i), self.y.wrapping_add(bottom)));
+        }
+        for i in 0..self.height as u8 {
+            points.push((self.x, self.y.wrapping_add(i)));
+            points.push((self.x.wrapping_add(right), self.y.wrapping_add(i)));
+        }
+        points
+    }
+}
+
+/// The part of the background shown on the LCD, given by SCX and SCY
+pub fn viewport(mem: &Memory) -> Area {
+    let lcdc = mem.get_register(MemoryRegister::LCDC);
+    Area {
+        map: check_bit(lcdc, 3) as usize,
+        x: mem.get_register(MemoryRegister::SCX),
+        y: mem.get_register(MemoryRegister::SCY),
+        width: 160,
+        height: 144,
+    }
+}
+
+/// The part of the window's map shown on the LCD, if the window is enabled and on screen.
+/// The window is drawn from the top left of its map, at WX-7,WY on the LCD
+pub fn window_area(mem: &Memory) -> Option<Area> {
+    let lcdc = mem.get_register(MemoryRegister::LCDC);
+    let wx = mem.get_register(MemoryRegister::WX) as usize;
+    let wy = mem.get_register(MemoryRegister::WY) as usize;
+    if !check_bit(lcdc, 5) || wx > 166 || wy > 143 {
+        return None;
+    }
+    Some(Area {
+        map: check_bit(lcdc, 6) as usize,
+        x: 0,
+        y: 0,
+        width: 160 - wx.saturating_sub(7),
+        height: 144 - wy,
+    })
+}
+
+/// Describes the tile at a pixel of the tile viewer, with how maps refer to it in each addressing mode
+pub fn describe_tile(x: usize, y: usize) -> Option<String> {
+    let index = (y / 8) * TILES_WIDE + x / 8;
+    if x >= TILES_WIDTH || index >= TILE_COUNT {
+        return None;
+    }
+    let mut text = format!("Tile {} at ${:04X}", index, tile_addr(index));
+    if index < 256 {
+        text += &format!(", ${:02X} from $8000", index);
+    }
+    if index >= 128 {
+        text += &format!(", ${:02X} from $8800", (index as isize - 256) as u8);
+    }
+    Some(text)
+}
+
+/// Describes the map entry at a pixel of a map
+pub fn describe_map(mem: &Memory, map: usize, x: usize, y: usize) -> String {
+    let (col, row) = (x / 8, y / 8);
+    let entry = 0x9800 + 0x400 * map as u16 + (row * 32 + col) as u16;
+    let n = mem.get_addr(entry);
+    let tile = map_tile(mem.get_register(MemoryRegister::LCDC), n);
+    format!(
+        "Map ${:04X} ({}, {}) at ${:04X}: tile ${:02X} at ${:04X}",
+        0x9800 + 0x400 * map as u16,
+        col,
+        row,
+        entry,
+        n,
+        tile_addr(tile)
+    )
+}
+
+/// Draws colour numbers onto a canvas at an offset, batching the points of each shade
+fn draw_pixels(canvas: &mut WindowCanvas, pixels: &[u8], width: usize, left: usize, palette: [GbColor; 4]) {
+    let mut points = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+    for (i, c) in pixels.iter().enumerate() {
+        points[*c as usize].push(Point::new((left + i % width) as i32, (i / width) as i32));
+    }
+    for (shade, points) in palette.iter().zip(points.iter()) {
+        canvas.set_draw_color(color_to_sdl(*shade));
+        canvas.draw_points(&points[..]).unwrap();
+    }
+}
+
+/// Windows showing the tiles and background maps in VRAM. Hovering over them shows details in the title,
+/// and P cycles through the palettes used to show them
+pub struct VramViewer {
+    tiles: WindowCanvas,
+    maps: WindowCanvas,
+    pub palette: Palette,
+    /// Set once either window is closed
+    pub closed: bool,
+    /// What is under the mouse, as a window and position in its unscaled pixels
+    hover: Option<(u32, usize, usize)>,
+}
+
+impl VramViewer {
+    pub fn open(video: &VideoSubsystem) -> Result<VramViewer, String> {
+        let open = |title: &str, width: usize, height: usize, scale: u32| -> Result<WindowCanvas, String> {
+            let window = video
+                .window(title, width as u32 * scale, height as u32 * scale)
+                .build()
+                .map_err(|e| e.to_string())?;
+            let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+            canvas.set_scale(scale as f32, scale as f32)?;
+            Ok(canvas)
+        };
+        Ok(VramViewer {
+            tiles: open("gbemu tiles", TILES_WIDTH, TILES_HEIGHT, TILES_SCALE)?,
+            maps: open("gbemu maps", 2 * MAP_SIZE + MAP_GAP, MAP_SIZE, MAPS_SCALE)?,
+            palette: Palette::Bgp,
+            closed: false,
+            hover: None,
+        })
+    }
+
+    /// Handles an event if it is for one of the viewer's windows, returning whether it was
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        let ids = [self.tiles.window().id(), self.maps.window().id()];
+        match *event {
+            Event::Window {
+                window_id,
+                win_event: WindowEvent::Close,
+                ..
+            } if ids.contains(&window_id) => self.closed = true,
+            Event::Window {
+                window_id,
+                win_event: WindowEvent::Leave,
+                ..
+            } if ids.contains(&window_id) => self.hover = None,
+            Event::MouseMotion { window_id, x, y, .. } if ids.contains(&window_id) => {
+                let scale = if window_id == ids[0] { TILES_SCALE } else { MAPS_SCALE };
+                self.hover = Some((window_id, x.max(0) as usize / scale as usize, y.max(0) as usize / scale as usize));
+            }
+            Event::KeyDown {
+                window_id,
+                keycode: Some(key),
+                ..
+            } if ids.contains(&window_id) => match key {
+                Keycode::P => self.palette = self.palette.next(),
+                Keycode::Escape => self.closed = true,
+                _ => {}
+            },
+            Event::KeyUp { window_id, .. } if ids.contains(&window_id) => {}
+            _ => return false,
+        }
+        true
+    }
+
+    /// Redraws both windows from the current contents of VRAM and the LCD registers
+    pub fn update(&mut self, mem: &Memory) {
+        let palette = self.palette.shades(mem);
+
+        self.tiles.set_draw_color(Color::BLACK);
+        self.tiles.clear();
+        draw_pixels(&mut self.tiles, &render_tiles(mem), TILES_WIDTH, 0, palette);
+        self.tiles.present();
+
+        self.maps.set_draw_color(Color::MAGENTA);
+        self.maps.clear();
+        for map in 0..2 {
+            let pixels = render_map(mem, 0x9800 + 0x400 * map as u16);
+            draw_pixels(&mut self.maps, &pixels, MAP_SIZE, map * (MAP_SIZE + MAP_GAP), palette);
+        }
+        let areas = [(Some(viewport(mem)), VIEWPORT_COLOR), (window_area(mem), WINDOW_COLOR)];
+        for (area, color) in areas.iter() {
+            if let Some(area) = area {
+                let left = area.map * (MAP_SIZE + MAP_GAP);
+                let points = area
+                    .outline()
+                    .into_iter()
+                    .map(|(x, y)| Point::new((left + x as usize) as i32, y as i32))
+                    .collect::<Vec<Point>>();
+                self.maps.set_draw_color(*color);
+                self.maps.draw_points(&points[..]).unwrap();
+            }
+        }
+        self.maps.present();
+
+        self.update_titles(mem);
+    }
+
+    fn update_titles(&mut self, mem: &Memory) {
+        let palette = self.palette.name();
+        let (mut tiles, mut maps) = (format!("gbemu tiles - {}", palette), format!("gbemu maps - {}", palette));
+        if let Some((window_id, x, y)) = self.hover {
+            if window_id == self.tiles.window().id() {
+                if let Some(tile) = describe_tile(x, y) {
+                    tiles = format!("{} - {}", tiles, tile);
+                }
+            } else {
+                let map = x / (MAP_SIZE + MAP_GAP);
+                let x = x % (MAP_SIZE + MAP_GAP);
+                if map < 2 && x < MAP_SIZE && y < MAP_SIZE {
+                    maps = format!("{} - {}", maps, describe_map(mem, map, x, y));
+                }
+            }
+        }
+        // Only changed titles are set, as setting one can be slow
+        if self.tiles.window().title() != tiles {
+            self.tiles.window_mut().set_title(&tiles).unwrap();
+        }
+        if self.maps.window().title() != maps {
+            self.maps.window_mut().set_title(&maps).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_vram_viewer() {
+        let mut cpu = CPU::headless();
+        let mem = &mut cpu.mem;
+        // Tile 1 has a top row of colours 0, 1, 2, 3 repeated, and tile 256 is solid colour 3
+        mem.set_addr(0x8010, 0b0101_0101);
+        mem.set_addr(0x8011, 0b0011_0011);
+        for i in 0..16 {
+            mem.set_addr(0x9000 + i, 0xFF);
+        }
+        let tiles = render_tiles(mem);
+        assert_eq!(tiles[8..16], [0, 1, 2, 3, 0, 1, 2, 3]);
+        assert_eq!(tiles[16 * 8 * TILES_WIDTH], 3);
+
+        // Map entry 0 refers to tile 0 from $9000 in the signed mode, and from $8000 in the other
+        mem.set_addr(0x9800, 0x00);
+        mem.set_addr(0x9801, 0x01);
+        mem.set_register(MemoryRegister::LCDC, 0x81);
+        assert_eq!(render_map(mem, 0x9800)[0], 3);
+        mem.set_register(MemoryRegister::LCDC, 0x91);
+        assert_eq!(render_map(mem, 0x9800)[0], 0);
+        assert_eq!(render_map(mem, 0x9800)[9], 1);
+
+        assert_eq!(describe_tile(8, 0).unwrap(), "Tile 1 at $8010, $01 from $8000");
+        assert_eq!(describe_tile(0, 16 * 8).unwrap(), "Tile 256 at $9000, $00 from $8800");
+        assert_eq!(describe_tile(0, TILES_HEIGHT), None);
+        assert_eq!(describe_map(mem, 0, 9, 3), "Map $9800 (1, 0) at $9801: tile $01 at $8010");
+
+        // The viewport wraps around the edges of the map
+        mem.set_register(MemoryRegister::SCX, 200);
+        mem.set_register(MemoryRegister::SCY, 0);
+        let viewport = viewport(mem);
+        assert_eq!((viewport.map, viewport.x), (0, 200));
+        let outline = viewport.outline();
+        assert!(outline.contains(&(103, 143)) && outline.contains(&(255, 0)));
+        assert!(!outline.contains(&(104, 0)));
+
+        assert_eq!(window_area(mem), None);
+        mem.set_register(MemoryRegister::LCDC, 0x91 | 0x60);
+        mem.set_register(MemoryRegister::WX, 87);
+        mem.set_register(MemoryRegister::WY, 100);
+        let window = window_area(mem).unwrap();
+        assert_eq!((window.map, window.width, window.height), (1, 80, 44));
+    }
+}
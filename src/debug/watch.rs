@@ -0,0 +1,72 @@
+/// What a watchpoint triggers on
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// A write of a value different to the one already there
+    Change,
+    /// Any read or write
+    Access,
+}
+
+/// A memory access, as checked against watchpoints
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// What made an access
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Accessor {
+    Cpu,
+    Dma,
+}
+
+pub struct Watchpoint {
+    pub id: u32,
+    /// The first address watched
+    pub start: u16,
+    /// The last address watched
+    pub end: u16,
+    pub kind: WatchKind,
+    /// Whether execution stops when triggered, rather than just reporting the access
+    pub halt: bool,
+    pub enabled: bool,
+}
+
+/// An access that triggered a watchpoint
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct WatchHit {
+    pub id: u32,
+    pub addr: u16,
+    pub access: Access,
+    pub by: Accessor,
+    /// The value at the address before the access
+    pub old: u8,
+    /// The value read or written
+    pub new: u8,
+    pub halt: bool,
+}
+
+impl Watchpoint {
+    pub fn triggers(&self, addr: u16, access: Access, old: u8, new: u8) -> bool {
+        self.enabled
+            && (self.start..=self.end).contains(&addr)
+            && match self.kind {
+                WatchKind::Read => access == Access::Read,
+                WatchKind::Write => access == Access::Write,
+                WatchKind::Change => access == Access::Write && old != new,
+                WatchKind::Access => true,
+            }
+    }
+
+    /// Describes the watched range, as `$C000` or `$C000-$C0FF`
+    pub fn range(&self) -> String {
+        if self.start == self.end {
+            format!("${:04X}", self.start)
+        } else {
+            format!("${:04X}-${:04X}", self.start, self.end)
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Golden framebuffer tests, comparing the frame a ROM ends on against a reference image or hash
+
+use crate::asm::assemble;
+use crate::cpu::CPU;
+use crate::peripherals::joypad::Button;
+use crate::peripherals::video::{GbColor, FRAME_DOTS, SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+type Frame = Vec<u8>;
+
+/// A button press or release, applied at the start of the given frame
+pub struct Input {
+    pub frame: u64,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+fn shade(c: GbColor) -> u8 {
+    match c {
+        GbColor::White => 255,
+        GbColor::LightGray => 170,
+        GbColor::DarkGray => 85,
+        GbColor::Black => 0,
+    }
+}
+
+/// Runs a ROM from the cartridge entry point for `frames` frames, returning the last one as grey levels.
+/// While the LCD is off a frame's worth of cycles counts as a frame
+pub fn run_frames(rom: Vec<u8>, frames: u64, inputs: &[Input]) -> Frame {
+    let mut cpu = CPU::headless();
+    cpu.load_code(rom);
+    cpu.skip_bootrom();
+
+    for frame in 0..frames {
+        for input in inputs.iter().filter(|i| i.frame == frame) {
+            if input.pressed {
+                cpu.joypad.press(input.button);
+            } else {
+                cpu.joypad.release(input.button);
+            }
+        }
+
+        let start = cpu.state.cycles;
+        while !cpu.video.frame_ready && cpu.state.cycles - start < FRAME_DOTS {
+            cpu.tick();
+        }
+        cpu.video.frame_ready = false;
+    }
+
+    cpu.video.framebuffer.iter().map(|c| shade(*c)).collect()
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("test_programs/golden")
+}
+
+fn output_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("target/golden")
+}
+
+fn write_pgm(path: &Path, frame: &[u8]) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let mut data = format!("P5\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    data.extend_from_slice(frame);
+    fs::write(path, data).unwrap();
+}
+
+/// Reads a binary PGM written by `write_pgm`
+fn read_pgm(path: &Path) -> Option<Frame> {
+    let data = fs::read(path).ok()?;
+    let header = format!("P5\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT);
+    let pixels = data.strip_prefix(header.as_bytes())?;
+    if pixels.len() == SCREEN_WIDTH * SCREEN_HEIGHT {
+        Some(pixels.to_vec())
+    } else {
+        None
+    }
+}
+
+/// FNV-1a, which is stable across platforms and Rust versions unlike `DefaultHasher`
+fn hash(frame: &[u8]) -> u64 {
+    frame.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// An image with the mismatched pixels in black, over a faded copy of the actual frame
+fn diff_image(actual: &[u8], expected: &[u8]) -> Frame {
+    actual
+        .iter()
+        .zip(expected)
+        .map(|(a, e)| if a == e { 192 + a / 4 } else { 0 })
+        .collect()
+}
+
+/// Compares a frame against the reference image `name`.pgm, writing out images and panicking on a mismatch.
+/// With `GBEMU_BLESS` set the frame is written out as the new reference instead
+pub fn check_image(name: &str, actual: &[u8]) {
+    let reference = golden_dir().join(format!("{}.pgm", name));
+    if std::env::var_os("GBEMU_BLESS").is_some() {
+        write_pgm(&reference, actual);
+        return;
+    }
+
+    let expected = read_pgm(&reference)
+        .unwrap_or_else(|| panic!("No reference image at {}, run with GBEMU_BLESS=1 to create it", reference.display()));
+    if actual != expected.as_slice() {
+        let out = output_dir();
+        write_pgm(&out.join(format!("{}.actual.pgm", name)), actual);
+        write_pgm(&out.join(format!("{}.expected.pgm", name)), &expected);
+        write_pgm(&out.join(format!("{}.diff.pgm", name)), &diff_image(actual, &expected));
+        let mismatched = actual.iter().zip(&expected).filter(|(a, e)| a != e).count();
+        panic!("{} differs from its reference in {} pixels, see {}", name, mismatched, out.display());
+    }
+}
+
+/// Compares a frame against a stored hash, writing out the actual image and panicking on a mismatch
+pub fn check_hash(name: &str, actual: &[u8], expected: u64) {
+    let h = hash(actual);
+    if h != expected {
+        let path = output_dir().join(format!("{}.actual.pgm", name));
+        write_pgm(&path, actual);
+        panic!("{} hashes to {:#018x}, expected {:#018x}, see {}", name, h, expected, path.display());
+    }
+}
+
+#[test]
+fn test_golden_background() {
+    let source = include_str!("../test_programs/background.s");
+    let frame = run_frames(assemble(source).unwrap(), 3, &[]);
+    // LCDC bit 0 is left clear, so the background is disabled and the screen stays white
+    check_image("background", &frame);
+
+    // With the background enabled the tile fills the screen, as the tile map is all zeroes
+    let source = source.replace("LD A,%10010000", "LD A,%10010001");
+    let frame = run_frames(assemble(&source).unwrap(), 3, &[]);
+    check_image("background_enabled", &frame);
+    check_hash("background_enabled", &frame, 0x4d8c19011e48d745);
+}
+
+#[test]
+fn test_golden_input() {
+    // Turns the screen black once A is pressed, by filling tile 0 and enabling the background
+    let source = "
+main:
+    ld A,%00010000
+    ld ($FF00),A
+    .wait:
+        ld A,($FF00)
+        and A,%00000001
+        jr nz,.wait
+    ld HL,$8000
+    ld A,$FF
+    .fill:
+        ldi (HL),A
+        bit 4,L
+        jr z,.fill
+    ld A,%11100100
+    ld ($FF47),A
+    ld A,%10010001
+    ld ($FF40),A
+    .loop:
+        jr .loop
+";
+    let rom = assemble(source).unwrap();
+    let idle = run_frames(rom.clone(), 4, &[]);
+    assert!(idle.iter().all(|p| *p == 255));
+
+    let inputs = [Input {
+        frame: 1,
+        button: Button::A,
+        pressed: true,
+    }];
+    let pressed = run_frames(rom, 4, &inputs);
+    assert!(pressed.iter().all(|p| *p == 0));
+}
@@ -1,10 +1,29 @@
+mod asm;
 mod cpu;
+mod debug;
+#[cfg(test)]
+mod golden;
 mod peripherals;
+mod runner;
 mod util;
 
 use crate::cpu::{BOOTROM, CPU, CYCLES_PER_FRAME, CLOCK_SPEED};
+use crate::debug::coverage::Coverage;
+use crate::debug::dap::DapServer;
+use crate::debug::debugger::{Debugger, RunMode};
+use crate::debug::gdb::GdbStub;
+use crate::debug::oam::OamViewer;
+use crate::debug::profile::Profiler;
+use crate::debug::symbols::Symbols;
+use crate::debug::trace::{Tracer, DOCTOR_LY};
+use crate::debug::vram::VramViewer;
+use crate::peripherals::joypad::Button;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use crate::util::parse_hex;
+use std::io::Write;
+use std::path::Path;
+use std::process;
 use std::time::{Instant, Duration};
 use std::sync::mpsc;
 use std::thread;
@@ -12,53 +31,89 @@ use std::thread;
 pub struct CpuDrv {
     cpu: cpu::CPU,
     last_time: Instant,
-    step_mode: bool,
-    breakpoints: Vec<u16>,
-    bp_channel: mpsc::Receiver<BreakpointMessage>,
-}
-
-pub enum BreakpointMessage {
-    Add(u16),
-    Remove(u16),
+    debugger: Debugger,
+    commands: mpsc::Receiver<String>,
+    gdb: Option<GdbStub>,
+    dap: Option<DapServer>,
+    /// Where to write the profile on exit, if profiling from the start
+    profile: Option<String>,
+    /// Where to save coverage on exit, if recording it from the start
+    coverage: Option<String>,
+    /// The tile and map viewer windows, if open
+    vram: Option<VramViewer>,
+    /// The sprite viewer window, if open
+    oam: Option<OamViewer>,
 }
 
 impl CpuDrv {
-    pub fn new(rom: Vec<u8>, bp_channel: mpsc::Receiver<BreakpointMessage>) -> CpuDrv {
+    pub fn new(rom: Vec<u8>, commands: mpsc::Receiver<String>) -> CpuDrv {
         let mut cpu = CPU::new();
         cpu.load_code(rom);
 
         CpuDrv {
             cpu,
             last_time: Instant::now(),
-            step_mode: true,
-            breakpoints: Vec::new(),
-            bp_channel
+            debugger: Debugger::new(),
+            commands,
+            gdb: None,
+            dap: None,
+            profile: None,
+            coverage: None,
+            vram: None,
+            oam: None,
         }
     }
 
     pub fn drive(mut self) {
-        let mut event = self.cpu.sdl.event_pump().unwrap();
+        let mut event = self.cpu.sdl.as_ref().unwrap().event_pump().unwrap();
+        prompt();
 
         'main: loop {
-            if !self.step_mode {
+            if let Some(gdb) = &mut self.gdb {
+                gdb.poll(&mut self.cpu, &mut self.debugger);
+                if self.debugger.quit {
+                    break 'main;
+                }
+            }
+            if let Some(dap) = &mut self.dap {
+                dap.poll(&mut self.cpu, &mut self.debugger);
+                if self.debugger.quit {
+                    break 'main;
+                }
+            }
+
+            // The viewers are redrawn each frame, or when stopped after anything that could change what they show
+            let mut redraw = self.debugger.is_running();
+            if self.debugger.is_running() {
                 self.run();
+            } else {
+                thread::sleep(Duration::from_millis(10));
             }
 
-            if let Ok(msg) = self.bp_channel.try_recv() {
-                match msg {
-                    BreakpointMessage::Add(pc) => if !self.breakpoints.contains(&pc) {
-                        println!("Added breakpoint at PC={:#x}", pc);
-                        self.breakpoints.push(pc)
-                    },
-                    BreakpointMessage::Remove(pc) => if let Some((i, _)) = self.breakpoints.iter().enumerate().find(|(_, bp)| pc == **bp) {
-                        println!("Removed breakpoint at PC={:#x}", pc);
-                        self.breakpoints.remove(i);
-                    }
+            while let Ok(line) = self.commands.try_recv() {
+                redraw = true;
+                print!("{}", self.debugger.command(&mut self.cpu, &line));
+                if self.debugger.quit {
+                    break 'main;
+                }
+                if !self.debugger.is_running() {
+                    prompt();
                 }
             }
 
-
             for ev in event.poll_iter() {
+                if let Some(vram) = &mut self.vram {
+                    if vram.handle_event(&ev) {
+                        redraw = true;
+                        continue;
+                    }
+                }
+                if let Some(oam) = &mut self.oam {
+                    if oam.handle_event(&ev) {
+                        redraw = true;
+                        continue;
+                    }
+                }
                 match ev {
                     Event::Quit { .. }
                     | Event::KeyDown {
@@ -69,86 +124,352 @@ impl CpuDrv {
                         keycode: Some(Keycode::E),
                         ..
                     } => {
-                        self.step_mode = !self.step_mode;
-                        println!("Single step mode: {}", self.step_mode)
+                        if self.debugger.is_running() {
+                            self.debugger.stop(self.cpu.reg.pc);
+                            self.stopped("Interrupted");
+                        } else {
+                            self.debugger.mode = RunMode::Running;
+                        }
                     }
                     Event::KeyDown {
                         keycode: Some(Keycode::S),
                         ..
+                    } if !self.debugger.is_running() => {
+                        self.debugger.mode = RunMode::Step(1);
+                        self.debugger.step(&mut self.cpu);
+                        self.stopped("Step complete");
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::V),
+                        ..
+                    } => {
+                        if self.vram.is_some() {
+                            self.vram = None;
+                        } else {
+                            self.open_vram();
+                            redraw = true;
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::O),
+                        ..
                     } => {
-                        if self.step_mode {
-                            self.cpu.tick();
+                        if self.oam.is_some() {
+                            self.oam = None;
+                        } else {
+                            self.open_oam();
+                            redraw = true;
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(key),
+                        ..
+                    } => {
+                        if let Some(button) = key_to_button(key) {
+                            self.cpu.joypad.press(button);
+                        }
+                    }
+                    Event::KeyUp {
+                        keycode: Some(key),
+                        ..
+                    } => {
+                        if let Some(button) = key_to_button(key) {
+                            self.cpu.joypad.release(button);
                         }
                     }
                     _ => {}
                 }
             }
+            if let Some(vram) = &mut self.vram {
+                if vram.closed {
+                    self.vram = None;
+                } else if redraw {
+                    vram.update(&self.cpu.mem);
+                }
+            }
+            if let Some(oam) = &mut self.oam {
+                if oam.closed {
+                    self.oam = None;
+                } else if redraw {
+                    oam.update(&self.cpu.mem);
+                }
+            }
             self.last_time = Instant::now();
         }
+
+        if let Some(path) = &self.profile {
+            self.write_profile(path);
+        }
+        if let Some(path) = &self.coverage {
+            if let Some(coverage) = self.cpu.coverage.as_ref().or(self.debugger.coverage.as_ref()) {
+                match coverage.save(Path::new(path)) {
+                    Ok(()) => println!("Saved coverage to {}", path),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
     }
 
-    /// Runs one full frame, plus additional cycles at the start of the period to clear the blanking interval
-    pub fn run(&mut self) {
-        // Tick through the blanking interval if necessary
-        while self.cpu.video.vblank_acc != 0 || self.cpu.video.hblank_acc != 0 {
-            self.cpu.tick();
+    /// Opens the tile and map viewer windows
+    fn open_vram(&mut self) {
+        let video = self.cpu.sdl.as_ref().unwrap().video().unwrap();
+        match VramViewer::open(&video) {
+            Ok(vram) => self.vram = Some(vram),
+            Err(e) => eprintln!("Unable to open the VRAM viewer: {}", e),
+        }
+    }
+
+    /// Opens the sprite viewer window
+    fn open_oam(&mut self) {
+        let video = self.cpu.sdl.as_ref().unwrap().video().unwrap();
+        match OamViewer::open(&video) {
+            Ok(oam) => self.oam = Some(oam),
+            Err(e) => eprintln!("Unable to open the OAM viewer: {}", e),
         }
+    }
 
-        let future = Instant::now() + Duration::from_secs(CYCLES_PER_FRAME / CLOCK_SPEED);
+    /// Writes the report of the profile to a file, and its folded stacks alongside it with a `.folded` extension
+    fn write_profile(&self, path: &str) {
+        let profiler = match self.cpu.profiler.as_ref().or(self.debugger.profile.as_ref()) {
+            Some(profiler) => profiler,
+            None => return,
+        };
+        let folded = format!("{}.folded", path);
+        let written = std::fs::write(path, profiler.report(&self.debugger.symbols))
+            .and_then(|_| std::fs::write(&folded, profiler.folded(&self.debugger.symbols)));
+        match written {
+            Ok(()) => println!("Wrote profile to {} and {}", path, folded),
+            Err(e) => eprintln!("Unable to write profile: {}", e),
+        }
+    }
+
+    /// Prints output from the debugger produced while running
+    fn print_messages(&mut self) {
+        for msg in self.debugger.messages() {
+            println!("{}", msg);
+        }
+    }
+
+    /// Reports where execution stopped and prompts for the next command
+    fn stopped(&mut self, reason: &str) {
+        self.print_messages();
+        println!("{} at {}", reason, self.debugger.location(&self.cpu));
+        prompt();
+    }
+
+    /// Runs until the LCD has scanned a full frame. If the LCD is disabled, a frame's worth of cycles is run instead
+    /// In STOP mode the CPU keeps being ticked, as that is where a joypad line going low wakes it
+    /// While stepping or with breakpoints set each instruction is checked, and this returns early once the debugger stops
+    pub fn run(&mut self) {
+        let future = Instant::now() + Duration::from_secs_f64(CYCLES_PER_FRAME as f64 / CLOCK_SPEED as f64);
 
         // Tick through the frame
-        while self.cpu.video.vblank_acc == 0 {
-            self.cpu.tick();
+        let mut cycles = 0;
+        let checked = self.debugger.is_stepping();
+        while !self.cpu.video.frame_ready && cycles < CYCLES_PER_FRAME {
+            if checked {
+                let (taken, stop) = self.debugger.step(&mut self.cpu);
+                cycles += taken as u64;
+                if let Some(reason) = stop {
+                    self.stopped(&reason);
+                    return;
+                }
+            } else {
+                cycles += self.cpu.tick() as u64;
+            }
         }
+        self.cpu.video.frame_ready = false;
+        self.print_messages();
 
         // If need be delay to match the expected frequency
         let now = Instant::now();
         if now < future {
             thread::sleep(future - now);
         }
-        // let mut cycles =
-        //     (self.last_time.elapsed().as_secs_f64() * cpu::CLOCK_SPEED as f64).floor() as i32;
-        //
-        // while cycles > 0 {
-        //     if self.breakpoints.contains(&self.cpu.reg.pc) && !self.step_mode {
-        //         self.step_mode = true;
-        //         println!("BREAK PC={:#x}", self.cpu.reg.pc);
-        //         return;
-        //     }
-        //     cycles -= self.cpu.tick() as i32;
-        // }
+    }
+}
+
+fn prompt() {
+    print!("(gbemu) ");
+    std::io::stdout().flush().unwrap();
+}
+
+/// Maps a keyboard key to the joypad button it controls
+fn key_to_button(key: Keycode) -> Option<Button> {
+    match key {
+        Keycode::Right => Some(Button::Right),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::Z => Some(Button::A),
+        Keycode::X => Some(Button::B),
+        Keycode::Backspace => Some(Button::Select),
+        Keycode::Return => Some(Button::Start),
+        _ => None,
     }
 }
 
 fn main() {
     use std::fs;
-    let rom = fs::read("cpu_instrs.gb").unwrap();
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    match args.first().map(String::as_str) {
+        Some("asm") => return asm::cli(&args[1..]),
+        Some("disasm") => return debug::disasm::cli(&args[1..]),
+        Some("test") => return runner::cli(&args[1..]),
+        _ => {}
+    }
+
+    let usage = "usage: gbemu [--trace FILE] [--trace-from PC] [--trace-labels] [--force-ly VALUE] [--gdb PORT] [--dap PORT] [--sym FILE]... [--profile FILE] [--coverage FILE] [--vram] [--oam] [rom]";
+    let mut path = "cpu_instrs.gb".to_string();
+    let mut trace = None;
+    let mut trace_from = None;
+    let mut trace_labels = false;
+    let mut force_ly = None;
+    let mut gdb_port = None;
+    let mut dap_port = None;
+    let mut sym_files = Vec::new();
+    let mut profile = None;
+    let mut coverage = None;
+    let mut vram = false;
+    let mut oam = false;
+    let mut opts = args.iter();
+    while let Some(arg) = opts.next() {
+        let mut value = || {
+            opts.next().cloned().unwrap_or_else(|| {
+                eprintln!("{} needs a value\n{}", arg, usage);
+                process::exit(2);
+            })
+        };
+        let mut hex = || {
+            let v = value();
+            parse_hex(&v).unwrap_or_else(|| {
+                eprintln!("Invalid hex number {}\n{}", v, usage);
+                process::exit(2);
+            })
+        };
+        match arg.as_str() {
+            "--trace" => trace = Some(value()),
+            "--sym" => sym_files.push(value()),
+            "--profile" => profile = Some(value()),
+            "--coverage" => coverage = Some(value()),
+            "--vram" => vram = true,
+            "--oam" => oam = true,
+            "--trace-from" => trace_from = Some(hex()),
+            "--trace-labels" => trace_labels = true,
+            "--force-ly" => force_ly = Some(hex() as u8),
+            "--gdb" => {
+                let v = value();
+                gdb_port = Some(v.parse::<u16>().unwrap_or_else(|_| {
+                    eprintln!("Invalid port {}\n{}", v, usage);
+                    process::exit(2);
+                }))
+            }
+            "--dap" => {
+                let v = value();
+                dap_port = Some(v.parse::<u16>().unwrap_or_else(|_| {
+                    eprintln!("Invalid port {}\n{}", v, usage);
+                    process::exit(2);
+                }))
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option {}\n{}", arg, usage);
+                process::exit(2);
+            }
+            _ => path = arg.clone(),
+        }
+    }
+
+    let rom = fs::read(&path).unwrap();
+    // Symbol files alongside the ROM are loaded automatically, and any others given are added to them
+    let mut symbols = Symbols::for_rom(Path::new(&path)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        Symbols::new()
+    });
+    for file in &sym_files {
+        match Symbols::load(Path::new(file)) {
+            Ok(loaded) => symbols.extend(loaded),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if !symbols.is_empty() {
+        println!("Loaded {} symbols", symbols.len());
+    }
+    // Coverage already saved to the file is carried on from, so runs accumulate
+    let rom_coverage = coverage.as_ref().map(|path| {
+        let mut recorded = Coverage::new(rom.len());
+        recorded.merge_file(Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        recorded
+    });
+
     let (tx, rx) = mpsc::channel();
     thread::spawn(move || {
-        let stdin = std::io::stdin();
-        loop {
-            let mut line = String::new();
-            stdin.read_line(&mut line).unwrap();
-
-            if line.starts_with("break") {
-                let parts = line.split(" ").collect::<Vec<&str>>();
-
-                if let Ok(pc) = u16::from_str_radix(parts[2].trim(), 16) {
-                    match parts[1].to_lowercase().as_str() {
-                        "add" => {
-                            tx.send(BreakpointMessage::Add(pc)).unwrap();
-                        }
-                        "rm" => {
-                            tx.send(BreakpointMessage::Remove(pc)).unwrap();
-                        }
-                        _ => {}
-                    }
-                }
+        for line in std::io::stdin().lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
             }
         }
     });
     let mut drv = CpuDrv::new(rom, rx);
+    // Traces are compared against Gameboy Doctor's, so LY is forced to the value it expects unless told otherwise
+    drv.cpu.force_ly = force_ly.or(trace.as_ref().map(|_| DOCTOR_LY));
+    if let Some(trace) = trace {
+        match Tracer::to_file(Path::new(&trace), trace_from) {
+            Ok(mut tracer) => {
+                if trace_labels {
+                    tracer.symbols = symbols.clone();
+                }
+                drv.cpu.tracer = Some(tracer);
+            }
+            Err(e) => {
+                eprintln!("Unable to create {}: {}", trace, e);
+                process::exit(1);
+            }
+        }
+    }
+    drv.debugger.symbols = symbols;
+    if vram {
+        drv.open_vram();
+    }
+    if oam {
+        drv.open_oam();
+    }
+    drv.cpu.coverage = rom_coverage;
+    drv.coverage = coverage;
+    if profile.is_some() {
+        drv.cpu.profiler = Some(Profiler::new());
+        drv.profile = profile;
+    }
+    if let Some(port) = gdb_port {
+        match GdbStub::listen(port) {
+            Ok(gdb) => {
+                println!("Waiting for GDB on port {}", gdb.port());
+                drv.gdb = Some(gdb);
+            }
+            Err(e) => {
+                eprintln!("Unable to listen on port {}: {}", port, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(port) = dap_port {
+        match DapServer::listen(port) {
+            Ok(dap) => {
+                println!("Waiting for a debug adapter client on port {}", dap.port());
+                drv.dap = Some(dap);
+            }
+            Err(e) => {
+                eprintln!("Unable to listen on port {}: {}", port, e);
+                process::exit(1);
+            }
+        }
+    }
 
-    println!("Reminder that CPU is started in single step mode.");
+    println!("The CPU starts stopped, enter continue to run it or help for the other commands");
     drv.drive();
 }
@@ -1,2 +1,6 @@
 pub mod audio;
+pub mod dma;
+pub mod joypad;
+pub mod serial;
+pub mod timer;
 pub mod video;
@@ -13,6 +13,10 @@ pub const DUTY_CYCLE_10: [bool; 8] = [true, true, false, false, false, false, tr
 /// The 75% duty cycle square wave. true is a signal, false is no signal
 pub const DUTY_CYCLE_11: [bool; 8] = [false, true, true, true, true, true, true, false];
 
+/// Clock cycles between steps of the 512Hz frame sequencer
+pub const FRAME_SEQUENCER_CYCLES: u64 = 8192;
+
+#[derive(Copy, Clone)]
 pub enum SelectedSquareWaveCycle {
     DutyCycle12_5,
     DutyCycle25,
@@ -23,31 +27,88 @@ pub enum SelectedSquareWaveCycle {
 pub struct AudioDrv {
     ch2_seq_ptr: usize,
     ch2_sel_cycle: SelectedSquareWaveCycle,
-    sdl_queue: AudioQueue<u8>,
+    /// The output queue, or None when running headless
+    sdl_queue: Option<AudioQueue<u8>>,
     init_timer: u32,
     timer: u32,
+    /// The step of the 512Hz frame sequencer, which clocks the length counters, sweep and envelopes
+    frame_seq_step: u8,
+    ch2_length: u8,
 }
 
 impl AudioDrv {
-    pub fn new(sdl: &Sdl) -> AudioDrv {
-        let subsys = sdl.audio().unwrap();
-        let desspec = AudioSpecDesired {
-            freq: Some(44_100),
-            channels: Some(2),
-            samples: Some(4096),
-        };
-
-        let queue = subsys.open_queue(None, &desspec).unwrap();
+    pub fn new(sdl: Option<&Sdl>) -> AudioDrv {
+        let queue = sdl.map(|sdl| {
+            let subsys = sdl.audio().unwrap();
+            let desspec = AudioSpecDesired {
+                freq: Some(44_100),
+                channels: Some(2),
+                samples: Some(4096),
+            };
+            subsys.open_queue(None, &desspec).unwrap()
+        });
         AudioDrv {
             ch2_seq_ptr: 0,
             ch2_sel_cycle: SelectedSquareWaveCycle::DutyCycle50,
             sdl_queue: queue,
             init_timer: 0,
             timer: 0,
+            frame_seq_step: 0,
+            ch2_length: 0,
+        }
+    }
+
+    /// Copies the state of the channels, without the output queue
+    pub fn snapshot(&self) -> AudioDrv {
+        AudioDrv {
+            ch2_seq_ptr: self.ch2_seq_ptr,
+            ch2_sel_cycle: self.ch2_sel_cycle,
+            sdl_queue: None,
+            init_timer: self.init_timer,
+            timer: self.timer,
+            frame_seq_step: self.frame_seq_step,
+            ch2_length: self.ch2_length,
+        }
+    }
+
+    /// Restores the state of the channels from a snapshot, keeping the output queue
+    pub fn restore(&mut self, from: &AudioDrv) {
+        let sdl_queue = self.sdl_queue.take();
+        *self = from.snapshot();
+        self.sdl_queue = sdl_queue;
+    }
+
+    /// Handles a write to NR24. Setting bit 7 triggers channel 2, reloading its length counter from NR21
+    pub fn write_nr24(&mut self, mem: &mut Memory, value: u8) {
+        mem.set_register(MemoryRegister::NR24, value);
+        if value & 0x80 != 0 {
+            let nr21 = mem.get_register(MemoryRegister::NR21);
+            self.ch2_length = 64 - (nr21 & 0x3F);
+            let nr52 = mem.get_register(MemoryRegister::NR52);
+            mem.set_register(MemoryRegister::NR52, nr52 | 0x02);
+        }
+    }
+
+    /// Advances the frame sequencer by one step. Called at 512Hz
+    pub fn step_frame_sequencer(&mut self, mem: &mut Memory) {
+        self.frame_seq_step = (self.frame_seq_step + 1) % 8;
+
+        // Length counters are clocked on every other step, disabling their channel when they expire
+        let length_enabled = mem.get_register(MemoryRegister::NR24) & 0x40 != 0;
+        if self.frame_seq_step & 1 == 0 && length_enabled && self.ch2_length != 0 {
+            self.ch2_length -= 1;
+            if self.ch2_length == 0 {
+                let nr52 = mem.get_register(MemoryRegister::NR52);
+                mem.set_register(MemoryRegister::NR52, nr52 & !0x02);
+            }
         }
     }
 
     pub fn tick(&mut self, mem: &Memory) {
+        let sdl_queue = match &mut self.sdl_queue {
+            Some(queue) => queue,
+            None => return,
+        };
         let freq_low = mem.get_register(MemoryRegister::NR23);
         let nr21 = mem.get_register(MemoryRegister::NR21);
         let nr24 = mem.get_register(MemoryRegister::NR24);
@@ -58,9 +119,9 @@ impl AudioDrv {
         let timer = 131072 / (2048 - x as u32);
         let volume = (nr22 & 0xf0) >> 4;
 
-        if volume == 0 && self.sdl_queue.status() == AudioStatus::Playing {
-            self.sdl_queue.pause();
-            self.sdl_queue.clear();
+        if volume == 0 && sdl_queue.status() == AudioStatus::Playing {
+            sdl_queue.pause();
+            sdl_queue.clear();
         }
 
         if volume != 0 {
@@ -76,7 +137,7 @@ impl AudioDrv {
                     0b11 => SelectedSquareWaveCycle::DutyCycle75,
                     _ => unreachable!(),
                 };
-                self.sdl_queue.resume();
+                sdl_queue.resume();
             }
 
             self.timer -= 1;
@@ -89,9 +150,9 @@ impl AudioDrv {
             };
 
             if bit {
-                self.sdl_queue.queue(&[volume]);
+                sdl_queue.queue(&[volume]);
             } else {
-                self.sdl_queue.queue(&[0]);
+                sdl_queue.queue(&[0]);
             }
         }
     }
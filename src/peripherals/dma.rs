@@ -0,0 +1,78 @@
+use crate::cpu::mem::Memory;
+use crate::debug::watch::Accessor;
+
+/// The number of bytes copied into OAM by a single transfer
+const OAM_SIZE: u16 = 0xA0;
+
+#[derive(Clone)]
+pub struct DmaDrv {
+    source: u16,
+    /// The next byte to copy
+    index: u16,
+    /// The cycle the first byte is copied on. Transfers start one M-cycle after DMA is written
+    start: u64,
+    active: bool,
+}
+
+impl DmaDrv {
+    pub fn new() -> DmaDrv {
+        DmaDrv {
+            source: 0,
+            index: 0,
+            start: 0,
+            active: false,
+        }
+    }
+
+    /// Starts an OAM DMA transfer from $XX00, in response to a write of $XX to the DMA register
+    /// Returns the cycle the first byte is copied on, at which point `step` should be called
+    pub fn start(&mut self, value: u8, now: u64) -> u64 {
+        self.source = (value as u16) << 8;
+        self.index = 0;
+        self.start = now + 4;
+        self.active = true;
+        self.start
+    }
+
+    /// Whether a transfer is in progress. While it is, the CPU can only access $FF00-$FFFF
+    pub fn active(&self, now: u64) -> bool {
+        self.active && now >= self.start
+    }
+
+    /// Copies the next byte into OAM, taking one M-cycle.
+    /// Returns true if there are more to copy, in which case this should be called again on the next M-cycle
+    pub fn step(&mut self, mem: &mut Memory) -> bool {
+        let value = mem.read(self.source + self.index, Accessor::Dma);
+        mem.write(0xFE00 + self.index, value, Accessor::Dma);
+        self.index += 1;
+        self.active = self.index < OAM_SIZE;
+        self.active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_dma() {
+        let mut cpu = CPU::headless();
+        for i in 0..0xA0 {
+            cpu.mem.set_addr(0xC000 + i, i as u8 + 1);
+        }
+        // The write lands on cycle 4, and a byte is copied on each M-cycle after it
+        cpu.write8(0xFF46, 0xC0);
+        cpu.cycle();
+        assert_eq!(cpu.mem.buffer[0xFE00..0xFE02], [1, 0]);
+        cpu.cycle();
+        assert_eq!(cpu.mem.buffer[0xFE00..0xFE03], [1, 2, 0]);
+        // Only the high page can be read until the transfer is done
+        assert_eq!(cpu.read8(0xC000), 0xFF);
+        while cpu.dma.active(cpu.state.cycles) {
+            cpu.cycle();
+        }
+        assert_eq!(cpu.state.cycles, 8 + 4 * 0x9F);
+        assert_eq!(cpu.mem.buffer[0xFE00..0xFEA0], cpu.mem.buffer[0xC000..0xC0A0]);
+        assert_eq!(cpu.read8(0xC000), 1);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::cpu::int::Interrupt;
+use crate::cpu::mem::{Memory, MemoryRegister};
+use crate::util::check_bit;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+#[derive(Clone)]
+pub struct JoypadDrv {
+    /// Currently held buttons, one bit per `Button`. The low nibble holds the direction keys and the high nibble the action keys
+    pressed: u8,
+    /// The input lines last reflected into P1, used to detect high to low transitions
+    lines: u8,
+}
+
+impl JoypadDrv {
+    pub fn new() -> JoypadDrv {
+        JoypadDrv {
+            pressed: 0,
+            lines: 0x0F,
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.pressed |= 1 << button as u8;
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !(1 << button as u8);
+    }
+
+    /// The held buttons, one bit per `Button`
+    pub fn pressed(&self) -> u8 {
+        self.pressed
+    }
+
+    pub fn set_pressed(&mut self, pressed: u8) {
+        self.pressed = pressed;
+    }
+
+    /// Reflects the held buttons into the low nibble of P1 for whichever key groups are selected by bits 4 and 5.
+    /// If this function returns Some, the caller should dispatch INT $60 (JOYPAD)
+    pub fn update(&mut self, mem: &mut Memory) -> Option<Interrupt> {
+        let p1 = mem.get_register(MemoryRegister::P1);
+
+        let mut lines = 0x0F;
+        if !check_bit(p1, 4) {
+            lines &= !(self.pressed & 0x0F);
+        }
+        if !check_bit(p1, 5) {
+            lines &= !(self.pressed >> 4);
+        }
+        mem.set_register(MemoryRegister::P1, 0xC0 | (p1 & 0x30) | lines);
+
+        // The interrupt is requested whenever one of the input lines goes from high to low
+        let fell = self.lines & !lines;
+        self.lines = lines;
+        if fell != 0 {
+            Some(Interrupt::Joypad)
+        } else {
+            None
+        }
+    }
+
+    /// Whether any of the selected input lines are currently low. This is what wakes the CPU from STOP
+    pub fn line_low(&self) -> bool {
+        self.lines != 0x0F
+    }
+}
@@ -0,0 +1,39 @@
+use crate::cpu::int::Interrupt;
+use crate::cpu::mem::{Memory, MemoryRegister};
+
+/// Clock cycles taken to shift out a byte with the internal 8192Hz clock
+pub const TRANSFER_CYCLES: u64 = 8 * 512;
+
+#[derive(Clone)]
+pub struct SerialDrv {
+    /// Every byte sent over the link cable with the internal clock
+    pub output: Vec<u8>,
+}
+
+impl SerialDrv {
+    pub fn new() -> SerialDrv {
+        SerialDrv { output: Vec::new() }
+    }
+
+    /// Handles a write to SC.
+    /// Returns true if a transfer was started using the internal clock, in which case the caller should schedule
+    /// its completion in `TRANSFER_CYCLES`
+    pub fn write_sc(&mut self, mem: &mut Memory, value: u8) -> bool {
+        mem.set_register(MemoryRegister::SC, 0x7E | value);
+        if value & 0x81 == 0x81 {
+            self.output.push(mem.get_register(MemoryRegister::SB));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Completes a transfer. No link partner is connected, so $FF is shifted in
+    /// The caller should dispatch INT $58 (SERIAL)
+    pub fn complete(&mut self, mem: &mut Memory) -> Interrupt {
+        mem.set_register(MemoryRegister::SB, 0xFF);
+        let sc = mem.get_register(MemoryRegister::SC);
+        mem.set_register(MemoryRegister::SC, sc & !0x80);
+        Interrupt::Serial
+    }
+}
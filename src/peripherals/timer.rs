@@ -0,0 +1,185 @@
+use crate::cpu::int::Interrupt;
+use crate::cpu::mem::{Memory, MemoryRegister};
+use crate::util::check_bit;
+
+/// The timer is not ticked, instead DIV and TIMA are derived from the clock whenever they are accessed,
+/// and TIMA overflows are scheduled ahead of time.
+#[derive(Clone)]
+pub struct TimerDrv {
+    /// The cycle on which the internal 16 bit system counter was last zero. DIV is the upper byte of the counter
+    div_base: u64,
+    /// The cycle TIMA was last brought up to date on
+    last_sync: u64,
+}
+
+impl TimerDrv {
+    pub fn new() -> TimerDrv {
+        TimerDrv {
+            div_base: 0,
+            last_sync: 0,
+        }
+    }
+
+    /// The number of clock cycles between increments of TIMA, or None if the timer is disabled
+    fn period(tac: u8) -> Option<u64> {
+        if !check_bit(tac, 2) {
+            return None;
+        }
+        Some(match tac & 0x3 {
+            0 => 1024,
+            1 => 16,
+            2 => 64,
+            3 => 256,
+            _ => unreachable!(),
+        })
+    }
+
+    fn counter(&self, now: u64) -> u64 {
+        now - self.div_base
+    }
+
+    /// The signal fed into the falling edge detector that increments TIMA, which is the counter bit selected by TAC
+    /// ANDed with the timer enable bit
+    fn input(&self, now: u64, tac: u8) -> bool {
+        match Self::period(tac) {
+            Some(period) => self.counter(now) & (period / 2) != 0,
+            None => false,
+        }
+    }
+
+    /// Brings DIV and TIMA up to date with the clock
+    pub fn sync(&mut self, mem: &mut Memory, now: u64) {
+        if let Some(period) = Self::period(mem.get_register(MemoryRegister::TAC)) {
+            let increments = self.counter(now) / period - self.counter(self.last_sync) / period;
+            let tima = mem.get_register(MemoryRegister::TIMA) as u64 + increments;
+            // An overflow always has an event scheduled, so wrapping here only happens in the M-cycle before the reload
+            mem.set_register(MemoryRegister::TIMA, tima as u8);
+        }
+        self.last_sync = now;
+        mem.set_register(MemoryRegister::DIV, (self.counter(now) >> 8) as u8);
+    }
+
+    /// Returns the cycle on which TIMA will next be reloaded from TMA, if the timer is running.
+    /// The reload happens one M-cycle after the increment that overflows TIMA
+    pub fn next_overflow(&self, mem: &Memory, now: u64) -> Option<u64> {
+        let period = Self::period(mem.get_register(MemoryRegister::TAC))?;
+        let remaining = 0x100 - mem.get_register(MemoryRegister::TIMA) as u64;
+
+        let first = (self.counter(now) / period + 1) * period;
+        Some(self.div_base + first + (remaining - 1) * period + 4)
+    }
+
+    /// Reloads TIMA from TMA after an overflow
+    /// The caller should dispatch INT $50 (TIMER) and schedule the next overflow
+    pub fn overflow(&mut self, mem: &mut Memory, now: u64) -> Interrupt {
+        self.sync(mem, now);
+        let tma = mem.get_register(MemoryRegister::TMA);
+        mem.set_register(MemoryRegister::TIMA, tma);
+        Interrupt::Timer
+    }
+
+    /// Handles a write to DIV, which resets the whole system counter
+    /// If this function returns Some, the caller should dispatch INT $50 (TIMER)
+    pub fn reset_div(&mut self, mem: &mut Memory, now: u64) -> Option<Interrupt> {
+        self.sync(mem, now);
+        let tac = mem.get_register(MemoryRegister::TAC);
+        let int = if self.input(now, tac) {
+            // Resetting the counter while the selected bit is high is seen as a falling edge
+            self.increment(mem)
+        } else {
+            None
+        };
+        self.div_base = now;
+        self.last_sync = now;
+        mem.set_register(MemoryRegister::DIV, 0);
+        int
+    }
+
+    /// Handles a write to TAC. Changing the selected bit or disabling the timer can itself cause TIMA to increment
+    /// If this function returns Some, the caller should dispatch INT $50 (TIMER)
+    pub fn write_tac(&mut self, mem: &mut Memory, now: u64, value: u8) -> Option<Interrupt> {
+        self.sync(mem, now);
+        let old = self.input(now, mem.get_register(MemoryRegister::TAC));
+        mem.set_register(MemoryRegister::TAC, 0xF8 | value);
+        if old && !self.input(now, value) {
+            self.increment(mem)
+        } else {
+            None
+        }
+    }
+
+    /// Handles a write to TIMA
+    pub fn write_tima(&mut self, mem: &mut Memory, now: u64, value: u8) {
+        self.sync(mem, now);
+        mem.set_register(MemoryRegister::TIMA, value);
+    }
+
+    /// Increments TIMA outside of the regular schedule, reloading it immediately if it overflows
+    fn increment(&mut self, mem: &mut Memory) -> Option<Interrupt> {
+        let (tima, overflow) = mem.get_register(MemoryRegister::TIMA).overflowing_add(1);
+        if overflow {
+            mem.set_register(MemoryRegister::TIMA, mem.get_register(MemoryRegister::TMA));
+            Some(Interrupt::Timer)
+        } else {
+            mem.set_register(MemoryRegister::TIMA, tima);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::mem::MemoryRegister;
+    use crate::cpu::CPU;
+
+    fn run_to(cpu: &mut CPU, cycles: u64) {
+        while cpu.state.cycles < cycles {
+            cpu.cycle();
+        }
+        cpu.timer.sync(&mut cpu.mem, cpu.state.cycles);
+    }
+
+    #[test]
+    fn test_timer_overflow() {
+        let mut cpu = CPU::headless();
+        let tima = |cpu: &CPU| cpu.mem.get_register(MemoryRegister::TIMA);
+        let requested = |cpu: &CPU| cpu.mem.get_register(MemoryRegister::IF) & 0x04 != 0;
+
+        // Writes land on cycles 4, 8 and 12, so TIMA increments on cycles 16 and 32 every 16 cycles
+        cpu.write8(0xFF06, 0x10);
+        cpu.write8(0xFF05, 0xFE);
+        cpu.write8(0xFF07, 0x05);
+        run_to(&mut cpu, 28);
+        assert_eq!(tima(&cpu), 0xFF);
+
+        // TIMA reads 0 for the M-cycle after it overflows, then is reloaded from TMA and requests the interrupt
+        run_to(&mut cpu, 32);
+        assert_eq!((tima(&cpu), requested(&cpu)), (0x00, false));
+        run_to(&mut cpu, 36);
+        assert_eq!((tima(&cpu), requested(&cpu)), (0x10, true));
+        run_to(&mut cpu, 32 + 16 * 0xF0);
+        assert_eq!(tima(&cpu), 0x00);
+        run_to(&mut cpu, 36 + 16 * 0xF0);
+        assert_eq!(tima(&cpu), 0x10);
+
+        // Writing TIMA moves the pending overflow
+        cpu.mem.set_register(MemoryRegister::IF, 0);
+        let now = cpu.state.cycles;
+        cpu.write8(0xFF05, 0xFF);
+        cpu.write8(0xFF05, 0x80);
+        run_to(&mut cpu, now + 16 * 0x40);
+        assert!(!requested(&cpu));
+        assert!(tima(&cpu) >= 0xBF);
+        run_to(&mut cpu, now + 16 * 0x82);
+        assert!(requested(&cpu));
+
+        // And stopping the timer cancels it. Stopping it may itself increment TIMA, if the selected bit was set
+        cpu.mem.set_register(MemoryRegister::IF, 0);
+        cpu.write8(0xFF05, 0xF0);
+        cpu.write8(0xFF07, 0x01);
+        let (now, stopped) = (cpu.state.cycles, tima(&cpu));
+        assert!(stopped == 0xF0 || stopped == 0xF1);
+        run_to(&mut cpu, now + 16 * 0x100);
+        assert_eq!((tima(&cpu), requested(&cpu)), (stopped, false));
+    }
+}
@@ -1,6 +1,7 @@
+pub mod oam;
+
 use crate::cpu::int::Interrupt;
 use crate::cpu::mem::{Memory, MemoryRegister};
-use crate::cpu::CPU;
 use crate::util::{check_bit, color_to_sdl};
 use sdl2::pixels::Color;
 use sdl2::rect::{Point, Rect};
@@ -8,6 +9,25 @@ use sdl2::render::WindowCanvas;
 use sdl2::Sdl;
 use sdl2::VideoSubsystem;
 
+/// The size of the LCD in pixels
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// Dots spent searching OAM at the start of each visible line
+const OAM_SCAN_DOTS: u32 = 80;
+/// Dots spent transferring pixels to the LCD
+const DRAWING_DOTS: u32 = 172;
+/// Dots left in the line once drawing has finished
+const HBLANK_DOTS: u32 = 204;
+/// Dots in a full line
+const LINE_DOTS: u32 = 456;
+/// The first line of the vertical blanking period
+const VBLANK_LINE: u8 = 144;
+/// The number of lines, including the 10 lines of vertical blanking
+const LINES: u8 = 154;
+/// Dots in a full frame, including vertical blanking
+pub const FRAME_DOTS: u64 = LINE_DOTS as u64 * LINES as u64;
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum GbColor {
     White,
@@ -21,110 +41,251 @@ pub enum BGTileIndexingMethod {
     Signed8800,
 }
 
+/// The mode the PPU is in, as reported in the bottom two bits of STAT
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum PpuMode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// The shade each colour number is given by a palette register such as BGP, two bits per colour
+pub fn shades(palette: u8) -> [GbColor; 4] {
+    let mut shades = [GbColor::White; 4];
+    for (i, shade) in shades.iter_mut().enumerate() {
+        *shade = match (palette >> (2 * i)) & 0b11 {
+            0 => GbColor::White,
+            1 => GbColor::LightGray,
+            2 => GbColor::DarkGray,
+            _ => GbColor::Black,
+        };
+    }
+    shades
+}
+
+/// The address of a tile's data, by its index into the 384 tiles
+pub fn tile_addr(index: usize) -> u16 {
+    0x8000 + 16 * index as u16
+}
+
+/// The colour number of a pixel of a tile
+pub fn tile_pixel(mem: &Memory, index: usize, x: usize, y: usize) -> u8 {
+    let row = tile_addr(index) + 2 * y as u16;
+    let bit = 7 - x;
+    let low = mem.get_addr(row) >> bit & 1;
+    let high = mem.get_addr(row + 1) >> bit & 1;
+    low | high << 1
+}
+
 pub struct VideoDrv {
-    video: VideoSubsystem,
-    canvas: WindowCanvas,
+    /// The window and its canvas, or None when running headless
+    video: Option<VideoSubsystem>,
+    canvas: Option<WindowCanvas>,
     bg_palette: [GbColor; 4],
     sprite0_palette: [GbColor; 3],
     sprite1_palette: [GbColor; 3],
-    pub hblank_acc: u16,
-    pub vblank_acc: u16,
-    tile_buffer: Vec<(u8, u16)>,
+    pub mode: PpuMode,
+    /// The shade of every pixel on the LCD, row by row. Updated as each line is drawn
+    pub framebuffer: Box<[GbColor; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    /// Set when the LCD enters vblank, signalling that a full frame has been scanned. Cleared by the frontend
+    pub frame_ready: bool,
+    /// The state of the STAT interrupt line. The interrupt is only requested on its rising edge
+    stat_line: bool,
     scale_factor: i32,
-    last_origin: (u16, u16),
     disabled: bool,
+    /// Outlines sprites on the LCD at the end of each frame, in red where the limit per line drops them
+    pub sprite_overlay: bool,
 }
 
 impl VideoDrv {
-    pub fn new(sdl: &Sdl) -> VideoDrv {
+    pub fn new(sdl: Option<&Sdl>) -> VideoDrv {
         let scale_factor = 4;
-        let video = sdl.video().unwrap();
-        let mut wnd = video
-            .window("gbemu", 160 * scale_factor, 144 * scale_factor)
-            .position_centered()
-            .build()
-            .unwrap();
-        wnd.show();
-        let mut canvas = wnd.into_canvas().present_vsync().build().unwrap();
-        canvas.set_draw_color(Color::WHITE);
-        canvas.clear();
-        canvas.present();
+        let (video, canvas) = match sdl {
+            Some(sdl) => {
+                let video = sdl.video().unwrap();
+                let mut wnd = video
+                    .window(
+                        "gbemu",
+                        SCREEN_WIDTH as u32 * scale_factor,
+                        SCREEN_HEIGHT as u32 * scale_factor,
+                    )
+                    .position_centered()
+                    .build()
+                    .unwrap();
+                wnd.show();
+                let mut canvas = wnd.into_canvas().present_vsync().build().unwrap();
+                canvas.set_draw_color(Color::WHITE);
+                canvas.clear();
+                canvas.present();
+                (Some(video), Some(canvas))
+            }
+            None => (None, None),
+        };
         VideoDrv {
             video,
             canvas,
             bg_palette: [GbColor::White; 4],
             sprite0_palette: [GbColor::White; 3],
             sprite1_palette: [GbColor::White; 3],
-            hblank_acc: 0,
-            vblank_acc: 0,
-            tile_buffer: Vec::new(),
+            mode: PpuMode::HBlank,
+            framebuffer: Box::new([GbColor::White; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            frame_ready: false,
+            stat_line: false,
             scale_factor: scale_factor as i32,
-            last_origin: (0, 0),
             disabled: true,
+            sprite_overlay: false,
         }
     }
 
-    /// Scans a line
-    /// If this function returns true, the caller should dispatch INT $40 (VBLANK)
-    pub fn tick(&mut self, mem: &mut Memory) -> Option<Interrupt> {
-        if self.hblank_acc != 0 {
-            self.hblank_acc -= 1;
-            return None;
+    /// Copies the state of the PPU, without the window
+    pub fn snapshot(&self) -> VideoDrv {
+        VideoDrv {
+            video: None,
+            canvas: None,
+            bg_palette: self.bg_palette,
+            sprite0_palette: self.sprite0_palette,
+            sprite1_palette: self.sprite1_palette,
+            mode: self.mode,
+            framebuffer: self.framebuffer.clone(),
+            frame_ready: self.frame_ready,
+            stat_line: self.stat_line,
+            scale_factor: self.scale_factor,
+            disabled: self.disabled,
+            sprite_overlay: self.sprite_overlay,
         }
-        if self.vblank_acc != 0 {
-            if self.vblank_acc == 1 {
-                self.canvas.present();
+    }
+
+    /// Restores the state of the PPU from a snapshot, keeping the window
+    pub fn restore(&mut self, from: &VideoDrv) {
+        let (video, canvas) = (self.video.take(), self.canvas.take());
+        *self = from.snapshot();
+        self.video = video;
+        self.canvas = canvas;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled
+    }
+
+    /// Turns the LCD on in response to LCDC bit 7 being set, starting the OAM scan of line 0
+    /// Returns the number of dots until `step` should be called
+    pub fn enable(&mut self, mem: &mut Memory) -> u32 {
+        self.disabled = false;
+        mem.set_register(MemoryRegister::LY, 0);
+        self.set_mode(mem, PpuMode::OamScan);
+        OAM_SCAN_DOTS
+    }
+
+    /// Turns the LCD off in response to LCDC bit 7 being cleared. LY is held at 0 and the screen is blanked
+    pub fn disable(&mut self, mem: &mut Memory) {
+        self.disabled = true;
+        mem.set_register(MemoryRegister::LY, 0);
+        self.set_mode(mem, PpuMode::HBlank);
+        self.framebuffer.fill(GbColor::White);
+        if let Some(canvas) = &mut self.canvas {
+            canvas.set_draw_color(Color::WHITE);
+            canvas.clear();
+            canvas.present();
+        }
+    }
+
+    /// Ends the current mode and enters the next one, drawing a line when pixel transfer begins.
+    /// Returns the number of dots until this should be called again, and the interrupts to dispatch.
+    /// The first is INT $40 (VBLANK), the second INT $48 (LCDSTAT)
+    pub fn step(&mut self, mem: &mut Memory) -> (u32, [Option<Interrupt>; 2]) {
+        let line = mem.get_register(MemoryRegister::LY);
+
+        let (dots, vblank) = match self.mode {
+            PpuMode::OamScan => {
+                self.draw_line(mem, line);
+                self.set_mode(mem, PpuMode::Drawing);
+                (DRAWING_DOTS, None)
             }
-            self.hblank_acc = 456;
-            self.vblank_acc -= 1;
-            if self.vblank_acc == 0 {
-                // Reset LY if this is the end of the vblank period
-                mem.set_register(MemoryRegister::LY, 0);
-            } else {
-                // Increment LY if there's still blanking to go
-                let l = mem.get_register(MemoryRegister::LY);
-                mem.set_register(MemoryRegister::LY, l + 1);
+            PpuMode::Drawing => {
+                self.set_mode(mem, PpuMode::HBlank);
+                (HBLANK_DOTS, None)
             }
-            return None;
-        }
-        let lcdc = mem.get_register(MemoryRegister::LCDC);
+            PpuMode::HBlank => {
+                mem.set_register(MemoryRegister::LY, line + 1);
+                if line + 1 == VBLANK_LINE {
+                    if self.sprite_overlay {
+                        self.draw_sprite_overlay(mem);
+                    }
+                    if let Some(canvas) = &mut self.canvas {
+                        canvas.present();
+                    }
+                    self.frame_ready = true;
+                    self.set_mode(mem, PpuMode::VBlank);
+                    (LINE_DOTS, Some(Interrupt::Vblank))
+                } else {
+                    self.set_mode(mem, PpuMode::OamScan);
+                    (OAM_SCAN_DOTS, None)
+                }
+            }
+            PpuMode::VBlank => {
+                if line + 1 == LINES {
+                    mem.set_register(MemoryRegister::LY, 0);
+                    self.set_mode(mem, PpuMode::OamScan);
+                    (OAM_SCAN_DOTS, None)
+                } else {
+                    mem.set_register(MemoryRegister::LY, line + 1);
+                    (LINE_DOTS, None)
+                }
+            }
+        };
+
+        (dots, [vblank, self.update_stat(mem)])
+    }
+
+    fn set_mode(&mut self, mem: &mut Memory, mode: PpuMode) {
+        self.mode = mode;
         let stat = mem.get_register(MemoryRegister::STAT);
-        let bgp = mem.get_register(MemoryRegister::BGP);
+        mem.set_register(MemoryRegister::STAT, (stat & !0x03) | mode as u8);
+    }
 
-        let hblank_int = check_bit(stat, 3);
-        let lyc_int = check_bit(stat, 6);
-
-        // LCD is disabled
-        if !check_bit(lcdc, 7) {
-            if !self.disabled {
-                // Clear the screen and exit
-                self.canvas.set_draw_color(Color::WHITE);
-                self.canvas.clear();
-                self.canvas.present();
-                self.disabled = true;
-            }
-            return None;
+    /// Updates the coincidence flag in STAT, and requests INT $48 (LCDSTAT) if any of the enabled
+    /// STAT interrupt sources has just become active
+    fn update_stat(&mut self, mem: &mut Memory) -> Option<Interrupt> {
+        let mut stat = mem.get_register(MemoryRegister::STAT);
+        let coincidence =
+            mem.get_register(MemoryRegister::LY) == mem.get_register(MemoryRegister::LYC);
+        if coincidence {
+            stat |= 1 << 2;
+        } else {
+            stat &= !(1 << 2);
         }
+        mem.set_register(MemoryRegister::STAT, stat | 0x80);
 
-        self.disabled = false;
-
-        let line = mem.get_register(MemoryRegister::LY);
-        let lyc = mem.get_register(MemoryRegister::LYC);
+        let line = (coincidence && check_bit(stat, 6))
+            || (self.mode == PpuMode::HBlank && check_bit(stat, 3))
+            || (self.mode == PpuMode::VBlank && check_bit(stat, 4))
+            || (self.mode == PpuMode::OamScan && check_bit(stat, 5));
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
 
-        if line != lyc {
-            // Clear coincidence bit
-            mem.set_register(MemoryRegister::STAT, stat & !(1 << 2));
+        if rising {
+            Some(Interrupt::LcdStat)
+        } else {
+            None
         }
+    }
+
+    /// Scans a line of the background and sprites into the canvas
+    fn draw_line(&mut self, mem: &Memory, line: u8) {
+        let lcdc = mem.get_register(MemoryRegister::LCDC);
+        let bgp = mem.get_register(MemoryRegister::BGP);
 
         self.update_bg_palette(bgp);
 
         // Get state from mmap registers. Offset of the screen, indexing mode, and tile map base address.
-        let scy = mem.get_register(MemoryRegister::SCY) as u16;
-        let scx = mem.get_register(MemoryRegister::SCX) as u16;
+        let scy = mem.get_register(MemoryRegister::SCY);
+        let scx = mem.get_register(MemoryRegister::SCX);
         let tile_map_base = if check_bit(lcdc, 3) {
-            0x9800
-        } else {
             0x9C00
+        } else {
+            0x9800
         };
         let indexing_mode = if check_bit(lcdc, 4) {
             BGTileIndexingMethod::Unsigned8000
@@ -132,122 +293,200 @@ impl VideoDrv {
             BGTileIndexingMethod::Signed8800
         };
 
-        // Find the address for the start of this scanline
-        let first_tile = 4 * scy + scx / 8;
+        // The line of the 256x256 background that is visible on this line of the screen
+        let y = line.wrapping_add(scy) as u16;
+
+        // The colour number of each background pixel, which sprites behind the background show through 0 of
+        let mut bg = [0; SCREEN_WIDTH];
+        let mut colors = [GbColor::White; SCREEN_WIDTH];
+
+        for x in 0..SCREEN_WIDTH as u16 {
+            let px = (x as u8).wrapping_add(scx) as u16;
 
-        let (old_scx, old_scy) = self.last_origin;
+            // With the background disabled it is drawn blank
+            if check_bit(lcdc, 0) {
+                // Get the tile from the tile map
+                let n = mem.get_addr(tile_map_base + (y / 8) * 32 + px / 8);
+                let tile = match indexing_mode {
+                    BGTileIndexingMethod::Unsigned8000 => 0x8000 + 16 * n as u16,
+                    BGTileIndexingMethod::Signed8800 => (0x9000 + 16 * (n as i8) as i32) as u16,
+                };
 
-        if line % 8 == 0 || old_scx != scx || old_scy != scy {
-            self.tile_buffer.clear();
-            // Push all the pending tiles to be handled as we continue scanning
-            for tile in first_tile..first_tile + 20 {
-                self.tile_buffer.push((line, tile));
+                // Figure out the offset into the tile, and get the 2 bytes for this line.
+                let line_start = tile + 2 * (y % 8);
+                let t1 = mem.get_addr(line_start);
+                let t2 = mem.get_addr(line_start + 1);
+
+                let bit = 7 - (px % 8);
+                let low = t1 >> bit & 1;
+                let high = t2 >> bit & 1;
+                bg[x as usize] = low | high << 1;
+                colors[x as usize] = self.bg_palette[bg[x as usize] as usize];
             }
         }
 
-        for (ly, map_addr) in self.tile_buffer.iter() {
-            // Get the tile from the tile map
-            let n = mem.get_addr(tile_map_base + *map_addr);
-            let tile = match indexing_mode {
-                BGTileIndexingMethod::Unsigned8000 => 0x8000 + n as u16,
-                BGTileIndexingMethod::Signed8800 => (0x9000 + n as i32) as u16,
-            };
-
-            // Figure out the offset into the tile, and get the 2 bytes for this line.
-            let line_start = tile + 2 * (line - *ly) as u16;
-            let t1 = mem.get_addr(line_start);
-            let t2 = mem.get_addr(line_start + 1);
+        if check_bit(lcdc, 1) {
+            self.draw_sprites(mem, line, &bg, &mut colors);
+        }
 
-            let mut line_data = [GbColor::White; 8];
+        for (x, c) in colors.iter().enumerate() {
+            self.framebuffer[line as usize * SCREEN_WIDTH + x] = *c;
 
-            // Go through the bytes for this line of tile data and get the color data from it
-            // store the color data in line_data.
-            for (j, i) in (0..8).rev().enumerate() {
-                let low = t1 >> i & 1;
-                let high = t2 >> i & 1;
-                let c = self.bg_palette[(low | high << 1) as usize];
-                line_data[j] = c;
+            let canvas = match &mut self.canvas {
+                Some(canvas) => canvas,
+                None => continue,
+            };
+            canvas.set_draw_color(color_to_sdl(*c));
+            if self.scale_factor == 1 {
+                let pt = Point::new(x as i32, line as i32);
+                canvas.draw_point(pt).unwrap();
+            } else {
+                let rect = Rect::new(
+                    self.scale_factor * x as i32,
+                    self.scale_factor * line as i32,
+                    self.scale_factor as u32,
+                    self.scale_factor as u32,
+                );
+                canvas.fill_rect(rect).unwrap();
             }
+        }
+    }
+
+    /// Draws the sprites on a line over the background. Of the ten the OAM scan selects, the one furthest left
+    /// is drawn on top, then the first in OAM. Its pixel is drawn unless transparent, or behind background
+    /// colours 1-3 if its priority bit is set, in which case it still hides the sprites below it
+    fn draw_sprites(&mut self, mem: &Memory, line: u8, bg: &[u8; SCREEN_WIDTH], colors: &mut [GbColor; SCREEN_WIDTH]) {
+        self.update_sprite_palettes(
+            mem.get_register(MemoryRegister::OBP0),
+            mem.get_register(MemoryRegister::OBP1),
+        );
+        let height = oam::sprite_height(mem);
+        let sprites = oam::sprites(mem);
+        let mut selected = oam::line_sprites(&sprites, line, height);
+        selected.sort_by_key(|s| (s.x, s.index));
 
-            // Draw each pixel in line_data
-            for (i, c) in line_data.iter().enumerate() {
-                self.canvas.set_draw_color(color_to_sdl(*c));
-                let line_inset = (*map_addr - first_tile);
-                if self.scale_factor == 1 {
-                    let pt = Point::new(8 * line_inset as i32 + i as i32, line as i32);
-                    self.canvas.draw_point(pt).unwrap();
+        for x in 0..SCREEN_WIDTH {
+            let pixel = selected.iter().find_map(|s| {
+                let col = x as i16 - s.screen_x();
+                if !(0..8).contains(&col) {
+                    return None;
+                }
+                let c = s.pixel(mem, height, col as u8, (line as i16 - s.screen_y()) as u8);
+                if c == 0 {
+                    None
                 } else {
-                    let rect = Rect::new(
-                        self.scale_factor * (8 * line_inset as i32 + i as i32),
-                        self.scale_factor * line as i32,
-                        self.scale_factor as u32,
-                        self.scale_factor as u32,
-                    );
-                    self.canvas.fill_rect(rect).unwrap();
+                    Some((s, c))
+                }
+            });
+            if let Some((sprite, c)) = pixel {
+                if !(sprite.behind_bg() && bg[x] != 0) {
+                    let palette = if sprite.obp1() { &self.sprite1_palette } else { &self.sprite0_palette };
+                    colors[x] = palette[c as usize - 1];
                 }
             }
         }
+    }
 
-        self.last_origin = (scx, scy);
-
-        // Update LY to hold the next line that will be scanned.
-        return if line == 144 {
-            self.vblank_acc = 10;
-            mem.set_register(MemoryRegister::LY, line + 1);
-            self.tile_buffer.clear();
-            Some(Interrupt::Vblank)
-        } else {
-            self.hblank_acc = 204;
-            mem.set_register(MemoryRegister::LY, line + 1);
-            if line + 1 == lyc && lyc_int {
-                mem.set_register(MemoryRegister::STAT, stat | (1 << 2)); // Set coincience bit
-                return Some(Interrupt::LcdStat);
-            }
-            if hblank_int {
-                Some(Interrupt::LcdStat)
-            } else {
-                None
-            }
+    /// Outlines the sprites on the canvas. Drawn over the finished frame, as the next one redraws every pixel
+    fn draw_sprite_overlay(&mut self, mem: &Memory) {
+        let canvas = match &mut self.canvas {
+            Some(canvas) => canvas,
+            None => return,
         };
+        let height = oam::sprite_height(mem);
+        let sprites = oam::sprites(mem);
+        let dropped = oam::dropped_lines(&sprites, height);
+        let scale = self.scale_factor;
+        for sprite in sprites.iter().filter(|s| s.on_screen(height)) {
+            let color = if dropped[sprite.index] > 0 {
+                Color::RED
+            } else {
+                Color::GREEN
+            };
+            canvas.set_draw_color(color);
+            let rect = Rect::new(
+                scale * sprite.screen_x() as i32,
+                scale * sprite.screen_y() as i32,
+                8 * scale as u32,
+                height as u32 * scale as u32,
+            );
+            canvas.draw_rect(rect).unwrap();
+        }
     }
 
     /// Updates the internal palette for background colours based on
     /// the contents of the Background Palette (BGP) register
     fn update_bg_palette(&mut self, bgp: u8) {
-        let mask = 0b11;
-
-        for i in 0..4 {
-            let mask = mask << 2 * i as u8;
-            self.bg_palette[i] = match (bgp & mask) >> 2 * i as u8 {
-                0 => GbColor::White,
-                1 => GbColor::LightGray,
-                2 => GbColor::DarkGray,
-                3 => GbColor::Black,
-                _ => unreachable!(),
-            };
-        }
+        self.bg_palette = shades(bgp);
     }
 
+    /// Updates the internal palettes for sprite colours 1-3 from the Object Palette (OBP0 and OBP1) registers.
+    /// Colour 0 is transparent, so has no shade
     fn update_sprite_palettes(&mut self, obp0: u8, obp1: u8) {
-        let mut mask = 0b11 << 2;
-
-        for i in 0..3 {
-            mask <<= 2 * i as u8;
-            self.sprite0_palette[i] = match (obp0 & mask) >> (2 * i as u8 + 2) {
-                0 => GbColor::White,
-                1 => GbColor::LightGray,
-                2 => GbColor::DarkGray,
-                3 => GbColor::Black,
-                _ => unreachable!(),
-            };
+        self.sprite0_palette.copy_from_slice(&shades(obp0)[1..]);
+        self.sprite1_palette.copy_from_slice(&shades(obp1)[1..]);
+    }
+}
 
-            self.sprite1_palette[i] = match (obp1 & mask) >> (2 * i as u8 + 2) {
-                0 => GbColor::White,
-                1 => GbColor::LightGray,
-                2 => GbColor::DarkGray,
-                3 => GbColor::Black,
-                _ => unreachable!(),
-            };
+#[cfg(test)]
+mod tests {
+    use super::oam::OAM_START;
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_sprites() {
+        let mut cpu = CPU::headless();
+        let mem = &mut cpu.mem;
+        mem.set_register(MemoryRegister::BGP, 0b11_10_01_00);
+        mem.set_register(MemoryRegister::OBP0, 0b11_10_01_00);
+        mem.set_register(MemoryRegister::OBP1, 0b00_01_10_11);
+        // Tile 1 is solid colour 1, tile 2 colour 3 on its left half and transparent on its right,
+        // and tile 3 colour 2 on its top row
+        for i in 0..8 {
+            mem.set_addr(0x8010 + 2 * i, 0xFF);
+            mem.set_addr(0x8020 + 2 * i, 0xF0);
+            mem.set_addr(0x8021 + 2 * i, 0xF0);
         }
+        mem.set_addr(0x8031, 0xFF);
+        // The background is colour 0, except for the third tile along
+        mem.set_addr(0x9802, 1);
+        let mut sprite = |index: u16, x: i16, y: i16, tile: u8, attrs: u8| {
+            let addr = OAM_START + 4 * index;
+            mem.set_addr(addr, (y + 16) as u8);
+            mem.set_addr(addr + 1, (x + 8) as u8);
+            mem.set_addr(addr + 2, tile);
+            mem.set_addr(addr + 3, attrs);
+        };
+        // Overlapping sprites, where the one further left is on top even where the other is first in OAM
+        sprite(1, 0, 0, 2, 0x00);
+        sprite(0, 2, 0, 1, 0x10);
+        // Sprites behind the background, only seen where it is colour 0
+        sprite(2, 16, 0, 1, 0x90);
+        sprite(3, 24, 0, 1, 0x90);
+        // Eleven sprites on line 8, the last of which is dropped
+        for i in 0..11 {
+            sprite(4 + i, 40 + 8 * i as i16, 8, 1, 0x00);
+        }
+
+        let (video, mem) = (&mut cpu.video, &mut cpu.mem);
+        let (w, l, d, b) = (GbColor::White, GbColor::LightGray, GbColor::DarkGray, GbColor::Black);
+        mem.set_register(MemoryRegister::LCDC, 0x93);
+        video.draw_line(mem, 0);
+        video.draw_line(mem, 8);
+        assert_eq!(video.framebuffer[..10], [b, b, b, b, d, d, d, d, d, d]);
+        assert_eq!(video.framebuffer[16..32], [[l; 8], [d; 8]].concat()[..]);
+        let line8 = &video.framebuffer[8 * SCREEN_WIDTH..];
+        assert_eq!((line8[40], line8[112], line8[120]), (l, l, w));
+
+        // 8x16 sprites use the tile after theirs, rounded down to even, below it
+        mem.set_register(MemoryRegister::LCDC, 0x97);
+        video.draw_line(mem, 8);
+        assert_eq!(video.framebuffer[8 * SCREEN_WIDTH..][..4], [d; 4]);
+
+        // With sprites disabled only the background is drawn
+        mem.set_register(MemoryRegister::LCDC, 0x91);
+        video.draw_line(mem, 0);
+        assert_eq!(video.framebuffer[..24], [&[w; 16][..], &[l; 8]].concat()[..]);
     }
 }
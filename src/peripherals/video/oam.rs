@@ -0,0 +1,163 @@
+use super::tile_pixel;
+use crate::cpu::mem::{Memory, MemoryRegister};
+use crate::util::check_bit;
+
+/// Where OAM starts, holding 4 bytes for each sprite
+pub const OAM_START: u16 = 0xFE00;
+pub const SPRITE_COUNT: usize = 40;
+/// The most sprites the PPU selects for a line. Any more on the line are dropped
+pub const MAX_PER_LINE: usize = 10;
+
+/// An entry of OAM
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sprite {
+    pub index: usize,
+    /// The position of the bottom right corner of an 8x16 sprite, so a sprite at 0,0 is entirely off screen
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub attrs: u8,
+}
+
+/// The height of sprites, which LCDC bit 2 makes 16 instead of 8
+pub fn sprite_height(mem: &Memory) -> u8 {
+    if check_bit(mem.get_register(MemoryRegister::LCDC), 2) {
+        16
+    } else {
+        8
+    }
+}
+
+/// Reads all 40 entries from OAM
+pub fn sprites(mem: &Memory) -> Vec<Sprite> {
+    (0..SPRITE_COUNT)
+        .map(|index| {
+            let addr = OAM_START + 4 * index as u16;
+            Sprite {
+                index,
+                y: mem.get_addr(addr),
+                x: mem.get_addr(addr + 1),
+                tile: mem.get_addr(addr + 2),
+                attrs: mem.get_addr(addr + 3),
+            }
+        })
+        .collect()
+}
+
+/// The sprites selected for a line as the PPU does in its OAM scan: the first ten in OAM order that cover it.
+/// X is not considered, so sprites off the side of the screen still count towards the limit
+pub fn line_sprites(sprites: &[Sprite], line: u8, height: u8) -> Vec<&Sprite> {
+    sprites.iter().filter(|s| s.covers(line, height)).take(MAX_PER_LINE).collect()
+}
+
+/// The number of visible lines each sprite covers, but is dropped from by the limit of sprites per line
+pub fn dropped_lines(sprites: &[Sprite], height: u8) -> Vec<u8> {
+    let mut dropped = vec![0; sprites.len()];
+    for line in 0..144 {
+        let selected = line_sprites(sprites, line, height);
+        for sprite in sprites.iter().filter(|s| s.covers(line, height) && !selected.contains(s)) {
+            dropped[sprite.index] += 1;
+        }
+    }
+    dropped
+}
+
+impl Sprite {
+    /// Drawn behind background colours 1-3
+    pub fn behind_bg(&self) -> bool {
+        check_bit(self.attrs, 7)
+    }
+
+    pub fn flip_y(&self) -> bool {
+        check_bit(self.attrs, 6)
+    }
+
+    pub fn flip_x(&self) -> bool {
+        check_bit(self.attrs, 5)
+    }
+
+    /// Uses OBP1 rather than OBP0
+    pub fn obp1(&self) -> bool {
+        check_bit(self.attrs, 4)
+    }
+
+    /// The VRAM bank of the tile on CGB
+    pub fn cgb_bank(&self) -> u8 {
+        self.attrs >> 3 & 1
+    }
+
+    /// The colour palette on CGB
+    pub fn cgb_palette(&self) -> u8 {
+        self.attrs & 0b111
+    }
+
+    /// The position of the top left corner on the LCD
+    pub fn screen_x(&self) -> i16 {
+        self.x as i16 - 8
+    }
+
+    pub fn screen_y(&self) -> i16 {
+        self.y as i16 - 16
+    }
+
+    pub fn covers(&self, line: u8, height: u8) -> bool {
+        let row = line as i16 - self.screen_y();
+        (0..height as i16).contains(&row)
+    }
+
+    /// Whether any of the sprite is on the LCD
+    pub fn on_screen(&self, height: u8) -> bool {
+        self.screen_x() > -8 && self.screen_x() < 160 && self.screen_y() > -(height as i16) && self.screen_y() < 144
+    }
+
+    /// The colour number of a pixel of the sprite, counted from its top left corner as drawn, so after flipping.
+    /// Sprite tiles are always addressed from $8000, and 8x16 sprites ignore the bottom bit of the tile number
+    pub fn pixel(&self, mem: &Memory, height: u8, x: u8, y: u8) -> u8 {
+        let x = if self.flip_x() { 7 - x } else { x };
+        let y = if self.flip_y() { height - 1 - y } else { y };
+        let tile = if height == 16 { self.tile & 0xFE } else { self.tile };
+        tile_pixel(mem, tile as usize + y as usize / 8, x as usize, y as usize % 8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_oam() {
+        let mut cpu = CPU::headless();
+        let mem = &mut cpu.mem;
+        // Twelve sprites on the top line, the last two of them dropped. The first is off the side of the screen
+        for i in 0..12 {
+            mem.set_addr(OAM_START + 4 * i, 16);
+            mem.set_addr(OAM_START + 4 * i + 1, if i == 0 { 0 } else { 8 * i as u8 });
+        }
+        // A flipped 8x16 sprite using tiles 2 and 3, with the top left pixel of tile 3 set
+        mem.set_addr(OAM_START + 4 * 12, 40);
+        mem.set_addr(OAM_START + 4 * 12 + 1, 40);
+        mem.set_addr(OAM_START + 4 * 12 + 2, 3);
+        mem.set_addr(OAM_START + 4 * 12 + 3, 0b1111_0101);
+        mem.set_addr(0x8030, 0x80);
+        mem.set_addr(0x8031, 0x80);
+        mem.set_addr(0x8020, 0x80);
+
+        let sprites = sprites(mem);
+        assert_eq!(line_sprites(&sprites, 0, 8).len(), MAX_PER_LINE);
+        let dropped = dropped_lines(&sprites, 8);
+        assert_eq!(dropped[..12], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 8]);
+        assert!(!sprites[0].on_screen(8) && sprites[1].on_screen(8));
+
+        let sprite = sprites[12];
+        assert!(sprite.behind_bg() && sprite.flip_y() && sprite.flip_x() && sprite.obp1());
+        assert_eq!((sprite.cgb_bank(), sprite.cgb_palette()), (0, 5));
+        assert!(sprite.covers(24, 16) && sprite.covers(39, 16) && !sprite.covers(40, 16));
+        // Flipped both ways, the top left of each tile is drawn at the bottom right, the top tile at the bottom
+        assert_eq!(sprite.pixel(mem, 16, 7, 15), 1);
+        assert_eq!(sprite.pixel(mem, 16, 7, 7), 3);
+        assert_eq!(sprite.pixel(mem, 16, 0, 0), 0);
+        // As an 8x8 sprite it is just tile 3
+        assert_eq!(sprite.pixel(mem, 8, 7, 7), 3);
+    }
+}
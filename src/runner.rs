@@ -0,0 +1,388 @@
+use crate::cpu::CPU;
+use crate::peripherals::video::FRAME_DOTS;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The opcode of LD B,B, used by the Mooneye suites as a software breakpoint to signal the end of a test
+const LD_B_B: u8 = 0x40;
+/// The values of B, C, D, E, H and L that a Mooneye test leaves on success
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+/// The value every one of those registers is set to on failure
+const MOONEYE_FAIL: u8 = 0x42;
+/// Frames a ROM runs for before timing out, a little over 2 minutes of emulated time
+const DEFAULT_TIMEOUT_FRAMES: u64 = 7200;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    /// Clock cycles emulated before a result was reached
+    pub cycles: u64,
+    pub duration: Duration,
+    /// Everything the ROM sent over the serial port
+    pub serial: String,
+}
+
+pub struct Options {
+    /// Frames to run each ROM for before giving up
+    pub timeout_frames: u64,
+    /// The number of ROMs to run at once
+    pub jobs: usize,
+    /// Where to write a JUnit XML report, if anywhere
+    pub junit: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            timeout_frames: DEFAULT_TIMEOUT_FRAMES,
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            junit: None,
+        }
+    }
+}
+
+/// Runs a ROM headless from the cartridge entry point until it reports a result, or `timeout_frames` have passed.
+///
+/// Blargg's suites print their result over the serial port, so the output is watched for "Passed" or "Failed".
+/// Mooneye's suites execute LD B,B when done, leaving the Fibonacci numbers 3, 5, 8, 13, 21, 34 in B-L on success.
+/// Blargg's suites test LD B,B like any other instruction, so it only ends a ROM that hasn't used the serial port.
+/// Returns the outcome, the cycles emulated and the serial output
+pub fn run_rom(rom: Vec<u8>, timeout_frames: u64) -> (Outcome, u64, String) {
+    let mut cpu = CPU::headless();
+    cpu.load_code(rom);
+    cpu.skip_bootrom();
+
+    let timeout = timeout_frames * FRAME_DOTS;
+    let mut serial_len = 0;
+    let outcome = loop {
+        if cpu.state.cycles >= timeout {
+            break Outcome::TimedOut;
+        }
+
+        // The registers are checked before LD B,B executes, as it is the last thing the test does
+        if serial_len == 0 && !cpu.state.halted && cpu.mem.get_addr(cpu.reg.pc) == LD_B_B {
+            break mooneye_outcome(&cpu);
+        }
+
+        cpu.tick();
+
+        if cpu.serial.output.len() != serial_len {
+            serial_len = cpu.serial.output.len();
+            let serial = String::from_utf8_lossy(&cpu.serial.output);
+            if serial.contains("Passed") {
+                break Outcome::Passed;
+            }
+            if serial.contains("Failed") {
+                break Outcome::Failed(format!("Serial output: {}", serial.trim()));
+            }
+        }
+    };
+
+    let serial = String::from_utf8_lossy(&cpu.serial.output).to_string();
+    (outcome, cpu.state.cycles, serial)
+}
+
+fn mooneye_outcome(cpu: &CPU) -> Outcome {
+    let regs = [cpu.reg.bc, cpu.reg.de, cpu.reg.hl]
+        .iter()
+        .flat_map(|r| r.to_be_bytes())
+        .collect::<Vec<u8>>();
+    if regs == MOONEYE_PASS {
+        Outcome::Passed
+    } else if regs.iter().all(|r| *r == MOONEYE_FAIL) {
+        Outcome::Failed("Failure signature in registers".to_string())
+    } else {
+        Outcome::Failed(format!(
+            "Unexpected registers B={} C={} D={} E={} H={} L={}",
+            regs[0], regs[1], regs[2], regs[3], regs[4], regs[5]
+        ))
+    }
+}
+
+/// Runs every ROM, `jobs` at a time. Results are returned in the same order as `paths`
+pub fn run_all(paths: &[PathBuf], opts: &Options) -> Vec<TestResult> {
+    let queue = Arc::new(Mutex::new(paths.iter().cloned().enumerate().collect::<VecDeque<_>>()));
+    let (tx, rx) = mpsc::channel();
+
+    let workers = (0..opts.jobs.max(1).min(paths.len()))
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let tx = tx.clone();
+            let timeout_frames = opts.timeout_frames;
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().pop_front();
+                let (i, path) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                tx.send((i, run_path(&path, timeout_frames))).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(tx);
+
+    let mut results = rx.iter().collect::<Vec<(usize, TestResult)>>();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+fn run_path(path: &Path, timeout_frames: u64) -> TestResult {
+    let start = Instant::now();
+    let (outcome, cycles, serial) = match fs::read(path) {
+        Ok(rom) => run_rom(rom, timeout_frames),
+        Err(e) => (Outcome::Failed(format!("Unable to read ROM: {}", e)), 0, String::new()),
+    };
+    TestResult {
+        name: path.display().to_string(),
+        outcome,
+        cycles,
+        duration: start.elapsed(),
+        serial,
+    }
+}
+
+/// Finds every ROM in the given files and directories, searching directories recursively
+pub fn collect_roms(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)
+                .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            entries.sort();
+            let (dirs, files): (Vec<PathBuf>, Vec<PathBuf>) = entries.into_iter().partition(|p| p.is_dir());
+            roms.extend(files.into_iter().filter(|p| {
+                p.extension()
+                    .is_some_and(|ext| ext == "gb" || ext == "gbc")
+            }));
+            roms.extend(collect_roms(&dirs));
+        } else {
+            roms.push(path.clone());
+        }
+    }
+    roms
+}
+
+fn escape_xml(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&apos;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Renders the results as a JUnit XML report, with a timeout reported as a failure
+pub fn junit_xml(results: &[TestResult], timeout_frames: u64) -> String {
+    let failures = results.iter().filter(|r| r.outcome != Outcome::Passed).count();
+    let time = results.iter().map(|r| r.duration.as_secs_f64()).sum::<f64>();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += &format!(
+        "<testsuite name=\"gbemu\" tests=\"{}\" failures=\"{}\" errors=\"0\" time=\"{:.3}\">\n",
+        results.len(),
+        failures,
+        time
+    );
+    for r in results {
+        xml += &format!(
+            "  <testcase name=\"{}\" time=\"{:.3}\">\n",
+            escape_xml(&r.name),
+            r.duration.as_secs_f64()
+        );
+        let message = match &r.outcome {
+            Outcome::Passed => None,
+            Outcome::Failed(reason) => Some(reason.clone()),
+            Outcome::TimedOut => Some(format!("Timed out after {} frames", timeout_frames)),
+        };
+        if let Some(message) = message {
+            xml += &format!("    <failure message=\"{}\"/>\n", escape_xml(&message));
+        }
+        if !r.serial.is_empty() {
+            xml += &format!("    <system-out>{}</system-out>\n", escape_xml(&r.serial));
+        }
+        xml += "  </testcase>\n";
+    }
+    xml += "</testsuite>\n";
+    xml
+}
+
+/// Entry point for `gbemu test [--jobs N] [--timeout-frames N] [--junit FILE] <rom|dir>...`
+pub fn cli(args: &[String]) {
+    let usage = "usage: gbemu test [--jobs N] [--timeout-frames N] [--junit FILE] <rom|dir>...";
+    let mut opts = Options::default();
+    let mut paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| match args.next() {
+            Some(v) => v.clone(),
+            None => {
+                eprintln!("{} needs a value\n{}", name, usage);
+                process::exit(2);
+            }
+        };
+        let number = |name: &str, v: String| match v.parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("{} must be a number, not {}\n{}", name, v, usage);
+                process::exit(2);
+            }
+        };
+        match arg.as_str() {
+            "--jobs" | "-j" => opts.jobs = number(arg, value(arg)) as usize,
+            "--timeout-frames" => opts.timeout_frames = number(arg, value(arg)),
+            "--junit" => opts.junit = Some(PathBuf::from(value(arg))),
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option {}\n{}", arg, usage);
+                process::exit(2);
+            }
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    let roms = collect_roms(&paths);
+    if roms.is_empty() {
+        eprintln!("No ROMs found\n{}", usage);
+        process::exit(2);
+    }
+
+    let results = run_all(&roms, &opts);
+    for r in &results {
+        let status = match r.outcome {
+            Outcome::Passed => "PASS",
+            Outcome::Failed(_) => "FAIL",
+            Outcome::TimedOut => "TIMEOUT",
+        };
+        println!(
+            "{:<7} {} ({} frames, {:.2}s)",
+            status,
+            r.name,
+            r.cycles / FRAME_DOTS,
+            r.duration.as_secs_f64()
+        );
+        if let Outcome::Failed(reason) = &r.outcome {
+            println!("        {}", reason);
+        }
+    }
+
+    let passed = results.iter().filter(|r| r.outcome == Outcome::Passed).count();
+    let timed_out = results.iter().filter(|r| r.outcome == Outcome::TimedOut).count();
+    let failed = results.len() - passed - timed_out;
+    println!("\n{} passed, {} failed, {} timed out", passed, failed, timed_out);
+
+    if let Some(path) = &opts.junit {
+        if let Err(e) = fs::write(path, junit_xml(&results, opts.timeout_frames)) {
+            eprintln!("Unable to write {}: {}", path.display(), e);
+            process::exit(2);
+        }
+    }
+    if passed != results.len() {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn test_pass_detection() {
+        let mooneye = "
+main:
+    ld B,3
+    ld C,5
+    ld D,8
+    ld E,13
+    ld H,21
+    ld L,34
+    ld B,B
+";
+        let (outcome, _, _) = run_rom(assemble(mooneye).unwrap(), 10);
+        assert_eq!(outcome, Outcome::Passed);
+
+        let failed = mooneye.replace("ld L,34", "ld L,35");
+        let (outcome, _, _) = run_rom(assemble(&failed).unwrap(), 10);
+        assert!(matches!(outcome, Outcome::Failed(_)));
+
+        let blargg = format!(
+            "main:\n ld HL,message\n call send_string\n .done:\n jr .done\nmessage: db \"Passed\", 0\n{}",
+            include_str!("../test_programs/serial.s")
+        );
+        let (outcome, _, serial) = run_rom(assemble(&blargg).unwrap(), 10);
+        assert_eq!((outcome, serial.as_str()), (Outcome::Passed, "Passed"));
+
+        // Once there is serial output, LD B,B is just another instruction being tested
+        let ld_r_r = format!(
+            "main:
+    ld HL,name
+    call send_string
+    ld B,B
+    ld HL,message
+    call send_string
+    .done:
+        jr .done
+name: db \"ld r,r \", 0
+message: db \"Passed\", 0
+{}",
+            include_str!("../test_programs/serial.s")
+        );
+        let (outcome, _, serial) = run_rom(assemble(&ld_r_r).unwrap(), 10);
+        assert_eq!((outcome, serial.as_str()), (Outcome::Passed, "ld r,r Passed"));
+
+        let (outcome, cycles, _) = run_rom(assemble("main:\n jr main").unwrap(), 2);
+        assert_eq!(outcome, Outcome::TimedOut);
+        assert!(cycles >= 2 * FRAME_DOTS);
+
+        // Nothing wakes a ROM from STOP, but it still times out
+        let (outcome, _, _) = run_rom(assemble("main:\n stop\n jr main").unwrap(), 2);
+        assert_eq!(outcome, Outcome::TimedOut);
+    }
+
+    #[test]
+    fn test_junit_xml() {
+        let results = vec![
+            TestResult {
+                name: "a<b>.gb".to_string(),
+                outcome: Outcome::Passed,
+                cycles: 0,
+                duration: Duration::from_millis(500),
+                serial: String::new(),
+            },
+            TestResult {
+                name: "c.gb".to_string(),
+                outcome: Outcome::TimedOut,
+                cycles: 0,
+                duration: Duration::from_millis(250),
+                serial: "Failed & more".to_string(),
+            },
+        ];
+        let xml = junit_xml(&results, 10);
+        assert!(xml.contains("tests=\"2\" failures=\"1\""));
+        assert!(xml.contains("<testcase name=\"a&lt;b&gt;.gb\" time=\"0.500\">"));
+        assert!(xml.contains("<failure message=\"Timed out after 10 frames\"/>"));
+        assert!(xml.contains("<system-out>Failed &amp; more</system-out>"));
+    }
+}
@@ -15,3 +15,13 @@ pub fn check_bit(b: u8, bit: u8) -> bool {
 
     b & mask == mask
 }
+
+/// Parses a number written in hex, with an optional `$` or `0x` prefix
+pub fn parse_hex(s: &str) -> Option<u16> {
+    let s = s.trim();
+    let digits = s
+        .strip_prefix('$')
+        .or_else(|| s.strip_prefix("0x"))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).ok()
+}